@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::join_all;
+use reqwest::Client;
+use std::time::Instant;
+
+/// Bytes each probe connection reads before it is dropped.
+const PROBE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Concurrency levels tried by `--workers-auto`, in order.
+const PROBE_LEVELS: [usize; 5] = [1, 2, 4, 8, 16];
+
+/// A level must beat the previous one by this factor to count as an improvement.
+const MIN_GAIN: f64 = 1.1;
+
+/// Measures throughput at increasing concurrency against `url` and returns the
+/// level after which adding connections stopped helping.
+pub async fn probe_workers(client: &Client, url: &str) -> Result<usize> {
+    let mut best = (PROBE_LEVELS[0], 0.0f64);
+
+    for &level in &PROBE_LEVELS {
+        let throughput = measure(client, url, level).await?;
+        if best.1 > 0.0 && throughput < best.1 * MIN_GAIN {
+            break;
+        }
+        best = (level, throughput);
+    }
+
+    Ok(best.0)
+}
+
+/// Bytes per second achieved by `connections` parallel ranged reads.
+async fn measure(client: &Client, url: &str, connections: usize) -> Result<f64> {
+    let started = Instant::now();
+    let reads = (0..connections).map(|i| {
+        // 每个连接读不同的区间，避免命中同一段缓存
+        let offset = i as u64 * PROBE_BYTES;
+        read_prefix(client, url, offset)
+    });
+
+    let mut total = 0u64;
+    for result in join_all(reads).await {
+        total += result?;
+    }
+    if total == 0 {
+        return Err(anyhow!("Bandwidth probe received no data from {}", url));
+    }
+
+    Ok(total as f64 / started.elapsed().as_secs_f64())
+}
+
+async fn read_prefix(client: &Client, url: &str, offset: u64) -> Result<u64> {
+    let mut response = client
+        .get(url)
+        .header("Range", format!("bytes={}-{}", offset, offset + PROBE_BYTES - 1))
+        .send()
+        .await
+        .context("Bandwidth probe request failed")?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Bandwidth probe failed: {}", response.status()));
+    }
+
+    let mut read = 0u64;
+    while read < PROBE_BYTES {
+        match response.chunk().await? {
+            Some(chunk) => read += chunk.len() as u64,
+            None => break,
+        }
+    }
+    Ok(read.min(PROBE_BYTES))
+}
+
+/// A window whose throughput falls below the previous one by more than this factor
+/// counts as congestion, like a failed attempt.
+const THROUGHPUT_DROP: f64 = 0.9;
+
+/// `--adaptive-concurrency`: additive-increase/multiplicative-decrease control of
+/// the number of parallel downloads, adjusted once per measurement window.
+#[derive(Debug)]
+pub struct Aimd {
+    current: usize,
+    max: usize,
+    last_rate: Option<f64>,
+}
+
+impl Aimd {
+    pub fn new(start: usize, max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            current: start.clamp(1, max),
+            max,
+            last_rate: None,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Concurrency for the next window, given the aggregate `rate` (bytes/s) and the
+    /// failed attempts of the one that just ended: halve on errors, step back one when
+    /// throughput fell, otherwise add one to probe for more bandwidth.
+    pub fn next(&mut self, rate: f64, errors: u64) -> usize {
+        let dropped = self.last_rate.is_some_and(|last| rate < last * THROUGHPUT_DROP);
+        self.current = if errors > 0 {
+            (self.current / 2).max(1)
+        } else if dropped {
+            (self.current - 1).max(1)
+        } else {
+            (self.current + 1).min(self.max)
+        };
+        self.last_rate = Some(rate);
+        self.current
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+/// Per-block CRC32C values for one file, e.g. from a GCS-style composite manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockChecksums {
+    pub block_size: u64,
+    /// Hex-encoded CRC32C of each block, in order. The last block may be short.
+    pub crc32c: Vec<String>,
+}
+
+impl BlockChecksums {
+    /// Rounds `pos` down to the start of its block.
+    pub fn align(&self, pos: u64) -> u64 {
+        pos / self.block_size * self.block_size
+    }
+
+    fn expected(&self, block: usize) -> Option<u32> {
+        self.crc32c
+            .get(block)
+            .and_then(|hex| u32::from_str_radix(hex.trim_start_matches("0x"), 16).ok())
+    }
+}
+
+/// Loads a JSON file mapping file names to their block checksums:
+/// `{"sa_000000.tar": {"block_size": 67108864, "crc32c": ["1a2b3c4d", ...]}}`.
+pub fn load_block_crc_file(path: &str) -> Result<HashMap<String, BlockChecksums>> {
+    let content = fs::read_to_string(path).context("Failed to read block CRC file")?;
+    let blocks: HashMap<String, BlockChecksums> =
+        serde_json::from_str(&content).context("Failed to parse block CRC file")?;
+
+    for (name, sums) in &blocks {
+        if sums.block_size == 0 {
+            return Err(anyhow!("Block size for {} must be greater than zero", name));
+        }
+        if let Some(bad) = sums.crc32c.iter().find(|c| u32::from_str_radix(c.trim_start_matches("0x"), 16).is_err()) {
+            return Err(anyhow!("Invalid CRC32C value '{}' for {}", bad, name));
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// A block whose CRC32C did not match; the download restarts from `block_start`.
+#[derive(Debug)]
+pub struct BlockMismatch {
+    pub block: usize,
+    pub block_start: u64,
+    pub expected: Option<u32>,
+    pub actual: u32,
+}
+
+impl fmt::Display for BlockMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "CRC32C mismatch in block {} (offset {}): expected {:08x}, got {:08x}",
+                self.block, self.block_start, expected, self.actual
+            ),
+            None => write!(f, "Unexpected data past the last block at offset {}", self.block_start),
+        }
+    }
+}
+
+impl std::error::Error for BlockMismatch {}
+
+/// Accumulates CRC32C over streamed data and checks each block as it completes.
+pub struct BlockVerifier<'a> {
+    sums: &'a BlockChecksums,
+    offset: u64,
+    crc: u32,
+}
+
+impl<'a> BlockVerifier<'a> {
+    /// `start` must be block-aligned (see [`BlockChecksums::align`]).
+    pub fn new(sums: &'a BlockChecksums, start: u64) -> Self {
+        Self {
+            sums,
+            offset: start,
+            crc: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), BlockMismatch> {
+        let block_size = self.sums.block_size;
+        while !data.is_empty() {
+            let in_block = self.offset % block_size;
+            let take = ((block_size - in_block) as usize).min(data.len());
+            self.crc = crc32c::crc32c_append(self.crc, &data[..take]);
+            self.offset += take as u64;
+            data = &data[take..];
+
+            if self.offset.is_multiple_of(block_size) {
+                self.check_block((self.offset / block_size - 1) as usize)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the trailing short block, if any, once the stream has ended.
+    pub fn finish(&mut self) -> Result<(), BlockMismatch> {
+        if !self.offset.is_multiple_of(self.sums.block_size) {
+            self.check_block((self.offset / self.sums.block_size) as usize)?;
+        }
+        Ok(())
+    }
+
+    fn check_block(&mut self, block: usize) -> Result<(), BlockMismatch> {
+        let expected = self.sums.expected(block);
+        let actual = self.crc;
+        self.crc = 0;
+        if expected != Some(actual) {
+            return Err(BlockMismatch {
+                block,
+                block_start: block as u64 * self.sums.block_size,
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: u64 = 16;
+
+    fn data() -> Vec<u8> {
+        (0..40u8).collect()
+    }
+
+    /// Checksums for [`data`]: two full blocks and a short 8-byte final block.
+    fn sums() -> BlockChecksums {
+        BlockChecksums {
+            block_size: BLOCK_SIZE,
+            crc32c: data()
+                .chunks(BLOCK_SIZE as usize)
+                .map(|block| format!("{:08x}", crc32c::crc32c(block)))
+                .collect(),
+        }
+    }
+
+    /// Feeds `data[start..]` in `chunk`-byte pieces, then finishes the stream.
+    fn verify(sums: &BlockChecksums, data: &[u8], start: u64, chunk: usize) -> Result<(), BlockMismatch> {
+        let mut verifier = BlockVerifier::new(sums, start);
+        for piece in data[start as usize..].chunks(chunk) {
+            verifier.update(piece)?;
+        }
+        verifier.finish()
+    }
+
+    #[test]
+    fn intact_stream_passes_in_any_chunking() {
+        for chunk in [1, 5, 16, 17, 40] {
+            verify(&sums(), &data(), 0, chunk).unwrap();
+        }
+    }
+
+    #[test]
+    fn mismatch_reports_the_start_of_the_bad_block() {
+        let mut corrupt = data();
+        corrupt[20] ^= 0xff;
+        let mismatch = verify(&sums(), &corrupt, 0, 7).unwrap_err();
+        assert_eq!((mismatch.block, mismatch.block_start), (1, 16));
+        assert!(mismatch.expected.is_some());
+    }
+
+    #[test]
+    fn restarting_from_the_reported_block_verifies_the_rest() {
+        let mut corrupt = data();
+        corrupt[20] ^= 0xff;
+        let mismatch = verify(&sums(), &corrupt, 0, 40).unwrap_err();
+        verify(&sums(), &data(), mismatch.block_start, 3).unwrap();
+    }
+
+    #[test]
+    fn unaligned_resume_offset_is_rounded_down_to_its_block() {
+        let sums = sums();
+        assert_eq!(sums.align(0), 0);
+        assert_eq!(sums.align(16), 16);
+        assert_eq!(sums.align(27), 16);
+        verify(&sums, &data(), sums.align(27), 4).unwrap();
+    }
+
+    #[test]
+    fn short_final_block_is_checked_on_finish() {
+        let mut corrupt = data();
+        corrupt[39] ^= 0xff;
+        let sums = sums();
+        let mut verifier = BlockVerifier::new(&sums, 0);
+        verifier.update(&corrupt).unwrap();
+        let mismatch = verifier.finish().unwrap_err();
+        assert_eq!((mismatch.block, mismatch.block_start), (2, 32));
+    }
+
+    #[test]
+    fn data_past_the_last_block_is_rejected() {
+        let mut sums = sums();
+        sums.crc32c.truncate(2);
+        let mismatch = verify(&sums, &data(), 0, 40).unwrap_err();
+        assert_eq!((mismatch.block_start, mismatch.expected), (32, None));
+        assert!(mismatch.to_string().contains("past the last block"), "{}", mismatch);
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Digest algorithm of the checksums in the link file or `--checksum-from`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Sha1,
+    Md5,
+    /// Infer per checksum from its hex length (32 MD5, 40 SHA-1, 64 SHA-256)
+    Auto,
+}
+
+impl HashAlgo {
+    /// The concrete algorithm used to check `digest`.
+    pub fn resolve(self, digest: &str) -> Result<HashAlgo> {
+        if self != HashAlgo::Auto {
+            return Ok(self);
+        }
+        match digest.len() {
+            32 => Ok(HashAlgo::Md5),
+            40 => Ok(HashAlgo::Sha1),
+            64 => Ok(HashAlgo::Sha256),
+            n => Err(anyhow!(
+                "Cannot infer hash algorithm of {} from its length {}; expected 32 (MD5), 40 (SHA-1) or 64 (SHA-256) hex digits",
+                digest,
+                n
+            )),
+        }
+    }
+}
+
+/// Parses a `sha256sum`-style file (`<hash>  <name>` or `<hash> *<name>`) into a
+/// map from file name to lowercase hex digest.
+pub fn parse_sums_file(path: &str) -> Result<HashMap<String, String>> {
+    let file = File::open(path).context("Failed to open checksum file")?;
+    let reader = BufReader::new(file);
+
+    let mut sums = HashMap::new();
+
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line.context("Failed to read line")?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (hash, name) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Malformed checksum line {}: {}", idx + 1, line))?;
+        // Two-space separator leaves a leading space; `*` marks binary mode
+        let name = name.strip_prefix(' ').unwrap_or(name);
+        let name = name.strip_prefix('*').unwrap_or(name);
+
+        if !hash.chars().all(|c| c.is_ascii_hexdigit()) || name.is_empty() {
+            return Err(anyhow!("Malformed checksum line {}: {}", idx + 1, line));
+        }
+
+        sums.insert(name.to_string(), hash.to_ascii_lowercase());
+    }
+
+    Ok(sums)
+}
+
+/// Computes the SHA-256 of a file as lowercase hex.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    digest_file::<Sha256>(path)
+}
+
+/// Hashes `path` with the algorithm `algo` picks for `expected`, for comparison with it.
+pub fn hash_file_for(path: &Path, expected: &str, algo: HashAlgo) -> Result<String> {
+    hash_file(path, algo.resolve(expected)?)
+}
+
+/// Hashes `path` with `algo`; `Auto`, having no digest to infer from, means SHA-256.
+pub fn hash_file(path: &Path, algo: HashAlgo) -> Result<String> {
+    match algo {
+        HashAlgo::Md5 => digest_file::<Md5>(path),
+        HashAlgo::Sha1 => digest_file::<Sha1>(path),
+        HashAlgo::Sha256 | HashAlgo::Auto => digest_file::<Sha256>(path),
+    }
+}
+
+fn digest_file<D: Digest>(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open {} for hashing", path.display()))?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
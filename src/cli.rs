@@ -1,10 +1,36 @@
-use clap::{Parser, ValueEnum};
+use crate::checksum::HashAlgo;
+use crate::columns::{ColumnMap, ColumnRef};
+use crate::downloader::{DownloadOptions, FsyncMode, HeadMethod, MismatchPolicy, TlsVersion};
+use crate::schedule::{BandwidthSchedule, Schedule};
+use crate::secret::Secret;
+use crate::since::{MissingLastModified, Since};
+use crate::state::StateFormat;
+use crate::units::{parse_bytes, parse_duration, parse_mode, parse_rate, parse_request_rate, ByteRange};
+use crate::useragent;
+use anyhow::Result;
+use clap::{ArgAction, Parser, ValueEnum};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum DownloadMode {
     All,
     Single,
     Range,
+    /// Check files on disk against their checksums without downloading
+    Verify,
+    /// Re-hash completed files against the hashes recorded in state and report bit-rot
+    Scrub,
+}
+
+/// Output format for `--print-urls`.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum UrlListFormat {
+    /// `file_name<TAB>url` per line
+    Tsv,
+    /// aria2c `--input-file` format, with mirrors on the same line
+    Aria2,
 }
 
 #[derive(Parser, Debug)]
@@ -15,9 +41,9 @@ pub struct Args {
     #[arg(short, long)]
     pub link_file: Option<String>,
 
-    /// Output directory
+    /// Output directory; repeat to stripe files round-robin across several (state lives in the first)
     #[arg(short, long, default_value = "./my_downloads")]
-    pub output: String,
+    pub output: Vec<String>,
 
     /// Download mode
     #[arg(short, long, value_enum, default_value = "all")]
@@ -44,10 +70,574 @@ pub struct Args {
     pub resume: bool,
 
     /// HTTP proxy (e.g., http://127.0.0.1:7890)
-    #[arg(long)]
+    #[arg(long, env = "SA1B_PROXY", hide_env_values = true)]
     pub proxy: Option<String>,
 
     /// Number of retry attempts on failure
     #[arg(short, long, default_value = "3")]
     pub retries: u32,
+
+    /// Abandon a file after this many seconds; its partial is kept and resumed on the next run
+    #[arg(long)]
+    pub max_runtime_per_file: Option<u64>,
+
+    /// Accepted Content-Type for downloads, repeatable (default: reject only text/html)
+    #[arg(long = "content-type")]
+    pub content_types: Vec<String>,
+
+    /// Save nested file names (e.g. images/sa_000000.tar) by basename only
+    #[arg(long)]
+    pub flatten: bool,
+
+    /// Download at most this many files per run (applies to all/range modes)
+    #[arg(long)]
+    pub max_files: Option<usize>,
+
+    /// Continue from the file after the one recorded in <output>/.cursor by the last run; the cursor only moves past files that finished
+    #[arg(long)]
+    pub continue_cursor: bool,
+
+    /// Only transfer data inside this daily window, e.g. 01:00-07:00 (may cross midnight)
+    #[arg(long)]
+    pub schedule: Option<Schedule>,
+
+    /// When the server reports no size, compare head/tail samples of existing files before skipping
+    #[arg(long)]
+    pub verify_existing_before_download: bool,
+
+    /// Stop starting new downloads when free space on an output drops below this (e.g. 50GB)
+    #[arg(long, value_parser = parse_bytes)]
+    pub min_free: Option<u64>,
+
+    /// Load checksums from a sha256sum-style file (<hash>  <name>); see --hash-algo
+    #[arg(long)]
+    pub checksum_from: Option<String>,
+
+    /// Extract each tar shard into a directory next to it once downloaded
+    #[arg(long)]
+    pub extract: bool,
+
+    /// Number of concurrent extraction workers (independent of --threads)
+    #[arg(long, default_value = "2")]
+    pub extract_threads: usize,
+
+    /// Print nothing but a single JSON summary object to stdout when finished (errors go to stderr)
+    #[arg(long)]
+    pub quiet_summary_json: bool,
+
+    /// Maximum HTTP requests (HEAD/GET) per second across all workers, e.g. 2 or 0.5
+    #[arg(long, value_parser = parse_request_rate)]
+    pub limit_files_per_second: Option<f64>,
+
+    /// Only download entries that are not in this previous link file
+    #[arg(long)]
+    pub manifest_diff: Option<String>,
+
+    /// JSON file with per-block CRC32C values checked while streaming
+    #[arg(long)]
+    pub block_crc_file: Option<String>,
+
+    /// Keep .part files in the output directory; set to false to stage them in --tmp-dir
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub tmpfile_in_output: bool,
+
+    /// Directory for .part files (default: system temp dir); implies --tmpfile-in-output=false
+    #[arg(long)]
+    pub tmp_dir: Option<String>,
+
+    /// Abort and retry a transfer (resuming from the .part file) when no data arrives for
+    /// this many seconds; 0 disables the watchdog and leaves transfers under a 300s
+    /// overall request timeout
+    #[arg(long, visible_alias = "stall-timeout", default_value = "60")]
+    pub max_idle_time: u64,
+
+    /// State file encoding; compact or zstd reduce I/O for very large manifests
+    #[arg(long, value_enum, default_value = "pretty")]
+    pub state_format: StateFormat,
+
+    /// How to discover file sizes: head, or get-range for servers that reject HEAD
+    #[arg(long, value_enum, default_value = "head")]
+    pub head_method: HeadMethod,
+
+    /// Cancel the whole run after this many consecutive failed files
+    #[arg(long)]
+    pub abort_after: Option<usize>,
+
+    /// Progress bar redraws per second; 2-4 reduces flicker over SSH
+    #[arg(long, default_value = "20", value_parser = clap::value_parser!(u8).range(1..))]
+    pub refresh_rate: u8,
+
+    /// Disable progress bars and print a per-file result table at the end
+    #[arg(long)]
+    pub summary_only: bool,
+
+    /// Before resuming a .tar, truncate the partial file back to its last structurally valid block
+    #[arg(long)]
+    pub safe_resume: bool,
+
+    /// Print the selected files' URLs to stdout and exit without downloading
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "tsv")]
+    pub print_urls: Option<UrlListFormat>,
+
+    /// Maximum idle keep-alive connections kept per host (default: unlimited)
+    #[arg(long)]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Seconds an idle pooled connection is kept before closing (0 = never)
+    #[arg(long, default_value = "90")]
+    pub pool_idle_timeout: u64,
+
+    /// Verify each file's hash right after it finishes and re-download on mismatch; hashes come from --checksum-from, --hash-col or a JSONL `sha256` field, in the --hash-algo algorithm
+    #[arg(long)]
+    pub verify_after_download: bool,
+
+    /// Abort a download when disk write speed stays below this per second (e.g. 5MB)
+    #[arg(long, value_parser = parse_bytes)]
+    pub min_disk_speed: Option<u64>,
+
+    /// In single mode, fetch only bytes START-END (END exclusive) into <file>.START-END
+    #[arg(long)]
+    pub byte_range: Option<ByteRange>,
+
+    /// Use file sizes from the manifest and skip the per-file HEAD request when one is given
+    #[arg(long)]
+    pub trust_manifest_size: bool,
+
+    /// Probe bandwidth at increasing concurrency and pick the thread count automatically
+    #[arg(long)]
+    pub workers_auto: bool,
+
+    /// Bearer token for private manifests and mirrors
+    #[arg(long, env = "SA1B_AUTH_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// Proxy credentials as USER:PASSWORD
+    #[arg(long, env = "SA1B_PROXY_AUTH", hide_env_values = true)]
+    pub proxy_auth: Option<String>,
+
+    /// In verify mode, compare each file's size and checksum (per --hash-algo; auto means SHA-256) with a trusted copy in this directory
+    #[arg(long)]
+    pub compare_dir: Option<String>,
+
+    /// Cap total download bandwidth per second across all concurrent downloads (e.g. 50MB)
+    #[arg(long, visible_alias = "limit-rate-total", value_parser = parse_rate)]
+    pub bandwidth_limit: Option<u64>,
+
+    /// Cap each connection's download bandwidth per second (e.g. 5MB); with --segments
+    /// every segment counts as its own connection
+    #[arg(long, value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+
+    /// Time-of-day bandwidth caps, e.g. 09:00-18:00=10MB,18:00-09:00=100MB
+    #[arg(long)]
+    pub bwlimit_schedule: Option<BandwidthSchedule>,
+
+    /// Pick a user agent per request from a built-in pool instead of a single fixed one
+    #[arg(long)]
+    pub randomize_user_agent: bool,
+
+    /// File with one user agent per line to randomize from (implies --randomize-user-agent)
+    #[arg(long)]
+    pub user_agent_file: Option<String>,
+
+    /// After the run, write the successfully downloaded files (name, URL, size, path, hash) to this manifest (.jsonl for JSON lines, otherwise TSV)
+    #[arg(long)]
+    pub output_manifest: Option<String>,
+
+    /// Seconds to cache DNS results (0 = resolve on every new connection); connect failures always re-resolve
+    #[arg(long, default_value = "60")]
+    pub dns_cache_ttl: u64,
+
+    /// Fail before downloading if the link file doesn't contain exactly this many entries
+    #[arg(long)]
+    pub expected_count: Option<usize>,
+
+    /// Only download files whose server Last-Modified is after this date (YYYY-MM-DD, RFC 3339, or "now" for newer than the copy recorded in state)
+    #[arg(long)]
+    pub since: Option<Since>,
+
+    /// With --since, what to do with files the server sends no Last-Modified for
+    #[arg(long, value_enum, default_value = "download")]
+    pub since_missing: MissingLastModified,
+
+    /// Cap on memory held in write buffers and chunk buffers across all workers (e.g. 256MB)
+    #[arg(long, value_parser = parse_bytes)]
+    pub max_memory: Option<u64>,
+
+    /// Periodically rewrite this file with a JSON snapshot of overall and per-file progress
+    #[arg(long)]
+    pub progress_file: Option<String>,
+
+    /// Seconds between --progress-file updates
+    #[arg(long, default_value = "2")]
+    pub progress_interval: u64,
+
+    /// In scrub mode, only re-hash files last hashed longer ago than this (e.g. 30d, 12h)
+    #[arg(long, value_parser = parse_duration)]
+    pub rehash_interval: Option<Duration>,
+
+    /// In scrub mode, delete and re-download files that fail the scrub
+    #[arg(long)]
+    pub scrub_repair: bool,
+
+    /// Link file column holding the file name: 0-based index or header name
+    #[arg(long, default_value = "0")]
+    pub name_col: ColumnRef,
+
+    /// Link file column holding the URL: 0-based index or header name
+    #[arg(long, default_value = "1")]
+    pub url_col: ColumnRef,
+
+    /// Link file column holding the checksum (per --hash-algo): 0-based index or header name
+    #[arg(long)]
+    pub hash_col: Option<ColumnRef>,
+
+    /// Link file column holding the size in bytes: 0-based index or header name
+    #[arg(long)]
+    pub size_col: Option<ColumnRef>,
+
+    /// Open one connection per worker to each host before the batch starts and keep them alive between files
+    #[arg(long)]
+    pub keep_open_connections: bool,
+
+    /// Print extra diagnostics, such as connect time saved by --keep-open-connections
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// What to do when a file fails its checksum (per --hash-algo) check
+    #[arg(long, value_enum, default_value = "retry")]
+    pub on_checksum_mismatch: MismatchPolicy,
+
+    /// Resume a file left incomplete under its final name by another tool (e.g. wget)
+    #[arg(long)]
+    pub continue_partial_from_other_tool: bool,
+
+    /// Append one row per run (timestamp, counts, bytes, elapsed, average speed) to this CSV
+    #[arg(long)]
+    pub summary_csv: Option<String>,
+
+    /// Accept files up to this many bytes smaller than the expected size (e.g. 512); 0 is strict
+    #[arg(long, value_parser = parse_bytes, default_value = "0")]
+    pub allow_smaller: u64,
+
+    /// Rebuild missing state entries from the files and `.part`s found on disk
+    /// before downloading, e.g. after the state file was lost
+    #[arg(long)]
+    pub resume_scan: bool,
+
+    /// Print a throughput heartbeat line (rate, files done, ETA) to stderr this often,
+    /// e.g. 60 or 5m; useful when progress bars are hidden
+    #[arg(long, value_parser = parse_duration)]
+    pub bps_report_interval: Option<Duration>,
+
+    /// Treat link file URLs ending in `/` as directory listings (HTML index or JSON)
+    /// and download every file found below them
+    #[arg(long)]
+    pub expand_dirs: bool,
+
+    /// When to fsync downloaded data: none (fastest, a crash may lose recent writes),
+    /// per-file (before each rename) or per-chunk (after every write, much slower)
+    #[arg(long, value_enum, default_value = "per-file")]
+    pub fsync_mode: FsyncMode,
+
+    /// Fully download this many random entries as a pipeline check, then exit
+    /// (non-zero if any of them failed) without touching the rest
+    #[arg(long)]
+    pub preflight_sample: Option<usize>,
+
+    /// Algorithm of the checksums from the link file or --checksum-from; auto infers
+    /// it per file from the digest length (32 MD5, 40 SHA-1, 64 SHA-256); with md5/sha1 a
+    /// JSON-lines `sha256` field is ignored
+    #[arg(long, value_enum, default_value = "sha256")]
+    pub hash_algo: HashAlgo,
+
+    /// Print the files state records as incomplete (name, URL, bytes so far, size,
+    /// last error) and exit without downloading
+    #[arg(long)]
+    pub list_failed: bool,
+
+    /// While extracting, write `<shard>.index.json` with each member's name, data
+    /// offset and size for random access into the tar
+    #[arg(long, requires = "extract")]
+    pub tar_index: bool,
+
+    /// Write each file's transcript (size probe, requests, offsets, retries, timing)
+    /// to `<file_name>.log` in this directory
+    #[arg(long)]
+    pub per_file_log: Option<PathBuf>,
+
+    /// Cap on HTTP requests open at once across all files, independent of --threads;
+    /// also bounds the idle connection pool
+    #[arg(long)]
+    pub max_connections_global: Option<usize>,
+
+    /// In verify and scrub mode, skip files whose mtime has not changed since they
+    /// last passed
+    #[arg(long)]
+    pub verify_only_new: bool,
+
+    /// In verify and scrub mode, hash every file, ignoring --verify-only-new and
+    /// --rehash-interval
+    #[arg(long)]
+    pub force_rehash: bool,
+
+    /// Download the smallest files first so as many as possible finish early; sizes
+    /// missing from the link file are probed in parallel, unknown sizes go last
+    #[arg(long)]
+    pub smallest_first: bool,
+
+    /// Refuse to start if any entry lacks a checksum from the link file or
+    /// --checksum-from, listing every such entry
+    #[arg(long)]
+    pub fail_on_missing_checksum: bool,
+
+    /// Download in the order of the file names listed one per line in this file;
+    /// unlisted entries follow in their usual order
+    #[arg(long, conflicts_with = "smallest_first")]
+    pub download_order_file: Option<PathBuf>,
+
+    /// With --download-order-file, skip entries the order file does not list
+    #[arg(long, requires = "download_order_file")]
+    pub only_ordered: bool,
+
+    /// Set this mode (octal, e.g. 0640) on every completed file; Unix only, ignored
+    /// with a warning elsewhere
+    #[arg(long, value_parser = parse_mode)]
+    pub output_permissions: Option<u32>,
+
+    /// Check once per host whether byte ranges are honoured (a `bytes=0-0` request),
+    /// print the result, and restart rather than resume files on hosts without them
+    #[arg(long)]
+    pub probe_ranges: bool,
+
+    /// Drop state entries whose files no longer exist on disk, rewrite the state file
+    /// and exit; the link file is not needed
+    #[arg(long)]
+    pub compact_state: bool,
+
+    /// With --compact-state, also drop every completed entry, keeping only partial
+    /// downloads
+    #[arg(long, requires = "compact_state")]
+    pub compact_completed: bool,
+
+    /// Save each file under the name in the response's `Content-Disposition` header
+    /// (path components stripped) instead of the link file's name, for mirrors whose
+    /// URLs don't carry it; falls back to the link file's name
+    #[arg(long)]
+    pub honor_content_disposition: bool,
+
+    /// Files hashed at once in verify and scrub mode (default: --threads); hashing
+    /// is CPU-bound, so many-core machines can use more than they download with
+    #[arg(long)]
+    pub parallel_hash_threads: Option<usize>,
+
+    /// Shell command run once before the batch starts (SA1B_FILES is set); the run
+    /// stops if it fails
+    #[arg(long)]
+    pub on_start: Option<String>,
+
+    /// Shell command run once after the batch, even when it failed, with the outcome
+    /// in SA1B_STATUS, SA1B_SUCCESS, SA1B_FAILED, SA1B_SKIPPED, SA1B_TOTAL_BYTES, ...
+    #[arg(long)]
+    pub on_finish: Option<String>,
+
+    /// Refuse TLS connections below this version (1.2 or 1.3); a mirror that only
+    /// offers older versions fails with a clear error instead of being retried
+    #[arg(long, value_enum, alias = "strict-tls-version")]
+    pub min_tls_version: Option<TlsVersion>,
+
+    /// Fetch the link file from this URL into `<output>/.manifest/` instead of reading
+    /// a local one; an interrupted fetch resumes, and the cached copy is reused until
+    /// the server reports a new ETag or Last-Modified
+    #[arg(long, conflicts_with = "link_file")]
+    pub manifest_url: Option<String>,
+
+    /// Move files that fail size or checksum validation here as `<file_name>.<reason>`
+    /// for inspection instead of deleting them; the download is then retried from scratch
+    #[arg(long)]
+    pub quarantine_dir: Option<PathBuf>,
+
+    /// For entries with mirrors, race a small ranged request against every mirror
+    /// before each attempt and download from the fastest
+    #[arg(long)]
+    pub race_mirrors: bool,
+
+    /// Delete the state file once a batch finishes with no failures and no
+    /// incomplete files recorded, so nothing stale is left for the next run
+    #[arg(long)]
+    pub trim_state_on_success: bool,
+
+    /// Split the link file into contiguous batches of about this many bytes (e.g.
+    /// 500GB), using listed or probed sizes, and print them; with --batch-index,
+    /// download only that batch (all mode)
+    #[arg(long, value_parser = parse_bytes)]
+    pub range_by_size: Option<u64>,
+
+    /// Which --range-by-size batch to download, counting from 0
+    #[arg(long, requires = "range_by_size")]
+    pub batch_index: Option<usize>,
+
+    /// Hardlink (or copy) entries whose checksum matches a file already downloaded
+    /// instead of downloading the same content again; reports the bytes saved
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// When a download fails with an HTTP/2 stream or protocol error, retry it and all
+    /// later requests to that host over HTTP/1.1
+    #[arg(long)]
+    pub http_version_fallback: bool,
+
+    /// Show an ETA for the whole batch on the overall progress bar (and a log line every
+    /// minute), from the remaining bytes and the throughput of the last 30 seconds
+    #[arg(long)]
+    pub show_eta_overall: bool,
+
+    /// Check the state file: report its schema version and entries that are duplicated
+    /// or inconsistent (e.g. completed with fewer bytes than expected), then exit
+    #[arg(long)]
+    pub validate_json_state: bool,
+
+    /// Split each file into this many byte ranges downloaded in parallel over separate
+    /// connections (files under 16 MiB, or on hosts without range support, use one);
+    /// the segment map is saved next to the .part file so each range resumes on its own,
+    /// and a .part from a single-connection run is kept as the first, finished range
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    pub segments: u16,
+
+    /// Buffer this many downloaded bytes per file before writing them to disk (e.g. 4MiB);
+    /// larger buffers mean fewer, bigger writes on spinning disks and NFS, 0 writes
+    /// every network chunk as it arrives
+    #[arg(long, default_value = "256KiB", value_parser = parse_bytes)]
+    pub write_buffer_size: u64,
+
+    /// Also write out the buffer at least this often (e.g. 5s), so a slow transfer
+    /// doesn't leave data sitting in memory; by default it is written when full
+    #[arg(long, value_parser = parse_duration)]
+    pub flush_interval: Option<Duration>,
+
+    /// Reserve disk space for each .part file up front when its size is known, reducing
+    /// fragmentation and failing early when the disk is full (Linux fallocate; with
+    /// --segments the whole file is allocated on any platform)
+    #[arg(long)]
+    pub preallocate: bool,
+
+    /// Start the batch even when the files still to download (sizes from the link file or
+    /// HEAD) exceed the free space in the output directories; only warn
+    #[arg(long)]
+    pub force: bool,
+
+    /// Start with --threads workers and adjust them every 15 seconds up to this maximum:
+    /// one more while throughput holds, half as many after failed attempts (AIMD)
+    #[arg(long, value_name = "MAX", value_parser = clap::value_parser!(u16).range(1..))]
+    pub adaptive_concurrency: Option<u16>,
+}
+
+impl Args {
+    /// Builds the downloader configuration from the parsed flags.
+    pub fn download_options(&self) -> Result<DownloadOptions> {
+        Ok(DownloadOptions {
+            resume: self.resume,
+            proxy: self.proxy.clone(),
+            retries: self.retries,
+            max_runtime_per_file: self.max_runtime_per_file.map(Duration::from_secs),
+            content_types: self.content_types.clone(),
+            flatten: self.flatten,
+            schedule: self.schedule,
+            verify_existing: self.verify_existing_before_download,
+            min_free: self.min_free,
+            extract: self.extract,
+            extract_threads: self.extract_threads,
+            quiet: self.quiet_summary_json,
+            requests_per_second: self.limit_files_per_second,
+            staging_dir: self.staging_dir()?,
+            max_idle_time: (self.max_idle_time > 0).then(|| Duration::from_secs(self.max_idle_time)),
+            state_format: self.state_format,
+            head_method: self.head_method,
+            abort_after: self.abort_after,
+            refresh_rate: self.refresh_rate,
+            summary_only: self.summary_only,
+            safe_resume: self.safe_resume,
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            pool_idle_timeout: (self.pool_idle_timeout > 0).then(|| Duration::from_secs(self.pool_idle_timeout)),
+            verify_after_download: self.verify_after_download,
+            min_disk_speed: self.min_disk_speed,
+            trust_manifest_size: self.trust_manifest_size,
+            auth_token: self.auth_token.clone().map(Secret::new),
+            proxy_auth: self.proxy_auth.clone().map(Secret::new),
+            compare_dir: self.compare_dir.as_ref().map(PathBuf::from),
+            bandwidth_limit: self.bandwidth_limit,
+            limit_rate: self.limit_rate,
+            bandwidth_schedule: self.bwlimit_schedule.clone(),
+            user_agents: self.user_agents()?,
+            dns_cache_ttl: Duration::from_secs(self.dns_cache_ttl),
+            since: self.since,
+            since_missing: self.since_missing,
+            max_memory: self.max_memory,
+            progress_file: self.progress_file.as_ref().map(PathBuf::from),
+            progress_interval: Duration::from_secs(self.progress_interval.max(1)),
+            columns: ColumnMap {
+                name: self.name_col.clone(),
+                url: self.url_col.clone(),
+                hash: self.hash_col.clone(),
+                size: self.size_col.clone(),
+            },
+            keep_open_connections: self.keep_open_connections,
+            on_checksum_mismatch: self.on_checksum_mismatch,
+            continue_partial_from_other_tool: self.continue_partial_from_other_tool,
+            allow_smaller: self.allow_smaller,
+            bps_report_interval: self.bps_report_interval,
+            fsync_mode: self.fsync_mode,
+            hash_algo: self.hash_algo,
+            tar_index: self.tar_index,
+            per_file_log: self.per_file_log.clone(),
+            max_connections_global: self.max_connections_global,
+            verify_only_new: self.verify_only_new,
+            force_rehash: self.force_rehash,
+            output_permissions: self.output_permissions,
+            probe_ranges: self.probe_ranges,
+            honor_content_disposition: self.honor_content_disposition,
+            min_tls_version: self.min_tls_version,
+            quarantine_dir: self.quarantine_dir.clone(),
+            race_mirrors: self.race_mirrors,
+            trim_state_on_success: self.trim_state_on_success,
+            dedup: self.dedup,
+            http_version_fallback: self.http_version_fallback,
+            show_eta_overall: self.show_eta_overall,
+            segments: self.segments as usize,
+            write_buffer_size: self.write_buffer_size as usize,
+            flush_interval: self.flush_interval,
+            preallocate: self.preallocate,
+            force: self.force,
+            adaptive_concurrency: self.adaptive_concurrency.map(usize::from),
+        })
+    }
+
+    fn user_agents(&self) -> Result<Vec<String>> {
+        match &self.user_agent_file {
+            Some(path) => useragent::load_user_agent_file(path),
+            None if self.randomize_user_agent => Ok(useragent::BUILTIN_POOL.iter().map(|ua| ua.to_string()).collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn staging_dir(&self) -> Result<Option<PathBuf>> {
+        let dir = match &self.tmp_dir {
+            Some(dir) => PathBuf::from(dir),
+            None if !self.tmpfile_in_output => std::env::temp_dir().join("sa-1b-dl"),
+            None => return Ok(None),
+        };
+        fs::create_dir_all(&dir)?;
+        Ok(Some(dir))
+    }
+
+    /// Whether informational messages on stdout should be suppressed because
+    /// stdout carries machine-readable output.
+    pub fn quiet_stdout(&self) -> bool {
+        self.quiet_summary_json || self.print_urls.is_some()
+    }
+
+    /// Concurrency for verify and scrub hashing.
+    pub fn hash_threads(&self) -> usize {
+        self.parallel_hash_threads.unwrap_or(self.threads).max(1)
+    }
 }
@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// A TSV column given either by 0-based index or by its header name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnRef {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for ColumnRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(anyhow!("Column must be an index or a header name"));
+        }
+        Ok(match s.parse::<usize>() {
+            Ok(index) => ColumnRef::Index(index),
+            Err(_) => ColumnRef::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for ColumnRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnRef::Index(index) => write!(f, "{}", index),
+            ColumnRef::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Where each `LinkEntry` field lives in a TSV link file. The default is the
+/// standard `file_name<TAB>url` layout.
+#[derive(Debug, Clone)]
+pub struct ColumnMap {
+    pub name: ColumnRef,
+    pub url: ColumnRef,
+    pub hash: Option<ColumnRef>,
+    pub size: Option<ColumnRef>,
+}
+
+impl Default for ColumnMap {
+    fn default() -> Self {
+        Self {
+            name: ColumnRef::Index(0),
+            url: ColumnRef::Index(1),
+            hash: None,
+            size: None,
+        }
+    }
+}
+
+/// A `ColumnMap` with every column resolved to an index.
+#[derive(Debug, Clone, Copy)]
+pub struct Columns {
+    pub name: usize,
+    pub url: usize,
+    pub hash: Option<usize>,
+    pub size: Option<usize>,
+}
+
+impl Columns {
+    /// Whether `index` holds one of the mapped fields.
+    pub fn is_mapped(&self, index: usize) -> bool {
+        index == self.name || index == self.url || self.hash == Some(index) || self.size == Some(index)
+    }
+}
+
+impl ColumnMap {
+    /// Whether any column is referenced by header name, which makes the first
+    /// line of the file a mandatory header.
+    pub fn needs_header(&self) -> bool {
+        [Some(&self.name), Some(&self.url), self.hash.as_ref(), self.size.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|c| matches!(c, ColumnRef::Name(_)))
+    }
+
+    /// Resolves header names against `header` (the first line, split on tabs).
+    pub fn resolve(&self, header: Option<&[&str]>) -> Result<Columns> {
+        let find = |column: &ColumnRef| -> Result<usize> {
+            match column {
+                ColumnRef::Index(index) => Ok(*index),
+                ColumnRef::Name(name) => header
+                    .and_then(|h| h.iter().position(|c| c.trim() == name))
+                    .ok_or_else(|| anyhow!("Column '{}' not found in link file header", name)),
+            }
+        };
+        Ok(Columns {
+            name: find(&self.name)?,
+            url: find(&self.url)?,
+            hash: self.hash.as_ref().map(find).transpose()?,
+            size: self.size.as_ref().map(find).transpose()?,
+        })
+    }
+}
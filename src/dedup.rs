@@ -0,0 +1,62 @@
+//! `--dedup`: entries whose checksum matches a file already downloaded are hardlinked
+//! (or copied) to it instead of being fetched again.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+#[derive(Debug, Default)]
+pub struct DedupIndex {
+    /// Completed file for each checksum seen so far.
+    files: Mutex<HashMap<String, PathBuf>>,
+    /// One lock per checksum, so entries with the same content run one after another
+    /// and the later ones find the first one's file.
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+    saved_bytes: AtomicU64,
+}
+
+impl DedupIndex {
+    /// Waits until no other entry with `checksum` is being downloaded.
+    pub async fn lock(&self, checksum: &str) -> OwnedMutexGuard<()> {
+        let lock = Arc::clone(self.locks.lock().unwrap().entry(key(checksum)).or_default());
+        lock.lock_owned().await
+    }
+
+    pub fn record(&self, checksum: &str, path: PathBuf) {
+        self.files.lock().unwrap().insert(key(checksum), path);
+    }
+
+    /// A completed file with `checksum` that is still on disk.
+    pub fn lookup(&self, checksum: &str) -> Option<PathBuf> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(&key(checksum))
+            .filter(|path| path.exists())
+            .cloned()
+    }
+
+    /// Hardlinks `target` to `source`, copying when the filesystem can't link (e.g.
+    /// across devices). Returns the file size, which is also counted as saved.
+    pub fn link(&self, source: &Path, target: &Path) -> Result<u64> {
+        if fs::hard_link(source, target).is_err() {
+            fs::copy(source, target)
+                .with_context(|| format!("Failed to copy {} to {}", source.display(), target.display()))?;
+        }
+        let bytes = fs::metadata(target)?.len();
+        self.saved_bytes.fetch_add(bytes, Ordering::Relaxed);
+        Ok(bytes)
+    }
+
+    pub fn saved_bytes(&self) -> u64 {
+        self.saved_bytes.load(Ordering::Relaxed)
+    }
+}
+
+fn key(checksum: &str) -> String {
+    checksum.trim().to_ascii_lowercase()
+}
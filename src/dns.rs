@@ -0,0 +1,56 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Resolved addresses per host, with the time they were looked up.
+type Entries = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+/// Resolver with a short, explicit cache. Entries expire after `ttl` and can be
+/// dropped early with [`DnsCache::invalidate`] so a retry after a connect failure
+/// re-resolves and, with the shuffled address order, likely reaches another backend.
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    ttl: Duration,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::default(),
+        }
+    }
+
+    pub fn invalidate(&self, host: &str) {
+        self.entries.lock().unwrap().remove(host);
+    }
+}
+
+impl Resolve for DnsCache {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        if let Some((resolved_at, addrs)) = self.entries.lock().unwrap().get(&host)
+            && resolved_at.elapsed() < self.ttl
+        {
+            let addrs: Addrs = Box::new(addrs.clone().into_iter());
+            return Box::pin(async move { Ok(addrs) });
+        }
+
+        let cache = Arc::clone(&self.entries);
+        let ttl = self.ttl;
+        Box::pin(async move {
+            let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            // 打乱顺序，让重新解析后的连接尽量落到不同的后端节点
+            fastrand::shuffle(&mut addrs);
+            if !ttl.is_zero() {
+                cache.lock().unwrap().insert(host, (Instant::now(), addrs.clone()));
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
@@ -1,51 +1,494 @@
-use crate::models::{DownloadState, LinkEntry};
-use crate::state::StateManager;
+use crate::autotune;
+use crate::blockcrc::{BlockMismatch, BlockVerifier};
+use crate::checksum::{self, HashAlgo};
+use crate::columns::{ColumnMap, Columns};
+use crate::dedup::DedupIndex;
+use crate::dns::DnsCache;
+use crate::error::DownloadError;
+use crate::extract::ExtractPool;
+use crate::filelog::{self, FileLog};
+use crate::limiter::{BandwidthLimiter, MemoryBudget, RequestPacer, CHUNK_RESERVATION};
+use crate::listing;
+use crate::mirrors::{host_of, MirrorHealth};
+use crate::models::{DownloadState, FileOutcome, FileStatus, LinkEntry, ManifestLine, ManifestRecord, RunSummary};
+use crate::multipart;
+use crate::progress::{self, ProgressTracker};
+use crate::ranges::{self, RangeCache, RangeCapability};
+use crate::report;
+use crate::schedule::{BandwidthSchedule, Schedule};
+use crate::secret::{self, Secret};
+use crate::segments::{self, SegmentMap};
+use crate::since::{self, MissingLastModified, Since};
+use crate::source::{self, Body};
+use crate::state::{StateFormat, StateManager};
+use crate::tarcheck;
+use crate::units::{ByteRange, MIN_REQUEST_RATE};
+use crate::useragent;
+use crate::warmup::{self, Warmup};
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use futures::future::join_all;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::Client;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_DISPOSITION, LAST_MODIFIED, USER_AGENT};
+use reqwest::{Client, Method, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::sync::Semaphore;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
 use tokio::task;
 
+/// A file that hit `--max-runtime-per-file` this many runs in a row is counted as failed.
+const MAX_RUNTIME_STRIKES: u32 = 3;
+
+/// Bytes compared at each end of a file by `--verify-existing-before-download`.
+const VERIFY_SAMPLE_BYTES: u64 = 64 * 1024;
+
+/// How often the `--min-free` monitor polls the output filesystems.
+const FREE_SPACE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wall-clock window over which `--min-disk-speed` averages write throughput.
+const DISK_SPEED_WINDOW: Duration = Duration::from_secs(10);
+
+/// Minimum time spent inside writes during a window before its speed is judged,
+/// so a lone page-cache flush doesn't trip the guard.
+const DISK_SPEED_MIN_BUSY: Duration = Duration::from_secs(1);
+
+/// TCP keepalive probe interval for `--keep-open-connections`, so idle pooled
+/// connections survive NAT and load-balancer timeouts between files.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Deadline for a whole request, including its body, unless the stall watchdog
+/// (`--max-idle-time`) is watching the transfer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Deadline for a download GET watched by `--max-idle-time`: a healthy 10 GB transfer
+/// must not be cut off, and a stalled one is caught by the watchdog long before.
+const WATCHED_TRANSFER_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Measurement window of `--adaptive-concurrency`; long enough to smooth over files
+/// starting and finishing.
+const ADAPTIVE_WINDOW: Duration = Duration::from_secs(15);
+
+/// How often each `--segments` range rewrites the segment map while it downloads.
+const SEGMENT_MAP_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many directory levels `--expand-dirs` descends below a listed entry.
+const MAX_LISTING_DEPTH: usize = 8;
+
+/// Bytes each mirror must deliver in a `--race-mirrors` probe.
+const RACE_PROBE_BYTES: u64 = 256 * 1024;
+
+/// Directory under the output directory where `--manifest-url` caches the manifest.
+const MANIFEST_CACHE_DIR: &str = ".manifest";
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub resume: bool,
+    pub proxy: Option<String>,
+    pub retries: u32,
+    pub max_runtime_per_file: Option<Duration>,
+    /// Accepted response content types. Empty means anything except `text/html`.
+    pub content_types: Vec<String>,
+    /// Collapse nested `file_name`s to their basename instead of recreating the directories.
+    pub flatten: bool,
+    /// Daily window outside of which transfers pause.
+    pub schedule: Option<Schedule>,
+    /// Compare head/tail samples of existing files with the remote when no size is known.
+    pub verify_existing: bool,
+    /// Stop launching downloads once free space on any output falls below this many bytes.
+    pub min_free: Option<u64>,
+    /// Unpack completed tar shards next to the archive.
+    pub extract: bool,
+    /// Size of the extraction worker pool, independent of download concurrency.
+    pub extract_threads: usize,
+    /// Hide progress bars and suppress all non-error output.
+    pub quiet: bool,
+    /// Maximum HTTP requests per second across all workers.
+    pub requests_per_second: Option<f64>,
+    /// Directory for `.part` files; `None` keeps them next to the output file.
+    pub staging_dir: Option<PathBuf>,
+    /// Abort a transfer when no data arrives for this long, and retry from the
+    /// `.part` offset; `None` leaves only the overall request timeout.
+    pub max_idle_time: Option<Duration>,
+    pub state_format: StateFormat,
+    pub head_method: HeadMethod,
+    /// Cancel the run after this many consecutive file failures.
+    pub abort_after: Option<usize>,
+    /// Progress bar redraws per second.
+    pub refresh_rate: u8,
+    /// No live bars; print a per-file table when the batch finishes.
+    pub summary_only: bool,
+    /// Check the tar structure of a `.part` file and cut off a torn tail before resuming.
+    pub safe_resume: bool,
+    /// Upper bound on idle keep-alive connections per host; `None` keeps reqwest's default.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle pooled connection is kept; `None` never expires it.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Hash each file as soon as it completes and retry on a checksum mismatch.
+    pub verify_after_download: bool,
+    /// Fail a download when sustained disk write speed drops below this many bytes/s.
+    pub min_disk_speed: Option<u64>,
+    /// Use sizes from the manifest instead of asking the server, when present.
+    pub trust_manifest_size: bool,
+    /// Bearer token sent with every request.
+    pub auth_token: Option<Secret>,
+    /// `USER:PASSWORD` for the HTTP proxy.
+    pub proxy_auth: Option<Secret>,
+    /// Reference copy that `--mode verify` compares against instead of checksums.
+    pub compare_dir: Option<PathBuf>,
+    /// Total bytes per second across all workers.
+    pub bandwidth_limit: Option<u64>,
+    /// Cap on each connection's bytes per second, on top of the total cap.
+    pub limit_rate: Option<u64>,
+    /// Time-of-day bandwidth caps; takes precedence over `bandwidth_limit` inside its windows.
+    pub bandwidth_schedule: Option<BandwidthSchedule>,
+    /// Pool to draw a user agent from per request; empty keeps the default one.
+    pub user_agents: Vec<String>,
+    /// How long resolved addresses are reused; connect failures drop them early.
+    pub dns_cache_ttl: Duration,
+    /// Only download files the server modified after this cutoff.
+    pub since: Option<Since>,
+    /// How `since` treats files without a `Last-Modified` header.
+    pub since_missing: MissingLastModified,
+    /// Upper bound on bytes buffered across all workers between read and write.
+    pub max_memory: Option<u64>,
+    /// File rewritten with a JSON progress snapshot every `progress_interval`.
+    pub progress_file: Option<PathBuf>,
+    pub progress_interval: Duration,
+    /// Where the fields of a TSV link file live.
+    pub columns: ColumnMap,
+    /// Pre-open pooled connections before the batch and keep them alive between files.
+    pub keep_open_connections: bool,
+    /// What to do when a downloaded file fails its checksum (per `--hash-algo`) check.
+    pub on_checksum_mismatch: MismatchPolicy,
+    /// Adopt a plain incomplete file left by wget and the like as the `.part` to resume.
+    pub continue_partial_from_other_tool: bool,
+    /// Accept a file up to this many bytes shorter than its expected size.
+    pub allow_smaller: u64,
+    /// Print a throughput heartbeat line to stderr this often.
+    pub bps_report_interval: Option<Duration>,
+    /// When written data is flushed to disk with fsync.
+    pub fsync_mode: FsyncMode,
+    /// Algorithm of the checksums in `LinkEntry::checksum`.
+    pub hash_algo: HashAlgo,
+    /// Write a `<shard>.index.json` of member offsets while extracting.
+    pub tar_index: bool,
+    /// Directory receiving a `<file_name>.log` transcript per file.
+    pub per_file_log: Option<PathBuf>,
+    /// Cap on HTTP requests in flight across all files, whatever `--threads` allows.
+    pub max_connections_global: Option<usize>,
+    /// In verify and scrub mode, skip files not modified since they last passed.
+    pub verify_only_new: bool,
+    /// Hash every file in verify and scrub mode, overriding `verify_only_new` and
+    /// the scrub rehash interval.
+    pub force_rehash: bool,
+    /// Unix mode set on each file once it is in place.
+    pub output_permissions: Option<u32>,
+    /// Probe each host's range support once and restart instead of resuming where
+    /// it is missing.
+    pub probe_ranges: bool,
+    /// Save files under the name in the response's `Content-Disposition`, if any.
+    pub honor_content_disposition: bool,
+    /// Refuse TLS connections below this version; `None` keeps reqwest's default.
+    pub min_tls_version: Option<TlsVersion>,
+    /// Move files failing size or checksum validation here instead of deleting them.
+    pub quarantine_dir: Option<PathBuf>,
+    /// Pick the mirror for each attempt by racing a small ranged request against all of them.
+    pub race_mirrors: bool,
+    /// Delete the state file after a batch that left nothing to resume.
+    pub trim_state_on_success: bool,
+    /// Link entries whose checksum matches an already-downloaded file instead of
+    /// downloading them again.
+    pub dedup: bool,
+    /// Retry a host over HTTP/1.1 after an HTTP/2 stream or protocol error.
+    pub http_version_fallback: bool,
+    /// Show the time left for the whole batch on the overall progress bar.
+    pub show_eta_overall: bool,
+    /// Byte ranges fetched in parallel per file; 1 downloads each file over one connection.
+    pub segments: usize,
+    /// Bytes buffered per `.part` file before they are written out.
+    pub write_buffer_size: usize,
+    /// Longest time buffered bytes wait before being written out.
+    pub flush_interval: Option<Duration>,
+    /// Reserve disk space for each `.part` file up front when the size is known.
+    pub preallocate: bool,
+    /// Start a batch even when the free-space check says it won't fit.
+    pub force: bool,
+    /// Upper bound for `--adaptive-concurrency`, which starts at `--threads` and resizes
+    /// the worker pool from throughput and errors.
+    pub adaptive_concurrency: Option<usize>,
+}
+
+/// What happens to a file whose checksum (per `--hash-algo`) check fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchPolicy {
+    /// Delete the file and download it again, up to --retries times
+    #[default]
+    Retry,
+    /// Keep the file for inspection and stop the whole run
+    Abort,
+    /// Delete the file, mark it failed and move on to the next one
+    Skip,
+}
+
+/// When `download_file` forces written data to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FsyncMode {
+    /// Never fsync; rely on the OS to write back dirty pages
+    None,
+    /// Fsync each `.part` once before it is renamed into place
+    #[default]
+    PerFile,
+    /// Fsync after every chunk written: crash-safe but much slower
+    PerChunk,
+}
+
+/// Lowest TLS version the client will negotiate (`--min-tls-version`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    V1_2,
+    #[value(name = "1.3")]
+    V1_3,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsVersion::V1_2 => write!(f, "TLS 1.2"),
+            TlsVersion::V1_3 => write!(f, "TLS 1.3"),
+        }
+    }
+}
+
+/// How `download_file` learns the remote file size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HeadMethod {
+    /// HEAD request, reading Content-Length
+    #[default]
+    Head,
+    /// `Range: bytes=0-0` GET, reading the total from Content-Range
+    GetRange,
+}
+
+/// Returned when a file is abandoned after exceeding `--max-runtime-per-file`.
+/// The `.part` file is kept so the next run can resume it.
+#[derive(Debug)]
+pub struct RuntimeExceeded {
+    pub file_name: String,
+    pub limit: Duration,
+}
+
+impl fmt::Display for RuntimeExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} exceeded max runtime of {}s, deferred to next run",
+            self.file_name,
+            self.limit.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for RuntimeExceeded {}
+
+/// Returned from the chunk loop when the `--schedule` window closes mid-transfer.
+/// The retry loop waits for the window to reopen and resumes from the `.part` file.
+#[derive(Debug)]
+pub struct SchedulePaused;
+
+impl fmt::Display for SchedulePaused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "paused outside download schedule")
+    }
+}
+
+impl std::error::Error for SchedulePaused {}
+
+/// Returned for in-flight downloads cancelled by the `--abort-after` circuit breaker.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled after too many consecutive failures")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Tracks how long `write_all` calls take, separately from time spent waiting on the network.
+struct WriteSpeedMonitor {
+    minimum: u64,
+    window_start: Instant,
+    busy: Duration,
+    bytes: u64,
+}
+
+impl WriteSpeedMonitor {
+    fn new(minimum: u64) -> Self {
+        Self {
+            minimum,
+            window_start: Instant::now(),
+            busy: Duration::ZERO,
+            bytes: 0,
+        }
+    }
+
+    /// Records one write. Returns the window's write speed if it closed below the minimum.
+    fn record(&mut self, bytes: usize, took: Duration) -> Option<u64> {
+        self.bytes += bytes as u64;
+        self.busy += took;
+        if self.window_start.elapsed() < DISK_SPEED_WINDOW {
+            return None;
+        }
+
+        let slow = (self.busy >= DISK_SPEED_MIN_BUSY)
+            .then(|| (self.bytes as f64 / self.busy.as_secs_f64()) as u64)
+            .filter(|&speed| speed < self.minimum);
+        self.window_start = Instant::now();
+        self.busy = Duration::ZERO;
+        self.bytes = 0;
+        slow
+    }
+}
+
+/// A file skipped by `--since` because the server copy is not newer than the cutoff.
+#[derive(Debug)]
+pub struct NotModified {
+    pub file_name: String,
+}
+
+impl fmt::Display for NotModified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} not modified since the --since cutoff", self.file_name)
+    }
+}
+
+impl std::error::Error for NotModified {}
+
 pub struct Downloader {
     client: Client,
+    /// Primary output directory; holds the state and cursor files.
     output_dir: PathBuf,
+    /// All destinations shards are striped across (the primary comes first), with
+    /// symlinks resolved so path checks compare against the real location.
+    output_dirs: Vec<PathBuf>,
     state_manager: StateManager,
-    resume: bool,
-    retries: u32,
+    options: DownloadOptions,
+    health: Arc<MirrorHealth>,
+    pacer: Option<Arc<RequestPacer>>,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    dns: Arc<DnsCache>,
+    memory: Option<Arc<MemoryBudget>>,
+    /// `--max-connections-global`: one permit per open HTTP request.
+    connections: Option<Arc<Semaphore>>,
+    /// `--probe-ranges`: per-host range support, shared by all clones.
+    ranges: Option<Arc<RangeCache>>,
+    /// Files moved to `--quarantine-dir` during this run, shared by all clones.
+    quarantined: Arc<Mutex<Vec<PathBuf>>>,
+    /// `--http-version-fallback`: client forced to HTTP/1.1, and the hosts that
+    /// have been switched to it after an HTTP/2 error.
+    http1_client: Option<Client>,
+    http1_hosts: Arc<Mutex<HashSet<String>>>,
+    /// Failed download attempts so far, the congestion signal for `--adaptive-concurrency`.
+    failed_attempts: Arc<AtomicU64>,
 }
 
 impl Downloader {
-    pub fn new(output_dir: &str, resume: bool, proxy: Option<&str>, retries: u32) -> Result<Self> {
-        let output_path = PathBuf::from(output_dir);
-        if !output_path.exists() {
-            fs::create_dir_all(&output_path)?;
+    pub fn new(output_dirs: &[String], options: DownloadOptions) -> Result<Self> {
+        let mut canonical_dirs: Vec<PathBuf> = Vec::with_capacity(output_dirs.len());
+        for dir in output_dirs.iter().map(PathBuf::from) {
+            if !dir.exists() {
+                // exists() 对悬空或循环的符号链接返回 false，此时不能再尝试创建
+                if dir.symlink_metadata().is_ok() {
+                    return Err(anyhow!(
+                        "Output directory {} is a symlink that does not resolve (dangling or a loop)",
+                        dir.display()
+                    ));
+                }
+                fs::create_dir_all(&dir).map_err(|e| DownloadError::from_io(e, &dir))?;
+            }
+            let canonical = dir.canonicalize().map_err(|e| DownloadError::from_io(e, &dir))?;
+            if !canonical.is_dir() {
+                return Err(anyhow!("Output path {} is not a directory", dir.display()));
+            }
+            if canonical_dirs.contains(&canonical) {
+                return Err(anyhow!(
+                    "Output directory {} resolves to {}, which is already an output directory",
+                    dir.display(),
+                    canonical.display()
+                ));
+            }
+            canonical_dirs.push(canonical);
         }
+        let output_dirs = canonical_dirs;
+        let output_path = output_dirs
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow!("At least one output directory is required"))?;
+
+        let state_manager = StateManager::new(&output_path, options.state_format);
+
+        let pacer = match options.requests_per_second {
+            Some(rate) if rate.is_finite() && rate >= MIN_REQUEST_RATE => Some(Arc::new(RequestPacer::new(rate))),
+            Some(rate) => return Err(anyhow!("Invalid request rate: {}", rate)),
+            None => None,
+        };
 
-        let state_manager = StateManager::new(&output_path);
-
-        let mut client_builder = Client::builder()
-            .timeout(Duration::from_secs(300));
-
-        if let Some(proxy_url) = proxy {
-            client_builder = client_builder.proxy(reqwest::Proxy::http(proxy_url)?);
+        if options.limit_rate == Some(0) {
+            return Err(anyhow!("Invalid per-connection rate limit: 0"));
+        }
+        if options.bandwidth_limit == Some(0) {
+            return Err(anyhow!("Invalid bandwidth limit: 0"));
         }
 
-        let client = client_builder
-            .build()
-            .context("Failed to build HTTP client")?;
+        let bandwidth = (options.bandwidth_limit.is_some() || options.bandwidth_schedule.is_some()).then(|| {
+            Arc::new(BandwidthLimiter::new(options.bandwidth_limit, options.bandwidth_schedule.clone()))
+        });
+
+        let memory = options.max_memory.map(|bytes| Arc::new(MemoryBudget::new(bytes)));
+        let connections = options
+            .max_connections_global
+            .map(|limit| Arc::new(Semaphore::new(limit.max(1))));
+        // 分段下载依赖 Range 支持，同样需要按主机探测
+        let ranges = (options.probe_ranges || options.segments > 1).then(|| Arc::new(RangeCache::default()));
+
+        let dns = Arc::new(DnsCache::new(options.dns_cache_ttl));
+        let client = Self::build_client(&options, &dns, false)?;
+        // HTTP/2 出错的主机改用这个强制 HTTP/1.1 的客户端
+        let http1_client = if options.http_version_fallback {
+            Some(Self::build_client(&options, &dns, true)?)
+        } else {
+            None
+        };
 
         Ok(Self {
             client,
             output_dir: output_path,
+            output_dirs,
             state_manager,
-            resume,
-            retries,
+            options,
+            health: Arc::new(MirrorHealth::default()),
+            pacer,
+            bandwidth,
+            dns,
+            memory,
+            connections,
+            ranges,
+            quarantined: Arc::default(),
+            http1_client,
+            http1_hosts: Arc::default(),
+            failed_attempts: Arc::default(),
         })
     }
 
@@ -53,335 +496,3224 @@ impl Downloader {
         let file = File::open(path).context("Failed to open link file")?;
         let reader = BufReader::new(file);
 
+        let map = &self.options.columns;
+        let mut columns: Option<Columns> = None;
         let mut entries = Vec::new();
+        let mut ignored_sha256 = 0usize;
 
         for (idx, line) in reader.lines().enumerate() {
             let line = line.context("Failed to read line")?;
 
-            if idx == 0 && line.starts_with("file_name") {
+            // JSON-lines 清单：每行一个对象，可携带 sha256、size 和其他元数据
+            if line.trim_start().starts_with('{') {
+                let manifest_line: ManifestLine = serde_json::from_str(&line)
+                    .with_context(|| format!("Invalid JSON on line {}", idx + 1))?;
+                validate_file_name(&manifest_line.file_name)
+                    .with_context(|| format!("Invalid entry on line {}", idx + 1))?;
+                let mut entry: LinkEntry = manifest_line.into();
+                // sha256 字段只能按 SHA-256 校验，与 --hash-algo md5/sha1 不符时忽略
+                if matches!(self.options.hash_algo, HashAlgo::Md5 | HashAlgo::Sha1) && entry.checksum.take().is_some() {
+                    ignored_sha256 += 1;
+                }
+                entries.push(entry);
                 continue;
             }
 
             let parts: Vec<&str> = line.split('\t').collect();
-            if parts.len() >= 2 {
-                entries.push(LinkEntry {
-                    file_name: parts[0].to_string(),
-                    url: parts[1].to_string().trim().to_string(),
-                });
+            // 首行若按列名映射、以 file_name 开头或 URL 列不是 URL，则视为表头
+            if idx == 0
+                && (map.needs_header()
+                    || line.starts_with("file_name")
+                    || map.resolve(None).is_ok_and(|c| parts.get(c.url).is_some_and(|u| !u.contains("://"))))
+            {
+                columns = Some(map.resolve(Some(&parts))?);
+                continue;
             }
+            let columns = match columns {
+                Some(columns) => columns,
+                None => *columns.insert(map.resolve(None)?),
+            };
+
+            let (Some(file_name), Some(url)) = (parts.get(columns.name), parts.get(columns.url)) else {
+                continue;
+            };
+            let file_name = file_name.trim();
+            validate_file_name(file_name).with_context(|| format!("Invalid entry on line {}", idx + 1))?;
+            let field = |index: Option<usize>| {
+                index
+                    .and_then(|i| parts.get(i))
+                    .map(|p| p.trim())
+                    .filter(|p| !p.is_empty())
+            };
+
+            // 未映射的列中，URL 列作为镜像地址，纯数字列作为文件大小
+            let extra: Vec<&str> = parts
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !columns.is_mapped(*i))
+                .map(|(_, p)| p.trim())
+                .collect();
+            let mirrors = extra
+                .iter()
+                .filter(|p| p.contains("://"))
+                .map(|p| p.to_string())
+                .collect();
+            let size = match columns.size {
+                Some(_) => field(columns.size)
+                    .map(|p| p.parse::<u64>())
+                    .transpose()
+                    .with_context(|| format!("Invalid size on line {}", idx + 1))?,
+                None => extra.iter().find_map(|p| p.parse::<u64>().ok()),
+            };
+            entries.push(LinkEntry {
+                file_name: file_name.to_string(),
+                url: url.trim().to_string(),
+                checksum: field(columns.hash).map(|h| h.to_ascii_lowercase()),
+                mirrors,
+                block_crcs: None,
+                size,
+                metadata: None,
+            });
         }
 
+        if ignored_sha256 > 0 {
+            eprintln!(
+                "Warning: ignored the sha256 field of {} JSON-lines entries because --hash-algo is {}",
+                ignored_sha256,
+                if self.options.hash_algo == HashAlgo::Md5 { "md5" } else { "sha1" }
+            );
+        }
         Ok(entries)
     }
 
+    /// Replaces entries whose URL ends in `/` with the files found by recursively
+    /// fetching their directory listings. Each file is named after the entry plus its
+    /// path below the listed directory.
+    pub async fn expand_dirs(&self, entries: Vec<LinkEntry>) -> Result<Vec<LinkEntry>> {
+        let mut expanded = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if !entry.url.ends_with('/') {
+                expanded.push(entry);
+                continue;
+            }
+
+            let root = reqwest::Url::parse(&entry.url)
+                .with_context(|| format!("Invalid directory URL for {}: {}", entry.file_name, entry.url))?;
+            let prefix = entry.file_name.trim_end_matches('/');
+            let mut pending = vec![(root, String::new(), 0)];
+            while let Some((dir, path, depth)) = pending.pop() {
+                let _connection = self.connection().await;
+                self.pace().await;
+                let response = self
+                    .get(dir.as_str())
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .with_context(|| format!("Failed to list {}", dir))?;
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                let body = response.text().await.with_context(|| format!("Failed to read listing {}", dir))?;
+
+                for item in listing::parse_listing(&dir, &content_type, &body)? {
+                    let name = format!("{}{}", path, item.name);
+                    if item.is_dir {
+                        // 超过深度的子目录直接忽略，避免循环链接导致无限递归
+                        if depth < MAX_LISTING_DEPTH {
+                            pending.push((item.url, format!("{}/", name), depth + 1));
+                        }
+                        continue;
+                    }
+                    expanded.push(LinkEntry {
+                        file_name: format!("{}/{}", prefix, name),
+                        url: item.url.to_string(),
+                        checksum: None,
+                        mirrors: Vec::new(),
+                        block_crcs: None,
+                        size: item.size,
+                        metadata: entry.metadata.clone(),
+                    });
+                }
+            }
+        }
+        Ok(expanded)
+    }
+
     async fn download_file_with_retry(
         &self,
         entry: &LinkEntry,
         state: Arc<Mutex<DownloadState>>,
         pb: &ProgressBar,
+    ) -> Result<()> {
+        let log = match &self.options.per_file_log {
+            Some(dir) => Some(Arc::new(FileLog::open(dir, &entry.file_name)?)),
+            None => None,
+        };
+        filelog::scope(log, self.retry_download(entry, state, pb)).await
+    }
+
+    async fn retry_download(
+        &self,
+        entry: &LinkEntry,
+        state: Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
     ) -> Result<()> {
         let mut attempt = 0u32;
-        let _partial_path = format!("{}.part", self.output_dir.join(&entry.file_name).display());
         let original_message = pb.message().to_string();
 
         loop {
             attempt += 1;
+            self.wait_for_schedule(pb).await;
 
-            match self.download_file(entry, state.clone(), pb).await {
-                Ok(_) => {
-                    // 成功后恢复原来的消息（移除重试信息）
-                    if attempt > 1 && !pb.is_finished() {
-                        pb.set_message(original_message.clone());
+            // 每次尝试都选择当前最健康的镜像，或用 --race-mirrors 选出最快的
+            let attempt_entry = if entry.mirrors.is_empty() {
+                entry.clone()
+            } else {
+                let raced = if self.options.race_mirrors {
+                    self.race_mirrors(entry, pb).await
+                } else {
+                    None
+                };
+                let url = raced.unwrap_or_else(|| self.health.rank(&entry.urls())[0].to_string());
+                LinkEntry { url, ..entry.clone() }
+            };
+            filelog::note(|| format!("Attempt {}/{}: {}", attempt, self.options.retries, secret::redact_url(&attempt_entry.url)));
+            let attempt_started = Instant::now();
+
+            let result = match self.options.max_runtime_per_file {
+                Some(limit) => {
+                    match tokio::time::timeout(limit, self.download_file(&attempt_entry, state.clone(), pb)).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            self.health.record_outcome(&attempt_entry.url, false);
+                            return Err(self.abandon_for_runtime(entry, &state, pb, limit));
+                        }
+                    }
+                }
+                None => self.download_file(&attempt_entry, state.clone(), pb).await,
+            };
+
+            // 握手因版本过低失败时给出明确原因，且不再重试
+            let result = result.map_err(|e| self.explain_tls_version(e, &attempt_entry.url));
+
+            filelog::note(|| {
+                let offset = state.lock().unwrap().downloaded_bytes;
+                match &result {
+                    Ok(_) => format!("Attempt {} finished in {:.1?} at byte {}", attempt, attempt_started.elapsed(), offset),
+                    Err(e) => format!("Attempt {} ended after {:.1?} at byte {}: {:#}", attempt, attempt_started.elapsed(), offset, e),
+                }
+            });
+
+            match &result {
+                Ok(_) => self.health.record_outcome(&attempt_entry.url, true),
+                Err(e) if e.is::<NotModified>() => self.health.record_outcome(&attempt_entry.url, true),
+                // 本地写入失败与镜像无关
+                Err(e) if e.is::<SchedulePaused>() || matches!(e.downcast_ref(), Some(DownloadError::NotWritable { .. })) => {}
+                Err(e) => {
+                    self.health.record_outcome(&attempt_entry.url, false);
+                    self.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                    // 连接阶段失败时丢弃 DNS 缓存，重试时重新解析，可能落到其他节点
+                    if is_connect_error(e) {
+                        self.dns.invalidate(&host_of(&attempt_entry.url));
                     }
+                }
+            }
+
+            match result {
+                Ok(_) => {
+                    state.lock().unwrap().timeouts = 0;
+                    // 成功后清除重试提示
+                    pb.set_prefix("");
                     return Ok(());
                 }
-                Err(e) if attempt < self.retries => {
+                Err(e) if e.is::<SchedulePaused>() => {
+                    // Pausing is not a failure, so it doesn't use up a retry
+                    attempt -= 1;
+                }
+                Err(e) if self.http1_client.is_some()
+                    && is_http2_error(&e)
+                    && self.http1_hosts.lock().unwrap().insert(host_of(&attempt_entry.url)) =>
+                {
+                    // 换用 HTTP/1.1 立即重试，不计入重试次数；该主机之后的请求都走 HTTP/1.1
+                    pb.println(format!(
+                        "{}: HTTP/2 error from {}, switching to HTTP/1.1: {:#}",
+                        entry.file_name,
+                        host_of(&attempt_entry.url),
+                        e
+                    ));
+                    filelog::note(|| "Switching to HTTP/1.1 after an HTTP/2 error".to_string());
+                    attempt -= 1;
+                }
+                // 磁盘问题、4xx 等重试也无济于事
+                Err(e) if e.downcast_ref::<DownloadError>().is_some_and(|d| !d.is_retryable()) => return Err(e),
+                Err(e) if is_checksum_mismatch(&e) && self.options.on_checksum_mismatch != MismatchPolicy::Retry => {
+                    return Err(e);
+                }
+                Err(e) if e.is::<NotModified>() => return Err(e),
+                Err(e) if attempt < self.options.retries => {
                     // Wait before retry (exponential backoff: 1s, 2s, 4s...)
                     let delay_ms = 1000 * (1 << (attempt - 1)).min(30000);
+                    filelog::note(|| format!("Retrying in {}s", delay_ms / 1000));
 
-                    // 在进度条上显示重试信息
-                    pb.set_message(format!("{} [Retry {}/{}: {}s wait...]", original_message, attempt, self.retries, delay_ms / 1000));
+                    // 重试信息放在 prefix 字段，显示在速度之后，不覆盖文件名和进度条
+                    pb.set_prefix(format!("[retry {}/{} in {}s: {}]", attempt, self.options.retries, delay_ms / 1000, e));
                     tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                    pb.set_prefix(format!("[retry {}/{} after: {}]", attempt, self.options.retries, e));
 
-                    // 恢复进度条显示（如果还没完成）
-                    if !pb.is_finished() {
-                        pb.set_message(format!("{} [Retry {}/{}: {}]", original_message, attempt, self.retries, e));
-                    }
+                    // 失败的那次尝试可能把消息改成了 "Size mismatch!" 等状态
+                    pb.set_message(original_message.clone());
                 }
                 Err(e) => return Err(e),
             }
         }
     }
 
-    async fn download_file(
-        &self,
-        entry: &LinkEntry,
-        state: Arc<Mutex<DownloadState>>,
-        pb: &ProgressBar,
-    ) -> Result<()> {
-        let output_path = self.output_dir.join(&entry.file_name);
-        let partial_path = format!("{}.part", output_path.display());
-
-        let response = self
-            .client
-            .head(&entry.url)
-            .send()
-            .await
-            .context("HEAD request failed")?;
-
-        let total_bytes = response
-            .headers()
-            .get("content-length")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse::<u64>().ok());
-
-        if output_path.exists() {
-            let actual_size = fs::metadata(&output_path)?.len();
-            let is_valid = if let Some(expected) = total_bytes {
-                actual_size == expected
-            } else {
-                actual_size > 0
-            };
-
-            if is_valid {
-                {
-                    let mut state = state.lock().unwrap();
-                    state.completed = true;
-                    state.downloaded_bytes = actual_size;
+    /// Requests the first [`RACE_PROBE_BYTES`] from every mirror of `entry` at once and
+    /// returns the one that delivered them first; the slower probes are dropped. `None`
+    /// when every probe failed.
+    async fn race_mirrors(&self, entry: &LinkEntry, pb: &ProgressBar) -> Option<String> {
+        let probes = entry.urls().into_iter().map(|url| {
+            Box::pin(async move {
+                let _connection = self.connection().await;
+                self.pace().await;
+                let started = Instant::now();
+                let mut response = self
+                    .get(url)
+                    .header("Range", format!("bytes=0-{}", RACE_PROBE_BYTES - 1))
+                    .send()
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
                 }
-                pb.set_message("Skipped (valid)");
-                pb.finish();
-                return Ok(());
-            } else {
-                fs::remove_file(&output_path)?;
-                pb.set_message("Removed invalid file, re-downloading...");
+                // 忽略 Range 的镜像会回 200 和整个文件，只读探测所需的字节后丢弃响应
+                let mut received = 0;
+                while received < RACE_PROBE_BYTES {
+                    let Some(chunk) = response.chunk().await? else {
+                        break;
+                    };
+                    received += chunk.len() as u64;
+                }
+                anyhow::Ok((url, started.elapsed()))
+            })
+        });
+        match futures::future::select_ok(probes).await {
+            Ok(((url, elapsed), _slower)) => {
+                self.health.record_latency(url, elapsed);
+                pb.println(format!(
+                    "{}: mirror race won by {} in {:.0?}",
+                    entry.file_name,
+                    secret::redact_url(url),
+                    elapsed
+                ));
+                filelog::note(|| format!("Mirror race won by {} in {:.1?}", secret::redact_url(url), elapsed));
+                Some(url.to_string())
+            }
+            Err(e) => {
+                filelog::note(|| format!("Mirror race failed on every mirror: {:#}", e));
+                None
             }
         }
+    }
 
-        let mut current_pos = 0u64;
-        if self.resume && Path::new(&partial_path).exists() {
-            current_pos = fs::metadata(&partial_path)?.len();
+    /// Turns a handshake failure caused by `--min-tls-version` into a
+    /// [`DownloadError::TlsVersion`]; other errors pass through.
+    fn explain_tls_version(&self, error: anyhow::Error, url: &str) -> anyhow::Error {
+        let Some(minimum) = self.options.min_tls_version else {
+            return error;
+        };
+        if !is_tls_version_error(&error) {
+            return error;
         }
-
-        if let Some(total) = total_bytes {
-            if current_pos > 0 && current_pos > total {
-                return Err(anyhow!(
-                    "Partial file size {} exceeds expected size {} for {}",
-                    current_pos, total, entry.file_name
-                ));
-            }
+        DownloadError::TlsVersion {
+            host: host_of(url),
+            minimum: minimum.to_string(),
         }
+        .into()
+    }
 
-        {
-            let mut state = state.lock().unwrap();
-            state.total_bytes = total_bytes;
+    /// Hashes `path` when `--verify-after-download` is set and a checksum is known,
+    /// returning the mismatch if the digest differs.
+    async fn checksum_mismatch(&self, entry: &LinkEntry, path: &Path, pb: &ProgressBar) -> Result<Option<DownloadError>> {
+        if !self.options.verify_after_download {
+            return Ok(None);
         }
+        let Some(expected) = entry.checksum.clone() else {
+            return Ok(None);
+        };
 
-        if let Some(total) = total_bytes {
-            if current_pos >= total {
-                self.rename_partial_to_complete(&partial_path, &output_path)?;
-                self.finalize_download(entry, state, pb, total_bytes, true)?;
-                return Ok(());
-            }
+        pb.set_message(format!("{} [Verifying]", entry.file_name));
+        let hash_path = path.to_path_buf();
+        let (check, algo) = (expected.clone(), self.options.hash_algo);
+        let actual = task::spawn_blocking(move || checksum::hash_file_for(&hash_path, &check, algo))
+            .await
+            .map_err(|e| anyhow!("Task error: {}", e))??;
 
-            pb.set_length(total);
-            pb.set_position(current_pos);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("{msg:30} {bar:40} {bytes}/{total_bytes} ({bytes_per_sec})")
-                    .unwrap()
-                    .progress_chars("=>-"),
-            );
+        if actual == expected {
+            return Ok(None);
         }
+        Ok(Some(DownloadError::ChecksumMismatch {
+            file_name: entry.file_name.clone(),
+            expected,
+            actual,
+        }))
+    }
 
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&partial_path)
-            .context("Failed to open output file")?;
-
-        let mut request = self.client.get(&entry.url);
-        if current_pos > 0 {
-            request = request.header("Range", format!("bytes={}-", current_pos));
+    /// Whether `--verify-only-new` may skip `path`: it passed a verify or scrub and has
+    /// not been modified since.
+    fn unchanged_since_verify(&self, state: &DownloadState, path: &Path) -> bool {
+        if !self.options.verify_only_new || self.options.force_rehash {
+            return false;
         }
+        let Some(verified_at) = state.verified_at else {
+            return false;
+        };
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| DateTime::<Utc>::from(modified) <= verified_at)
+    }
 
-        let mut response = request.send().await.context("GET request failed")?;
+    /// The entry's checksum if it is a SHA-256, the digest recorded in state and manifests.
+    fn sha256_checksum(&self, entry: &LinkEntry) -> Option<String> {
+        entry
+            .checksum
+            .clone()
+            .filter(|c| self.options.hash_algo.resolve(c).is_ok_and(|algo| algo == HashAlgo::Sha256))
+    }
 
-        if !response.status().is_success() && response.status() != 206 {
-            return Err(anyhow!("HTTP request failed: {}", response.status()));
+    /// Whether `actual` bytes on disk count as the complete file: an exact match, or
+    /// at most `--allow-smaller` bytes short, which is logged.
+    fn size_matches(&self, entry: &LinkEntry, actual: u64, expected: u64, pb: &ProgressBar) -> bool {
+        if actual == expected {
+            return true;
         }
-
-        let mut downloaded = 0u64;
-
-        while let Some(chunk) = response.chunk().await? {
-            let n = chunk.len();
-            if n == 0 {
-                break;
-            }
-            file.write_all(&chunk)?;
-            downloaded += n as u64;
-
-            let total_downloaded = current_pos + downloaded;
-            {
-                let mut state = state.lock().unwrap();
-                state.downloaded_bytes = total_downloaded;
-            }
-            if total_bytes.is_some() {
-                pb.set_position(total_downloaded);
-            }
+        let shortfall = expected.saturating_sub(actual);
+        if actual > expected || shortfall > self.options.allow_smaller {
+            return false;
         }
+        pb.println(format!(
+            "{}: accepting {} bytes, {} short of the expected {} (--allow-smaller {})",
+            entry.file_name, actual, shortfall, expected, self.options.allow_smaller
+        ));
+        true
+    }
 
-        fs::rename(&partial_path, &output_path)?;
-
-        let actual_size = fs::metadata(&output_path)?.len();
+    /// Validates the `.part` file's size (and hash, if enabled) and only then renames it
+    /// to its final name, so a crash can never leave an unvalidated file under that name.
+    async fn complete_partial(
+        &self,
+        entry: &LinkEntry,
+        state: Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
+        partial_path: &Path,
+        output_path: &Path,
+        total_bytes: Option<u64>,
+    ) -> Result<()> {
+        let actual_size = fs::metadata(partial_path)?.len();
         let expected_size = total_bytes.unwrap_or(0);
 
-        let is_valid = if expected_size > 0 {
-            actual_size == expected_size
+        let is_manifest = state.lock().unwrap().is_manifest;
+        let is_valid = if is_manifest {
+            // 清单可能远小于 1 KiB：能完整解析出条目才算下载完成
+            (expected_size == 0 || actual_size == expected_size) && self.parses_as_link_file(partial_path)
+        } else if expected_size > 0 {
+            self.size_matches(entry, actual_size, expected_size, pb)
         } else {
             actual_size > 1024
         };
 
-        self.finalize_download(entry, state, pb, total_bytes, is_valid)?;
-
+        if !is_valid && is_manifest {
+            return Err(anyhow!("Downloaded manifest {} is empty or not a valid link file", partial_path.display()));
+        }
         if !is_valid {
-            return Err(anyhow!(
-                "File size mismatch for {}: expected {} bytes, got {} bytes",
-                entry.file_name,
-                expected_size,
-                actual_size
-            ));
+            // 比预期短的 .part 保留下来供续传，比预期长的说明数据有误，只能重新下载
+            if expected_size > 0 && actual_size > expected_size {
+                self.discard(entry, partial_path, "size-mismatch", pb)?;
+                state.lock().unwrap().downloaded_bytes = 0;
+            }
+            pb.set_message("Size mismatch!");
+            return Err(DownloadError::SizeMismatch {
+                file_name: entry.file_name.clone(),
+                expected: expected_size,
+                actual: actual_size,
+            }
+            .into());
         }
 
-        Ok(())
-    }
+        if let Some(mismatch) = self.checksum_mismatch(entry, partial_path, pb).await? {
+            // abort 时保留文件供排查镜像问题
+            if self.options.on_checksum_mismatch != MismatchPolicy::Abort {
+                self.discard(entry, partial_path, "checksum-mismatch", pb)?;
+                state.lock().unwrap().downloaded_bytes = 0;
+            }
+            pb.set_message("Checksum mismatch!");
+            return Err(mismatch.into());
+        }
+        if self.options.verify_after_download
+            && let Some(expected) = self.sha256_checksum(entry)
+        {
+            // 已校验的哈希记入状态，作为之后 scrub 的基准
+            let mut state = state.lock().unwrap();
+            state.sha256 = Some(expected);
+            state.hashed_at = Some(Utc::now());
+        }
 
-    fn rename_partial_to_complete(&self, partial_path: &str, output_path: &PathBuf) -> Result<()> {
-        fs::rename(partial_path, output_path).context("Failed to rename completed file")?;
-        Ok(())
+        // 下载期间另一条目可能已用同一个 Content-Disposition 名字完成，退回清单中的名字
+        let fallback;
+        let output_path = if output_path.exists() && state.lock().unwrap().saved_as.is_some() {
+            fallback = self.output_path(entry, &self.destination(&state))?;
+            pb.println(format!(
+                "{}: {} was taken while downloading, saving under the link file's name",
+                entry.file_name,
+                output_path.display()
+            ));
+            state.lock().unwrap().saved_as = None;
+            fallback.as_path()
+        } else {
+            output_path
+        };
+
+        self.move_file(partial_path, output_path)?;
+        self.finalize_download(state, pb, output_path)
     }
 
-    fn finalize_download(
-        &self,
-        entry: &LinkEntry,
-        state: Arc<Mutex<DownloadState>>,
-        pb: &ProgressBar,
-        _total_bytes: Option<u64>,
-        is_valid: bool,
-    ) -> Result<()> {
-        let actual_size = fs::metadata(&self.output_dir.join(&entry.file_name))?.len();
+    /// Pre-opens up to `connections` pooled connections to each host in `entries`
+    /// for `--keep-open-connections`. Hosts that fail the warm-up are skipped.
+    pub async fn warm_up(&self, entries: &[LinkEntry], connections: usize) -> Vec<Warmup> {
+        let mut hosts: Vec<(String, String)> = Vec::new();
+        for entry in entries {
+            let url = self.health.rank(&entry.urls())[0].to_string();
+            if source::local_path(&url).is_some() {
+                continue;
+            }
+            let host = host_of(&url);
+            if !hosts.iter().any(|(h, _)| *h == host) {
+                hosts.push((host, url));
+            }
+        }
 
-        {
-            let mut state = state.lock().unwrap();
-            state.completed = is_valid;
-            state.downloaded_bytes = actual_size;
+        let mut warmed = Vec::new();
+        for (host, url) in hosts {
+            // 连接数不超过该主机上的文件数
+            let files = entries.iter().filter(|e| host_of(&e.url) == host).count();
+            let connections = connections.min(files).min(self.options.max_connections_global.unwrap_or(usize::MAX));
+            match warmup::warm_host(&self.client, &host, &url, connections).await {
+                Ok(warmup) => warmed.push(warmup),
+                Err(e) => eprintln!("Warning: {:#}", e),
+            }
         }
+        warmed
+    }
 
-        if is_valid {
-            pb.set_message("Done");
-        } else {
-            pb.set_message("Size mismatch!");
+    /// Picks a worker count for `--workers-auto` by probing the healthiest URL of the
+    /// first entry. Capped by CPU count when extraction also competes for cores.
+    pub async fn auto_workers(&self, entries: &[LinkEntry], fallback: usize) -> Result<usize> {
+        let Some(entry) = entries.first() else {
+            return Ok(fallback);
+        };
+        let url = self.health.rank(&entry.urls())[0].to_string();
+        // 本地/NFS 源没有网络瓶颈可测
+        if source::local_path(&url).is_some() {
+            return Ok(fallback);
         }
-        pb.finish();
 
-        Ok(())
+        let mut workers = autotune::probe_workers(&self.client, &url).await?;
+        if self.options.extract {
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+            workers = workers.min(cpus);
+        }
+        Ok(workers)
     }
 
-    pub async fn download_all(&self, entries: Vec<LinkEntry>, num_threads: usize) -> Result<()> {
-        let states: Arc<Mutex<Vec<DownloadState>>> =
-            Arc::new(Mutex::new(self.state_manager.load_state()?));
-        let mp = Arc::new(MultiProgress::new());
+    /// Orders `entries` by ascending size for `--smallest-first`, probing the sizes the
+    /// manifest lacks with up to `concurrency` parallel requests. Files whose size stays
+    /// unknown go last, in their original order.
+    pub async fn smallest_first(&self, entries: Vec<LinkEntry>, concurrency: usize) -> Vec<LinkEntry> {
+        let sizes = self.probe_sizes(&entries, concurrency).await;
+        let mut sized: Vec<(Option<u64>, LinkEntry)> = sizes.into_iter().zip(entries).collect();
+        sized.sort_by_key(|(size, _)| size.unwrap_or(u64::MAX));
+        sized.into_iter().map(|(_, entry)| entry).collect()
+    }
 
-        let semaphore = Arc::new(Semaphore::new(num_threads));
+    /// Size of each entry: the link file's when given, otherwise probed, `concurrency`
+    /// at a time. `None` where the probe failed.
+    pub async fn probe_sizes(&self, entries: &[LinkEntry], concurrency: usize) -> Vec<Option<u64>> {
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        join_all(entries.iter().map(|entry| {
+            let semaphore = Arc::clone(&semaphore);
+            async move {
+                if entry.size.is_some() {
+                    return entry.size;
+                }
+                let _permit = semaphore.acquire().await.unwrap();
+                self.discover_remote(entry).await.ok().and_then(|remote| remote.size)
+            }
+        }))
+        .await
+    }
 
-        let mut handles = Vec::new();
+    fn get(&self, url: &str) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    /// Builds a request, picking a random user agent per request with `--randomize-user-agent`.
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let client = match &self.http1_client {
+            Some(http1) if self.http1_hosts.lock().unwrap().contains(&host_of(url)) => http1,
+            _ => &self.client,
+        };
+        let request = client.request(method, url);
+        if self.options.user_agents.is_empty() {
+            return request;
+        }
+        let user_agent = &self.options.user_agents[fastrand::usize(..self.options.user_agents.len())];
+        request.header(USER_AGENT, user_agent)
+    }
+
+    /// Sleeps as needed to keep this connection under `--limit-rate` and total
+    /// throughput under the bandwidth cap.
+    async fn throttle(&self, connection: Option<&BandwidthLimiter>, bytes: usize) {
+        if let Some(limiter) = connection {
+            limiter.consume(bytes).await;
+        }
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.consume(bytes).await;
+        }
+    }
+
+    /// Wraps a `.part` file in a `--write-buffer-size` buffer, so small network chunks
+    /// reach the disk in fewer, larger writes. Under `--max-memory` the buffer and the
+    /// chunk being read are reserved together for the life of the writer; taking both in
+    /// one step means a transfer never waits on the budget while holding part of it.
+    async fn write_buffer(&self, file: tokio::fs::File) -> (BufWriter<tokio::fs::File>, Option<SemaphorePermit<'_>>) {
+        let reservation = match &self.memory {
+            Some(memory) => Some(memory.reserve(self.options.write_buffer_size + CHUNK_RESERVATION).await),
+            None => None,
+        };
+        (BufWriter::with_capacity(self.options.write_buffer_size, file), reservation)
+    }
+
+    /// Whether `--flush-interval` has passed since `last_flush`; restarts the interval
+    /// when it has.
+    fn flush_due(&self, last_flush: &mut Instant) -> bool {
+        match self.options.flush_interval {
+            Some(interval) if last_flush.elapsed() >= interval => {
+                *last_flush = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A fresh `--limit-rate` bucket for one connection's transfer.
+    fn connection_limiter(&self) -> Option<BandwidthLimiter> {
+        self.options.limit_rate.map(|rate| BandwidthLimiter::new(Some(rate), None))
+    }
+
+    /// Waits for a request slot when `--limit-files-per-second` is set.
+    async fn pace(&self) {
+        if let Some(pacer) = &self.pacer {
+            pacer.acquire().await;
+        }
+    }
+
+    /// Builds the HTTP client from the connection, TLS, proxy and auth options;
+    /// `http1_only` gives the `--http-version-fallback` client.
+    fn build_client(options: &DownloadOptions, dns: &Arc<DnsCache>, http1_only: bool) -> Result<Client> {
+        let mut client_builder = Client::builder()
+            .dns_resolver(Arc::clone(dns))
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(useragent::DEFAULT_USER_AGENT)
+            .pool_idle_timeout(options.pool_idle_timeout);
+
+        if options.keep_open_connections {
+            client_builder = client_builder.tcp_keepalive(KEEPALIVE_INTERVAL);
+        }
+
+        match options.min_tls_version {
+            Some(TlsVersion::V1_2) => {
+                client_builder = client_builder.min_tls_version(reqwest::tls::Version::TLS_1_2);
+            }
+            Some(TlsVersion::V1_3) => {
+                // 默认的 native-tls 后端不支持把下限设为 1.3，改用 rustls
+                client_builder = client_builder
+                    .use_rustls_tls()
+                    .min_tls_version(reqwest::tls::Version::TLS_1_3);
+            }
+            None => {}
+        }
+
+        // 空闲连接同样占用端口，池大小也不超过全局连接上限
+        let max_idle = match (options.pool_max_idle_per_host, options.max_connections_global) {
+            (Some(idle), Some(limit)) => Some(idle.min(limit)),
+            (idle, limit) => idle.or(limit),
+        };
+        if let Some(max_idle) = max_idle {
+            client_builder = client_builder.pool_max_idle_per_host(max_idle);
+        }
+
+        if let Some(proxy_url) = &options.proxy {
+            let mut proxy = reqwest::Proxy::http(proxy_url)
+                .with_context(|| format!("Invalid proxy URL: {}", secret::redact_url(proxy_url)))?;
+            if let Some(auth) = &options.proxy_auth {
+                let (user, password) = auth
+                    .expose()
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Proxy credentials must be in USER:PASSWORD form"))?;
+                proxy = proxy.basic_auth(user, password);
+            }
+            client_builder = client_builder.proxy(proxy);
+        }
+
+        if let Some(token) = &options.auth_token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose()))
+                .map_err(|_| anyhow!("Auth token contains invalid characters"))?;
+            value.set_sensitive(true);
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, value);
+            client_builder = client_builder.default_headers(headers);
+        }
+
+        if http1_only {
+            client_builder = client_builder.http1_only();
+        }
+
+        client_builder.build().context("Failed to build HTTP client")
+    }
+
+    /// `--probe-ranges` (or `--segments`): whether `url`'s host answers byte ranges,
+    /// probed on first use and cached per host. `None` when probing is off or the URL
+    /// has no host.
+    pub async fn range_capability(&self, url: &str) -> Result<Option<RangeCapability>> {
+        match &self.ranges {
+            Some(cache) => cache.get_or_probe(url, || self.probe_range(url)).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Probes the range support of every distinct host in `entries`.
+    pub async fn probe_hosts(&self, entries: &[LinkEntry]) -> Vec<(String, Result<RangeCapability>)> {
+        let mut seen: HashMap<String, &str> = HashMap::new();
+        for entry in entries {
+            if let Some(host) = ranges::host_key(&entry.url) {
+                seen.entry(host).or_insert(&entry.url);
+            }
+        }
+        let mut hosts: Vec<(String, &str)> = seen.into_iter().collect();
+        hosts.sort();
+        let probes = hosts.iter().map(|(_, url)| self.range_capability(url));
+        hosts
+            .iter()
+            .map(|(host, _)| host.clone())
+            .zip(join_all(probes).await)
+            .map(|(host, result)| (host, result.and_then(|c| c.ok_or_else(|| anyhow!("Probing is disabled")))))
+            .collect()
+    }
+
+    async fn probe_range(&self, url: &str) -> Result<RangeCapability> {
+        let _connection = self.connection().await;
+        self.pace().await;
+        // 只看状态码和响应头；不支持 Range 的服务器会返回整个文件，不读响应体
+        let response = self
+            .get(url)
+            .header("Range", "bytes=0-0")
+            .send()
+            .await
+            .context("Range probe failed")?;
+        let capability = RangeCapability {
+            partial_content: response.status() == 206,
+            accept_ranges: response
+                .headers()
+                .get("accept-ranges")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.to_ascii_lowercase().contains("bytes")),
+        };
+        filelog::note(|| format!("Range probe: HTTP {}, {}", response.status(), capability));
+        Ok(capability)
+    }
+
+    /// Waits for a slot under `--max-connections-global`; hold the permit until the
+    /// response has been read.
+    async fn connection(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.connections {
+            Some(connections) => Some(Arc::clone(connections).acquire_owned().await.unwrap()),
+            None => None,
+        }
+    }
+
+    async fn wait_for_schedule(&self, pb: &ProgressBar) {
+        let Some(schedule) = &self.options.schedule else {
+            return;
+        };
+        if schedule.is_open() {
+            return;
+        }
+
+        let message = pb.message().to_string();
+        pb.set_message(format!("{} [Paused until {}]", message, schedule.start().format("%H:%M")));
+        while !schedule.is_open() {
+            // Re-check at least once a minute in case the clock jumps
+            tokio::time::sleep(schedule.until_open().clamp(Duration::from_secs(1), Duration::from_secs(60))).await;
+        }
+        pb.set_message(message);
+    }
+
+    /// Records a runtime strike for `entry`. The file is deferred to a later run
+    /// unless it has now timed out `MAX_RUNTIME_STRIKES` runs in a row.
+    fn abandon_for_runtime(
+        &self,
+        entry: &LinkEntry,
+        state: &Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
+        limit: Duration,
+    ) -> anyhow::Error {
+        let timeouts = {
+            let mut state = state.lock().unwrap();
+            state.timeouts += 1;
+            state.timeouts
+        };
+
+        if timeouts >= MAX_RUNTIME_STRIKES {
+            pb.abandon_with_message(format!("Timed out ({} runs)", timeouts));
+            anyhow!(
+                "{} exceeded max runtime of {}s in {} consecutive runs",
+                entry.file_name,
+                limit.as_secs(),
+                timeouts
+            )
+        } else {
+            pb.abandon_with_message("Timed out, deferred");
+            RuntimeExceeded {
+                file_name: entry.file_name.clone(),
+                limit,
+            }
+            .into()
+        }
+    }
+
+    async fn download_file(
+        &self,
+        entry: &LinkEntry,
+        state: Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
+    ) -> Result<()> {
+        let destination = self.destination(&state);
+        let saved_as = state.lock().unwrap().saved_as.clone();
+        let mut output_path = self.saved_path(entry, saved_as.as_deref(), &destination)?;
+        self.create_parent_dir(&output_path, &destination)?;
+        // 优先使用状态中记录的 .part 位置（可能来自之前使用不同临时目录的运行）
+        let partial_path = {
+            let mut state = state.lock().unwrap();
+            let partial_path = state
+                .partial_path
+                .clone()
+                .filter(|p| p.exists())
+                .unwrap_or_else(|| self.partial_path(entry, &output_path));
+            state.partial_path = self.options.staging_dir.as_ref().map(|_| partial_path.clone());
+            partial_path
+        };
+
+        let remote = self.discover_remote(entry).await?;
+        let total_bytes = remote.size;
+
+        if self.options.since.is_some() {
+            self.apply_since(entry, &state, &remote, &output_path, &partial_path)?;
+        }
+
+        // aria2 预分配整个文件并乱序写入，大小对得上也不代表已下载完成
+        let aria2_control = PathBuf::from(format!("{}.aria2", output_path.display()));
+        let from_aria2 = output_path.exists() && aria2_control.exists();
+        if from_aria2 {
+            pb.println(format!(
+                "Warning: {} has an aria2 control file ({}); discarding both and re-downloading because aria2 writes out of order",
+                entry.file_name,
+                aria2_control.display()
+            ));
+            fs::remove_file(&aria2_control).context("Failed to remove aria2 control file")?;
+        } else if self.options.continue_partial_from_other_tool
+            && output_path.exists()
+            && !partial_path.exists()
+            && let Some(total) = total_bytes
+        {
+            // wget 等工具直接写目标文件名，比远程小的文件当作 .part 续传
+            let actual_size = fs::metadata(&output_path)?.len();
+            if actual_size < total {
+                self.move_file(&output_path, &partial_path)?;
+                pb.println(format!(
+                    "{}: continuing incomplete file from another tool at {} of {} bytes",
+                    entry.file_name, actual_size, total
+                ));
+            }
+        }
+
+        if output_path.exists() {
+            let actual_size = fs::metadata(&output_path)?.len();
+            let is_valid = if from_aria2 {
+                false
+            } else if let Some(expected) = total_bytes {
+                self.size_matches(entry, actual_size, expected, pb)
+            } else if self.options.verify_existing {
+                pb.set_message(format!("{} [Verifying existing]", entry.file_name));
+                match self.sample_matches_remote(entry, &output_path, actual_size).await? {
+                    Some(matches) => matches,
+                    None => actual_size > 0,
+                }
+            } else {
+                actual_size > 0
+            };
+            let is_valid = match self.checksum_mismatch(entry, &output_path, pb).await? {
+                Some(_) if !is_valid => false,
+                Some(mismatch) if self.options.on_checksum_mismatch == MismatchPolicy::Abort => {
+                    return Err(mismatch.into());
+                }
+                Some(_) => false,
+                None => is_valid,
+            };
+
+            if is_valid {
+                {
+                    let mut state = state.lock().unwrap();
+                    state.completed = true;
+                    state.downloaded_bytes = actual_size;
+                }
+                filelog::note(|| format!("Existing file of {} bytes is valid, skipping", actual_size));
+                pb.set_message("Skipped (valid)");
+                pb.finish();
+                return Ok(());
+            } else {
+                filelog::note(|| format!("Removing invalid existing file of {} bytes", actual_size));
+                self.discard(entry, &output_path, "invalid", pb)?;
+                pb.set_message("Removed invalid file, re-downloading...");
+            }
+        }
+
+        // 分段下载的 .part 按总大小预留，长度不代表进度，进度记录在分段表中
+        let segment_map = self.segment_plan(entry, total_bytes, &partial_path, pb).await?;
+
+        let mut current_pos = 0u64;
+        if self.options.resume && partial_path.exists() && segment_map.is_none() {
+            current_pos = fs::metadata(&partial_path)?.len();
+        }
+
+        // 截掉 tar 结构已损坏的尾部，避免在垃圾数据后面继续追加
+        if self.options.safe_resume && current_pos > 0 && tarcheck::is_supported(&entry.file_name) {
+            let good = tarcheck::last_good_offset(&partial_path)?;
+            if good < current_pos {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(&partial_path)?
+                    .set_len(good)?;
+                pb.println(format!(
+                    "{}: truncated partial file from {} to {} bytes (last valid tar block)",
+                    entry.file_name, current_pos, good
+                ));
+                current_pos = good;
+            }
+        }
+
+        // 有分块校验时从块边界续传，保证每块 CRC 都能完整计算
+        if let Some(blocks) = &entry.block_crcs {
+            let aligned = blocks.align(current_pos);
+            if aligned != current_pos {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(&partial_path)?
+                    .set_len(aligned)?;
+                current_pos = aligned;
+            }
+        }
+
+        if current_pos > 0
+            && let Some(capability) = self.range_capability(&entry.url).await?
+            && !capability.supports_ranges()
+        {
+            // 主机不支持 Range，续传会把完整文件追加到 .part 之后，只能从头下载
+            pb.println(format!(
+                "{}: host does not support ranges, restarting from byte 0 instead of resuming at {}",
+                entry.file_name, current_pos
+            ));
+            current_pos = 0;
+        }
+
+        filelog::note(|| format!("Starting at byte {} of {:?} ({})", current_pos, total_bytes, partial_path.display()));
+
+        if let Some(total) = total_bytes
+            && current_pos > 0
+            && current_pos > total
+        {
+            // 比远程文件还长的 .part 不可能是它的前缀，丢弃后由重试从头下载
+            self.discard(entry, &partial_path, "size-mismatch", pb)?;
+            state.lock().unwrap().downloaded_bytes = 0;
+            return Err(DownloadError::SizeMismatch {
+                file_name: entry.file_name.clone(),
+                expected: total,
+                actual: current_pos,
+            }
+            .into());
+        }
+
+        {
+            let mut state = state.lock().unwrap();
+            state.total_bytes = total_bytes;
+        }
+
+        if let Some(total) = total_bytes {
+            if current_pos >= total {
+                return self
+                    .complete_partial(entry, state, pb, &partial_path, &output_path, total_bytes)
+                    .await;
+            }
+
+            pb.set_length(total);
+            pb.set_position(current_pos);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{msg:30} {bar:40} {bytes}/{total_bytes} ({bytes_per_sec}) {prefix}")
+                    .unwrap()
+                    .progress_chars("=>-"),
+            );
+        }
+
+        // 本地镜像在不需要逐块处理时交给内核直接复制
+        if let Some(source_path) = source::local_path(&entry.url)
+            && entry.block_crcs.is_none()
+            && self.bandwidth.is_none()
+            && self.options.limit_rate.is_none()
+            && self.options.min_disk_speed.is_none()
+            && self.options.fsync_mode != FsyncMode::PerChunk
+        {
+            self.copy_local_mirror(&source_path, current_pos, &partial_path, &state, pb)
+                .await?;
+            return self
+                .complete_partial(entry, state, pb, &partial_path, &output_path, total_bytes)
+                .await;
+        }
+
+        if let Some(map) = segment_map {
+            self.download_segments(entry, &state, pb, &partial_path, map).await?;
+            return self
+                .complete_partial(entry, state, pb, &partial_path, &output_path, total_bytes)
+                .await;
+        }
+
+        let (mut body, _connection) = match source::local_path(&entry.url) {
+            Some(source_path) => (Body::open_local(&source_path, current_pos).await?, None),
+            None => {
+                // 读完响应体之前一直占用全局连接名额
+                let connection = self.connection().await;
+                let response = self.open_http(entry, &output_path, current_pos).await?;
+                if self.options.honor_content_disposition
+                    && let Some(name) = content_disposition_filename(response.headers())
+                    && output_path.file_name().is_none_or(|current| current != name.as_str())
+                {
+                    let renamed = output_path.with_file_name(&name);
+                    if renamed.exists() {
+                        // 服务器常给出 download.tar 之类的通用名，已有的同名文件可能属于另一条目，不覆盖
+                        pb.println(format!(
+                            "{}: {} already exists, keeping the link file's name instead of the Content-Disposition name",
+                            entry.file_name, name
+                        ));
+                        filelog::note(|| format!("Content-Disposition name {} is taken, keeping the link file's name", name));
+                    } else {
+                        // .part 仍使用清单中的名字，完成后重命名为服务器给出的名字
+                        pb.println(format!("{}: saving as {} (Content-Disposition)", entry.file_name, name));
+                        filelog::note(|| format!("Content-Disposition names the file {}", name));
+                        output_path = renamed;
+                        state.lock().unwrap().saved_as = Some(name);
+                    }
+                }
+                if current_pos > 0 && response.status() == 200 {
+                    // 服务器忽略了 Range，返回的是完整文件：从头重写而不是追加
+                    pb.println(format!(
+                        "{}: server ignored the resume range, restarting from byte 0",
+                        entry.file_name
+                    ));
+                    filelog::note(|| "Server ignored Range, restarting from byte 0".to_string());
+                    current_pos = 0;
+                    state.lock().unwrap().downloaded_bytes = 0;
+                    pb.set_position(0);
+                } else if response.status() == 206
+                    && let Err(e) = self.check_content_range(entry, response.headers(), current_pos, total_bytes)
+                {
+                    if matches!(e.downcast_ref(), Some(DownloadError::RangeMismatch { .. })) {
+                        // 服务器给出的区间接不上 .part，同样的 Range 重试也无济于事：清空后从头下载
+                        tokio::fs::File::create(&partial_path)
+                            .await
+                            .map_err(|e| DownloadError::from_io(e, &partial_path))?;
+                        state.lock().unwrap().downloaded_bytes = 0;
+                        filelog::note(|| "Range mismatch, truncated the partial file to restart from byte 0".to_string());
+                    }
+                    return Err(e);
+                }
+                (Body::from_response(response, current_pos), connection)
+            }
+        };
+
+        // 不续传时截断旧的 .part，避免追加到残留数据之后
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(current_pos > 0)
+            .write(true)
+            .truncate(current_pos == 0)
+            .open(&partial_path)
+            .await
+            .map_err(|e| DownloadError::from_io(e, &partial_path))?;
+        if self.options.preallocate
+            && let Some(total) = total_bytes
+        {
+            // 提前占用整个文件的磁盘块：减少碎片，空间不足时在下载前就失败
+            let std_file = file.into_std().await;
+            let reserved = preallocate(&std_file, total).map_err(|e| DownloadError::from_io(e, &partial_path))?;
+            filelog::note(|| {
+                if reserved {
+                    format!("Preallocated {} bytes", total)
+                } else {
+                    "Filesystem does not support preallocation".to_string()
+                }
+            });
+            file = tokio::fs::File::from_std(std_file);
+        }
+        let (mut file, _reservation) = self.write_buffer(file).await;
+        let mut last_flush = Instant::now();
+
+        let mut downloaded = 0u64;
+        let limiter = self.connection_limiter();
+        let mut last_schedule_check = Instant::now();
+        let mut block_verifier = entry
+            .block_crcs
+            .as_ref()
+            .map(|blocks| BlockVerifier::new(blocks, current_pos));
+        let mut write_monitor = self.options.min_disk_speed.map(WriteSpeedMonitor::new);
+
+        loop {
+            let next = match self.options.max_idle_time {
+                Some(idle) => tokio::time::timeout(idle, body.chunk()).await.unwrap_or_else(|_| {
+                    // 连接假死：保留已写入的数据，重试时从 .part 续传
+                    Err(DownloadError::Timeout {
+                        idle,
+                        offset: current_pos + downloaded,
+                    }
+                    .into())
+                }),
+                None => body.chunk().await,
+            };
+            let next = match next {
+                Ok(next) => next,
+                Err(e) => {
+                    // 异步写入可能仍在进行，等它落盘后再返回，重试时 .part 的长度才准确
+                    file.flush().await.map_err(|e| DownloadError::from_io(e, &partial_path))?;
+                    return Err(e);
+                }
+            };
+            let Some(chunk) = next else {
+                break;
+            };
+            let n = chunk.len();
+            if n == 0 {
+                break;
+            }
+            let write_started = Instant::now();
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| DownloadError::from_io(e, &partial_path))?;
+            if self.options.fsync_mode == FsyncMode::PerChunk {
+                file.flush().await.map_err(|e| DownloadError::from_io(e, &partial_path))?;
+                file.get_ref()
+                    .sync_data()
+                    .await
+                    .map_err(|e| DownloadError::from_io(e, &partial_path))?;
+            } else if self.flush_due(&mut last_flush) {
+                file.flush().await.map_err(|e| DownloadError::from_io(e, &partial_path))?;
+            }
+            if let Some(monitor) = &mut write_monitor
+                && let Some(bytes_per_sec) = monitor.record(n, write_started.elapsed())
+            {
+                file.flush().await.map_err(|e| DownloadError::from_io(e, &partial_path))?;
+                return Err(DownloadError::DiskTooSlow {
+                    path: partial_path.clone(),
+                    bytes_per_sec,
+                    minimum: monitor.minimum,
+                }
+                .into());
+            }
+            downloaded += n as u64;
+            self.throttle(limiter.as_ref(), n).await;
+
+            if let Some(verifier) = &mut block_verifier
+                && let Err(mismatch) = verifier.update(&chunk)
+            {
+                return Err(self.rewind_to_block(&mut file, &state, mismatch).await);
+            }
+
+            let total_downloaded = current_pos + downloaded;
+            {
+                let mut state = state.lock().unwrap();
+                state.downloaded_bytes = total_downloaded;
+            }
+            if total_bytes.is_some() {
+                pb.set_position(total_downloaded);
+            }
+
+            if let Some(schedule) = &self.options.schedule
+                && last_schedule_check.elapsed() >= Duration::from_secs(1)
+            {
+                last_schedule_check = Instant::now();
+                if !schedule.is_open() {
+                    file.flush().await.map_err(|e| DownloadError::from_io(e, &partial_path))?;
+                    if self.options.fsync_mode != FsyncMode::None {
+                        file.get_ref().sync_all().await?;
+                    }
+                    return Err(SchedulePaused.into());
+                }
+            }
+        }
+
+        if let Some(verifier) = &mut block_verifier
+            && let Err(mismatch) = verifier.finish()
+        {
+            return Err(self.rewind_to_block(&mut file, &state, mismatch).await);
+        }
+
+        file.flush().await.map_err(|e| DownloadError::from_io(e, &partial_path))?;
+        if self.options.fsync_mode != FsyncMode::None {
+            file.get_ref().sync_all().await?;
+        }
+        drop(file);
+        self.complete_partial(entry, state, pb, &partial_path, &output_path, total_bytes)
+            .await
+    }
+
+    /// `--segments`: the segment map to download `entry` with, resumed from the map
+    /// saved next to the `.part` file when it still matches. `None` when the file goes
+    /// over a single connection: segmenting is off, the size is unknown, the file is too
+    /// small, blocks must be verified in order, or the host ignores ranges. A segmented
+    /// `.part` left behind in that case is discarded, since its length says nothing
+    /// about how much of it was written.
+    async fn segment_plan(
+        &self,
+        entry: &LinkEntry,
+        total_bytes: Option<u64>,
+        partial_path: &Path,
+        pb: &ProgressBar,
+    ) -> Result<Option<SegmentMap>> {
+        let map_path = segments::map_path(partial_path);
+        let plan = match total_bytes {
+            Some(total)
+                if self.options.segments > 1
+                    && total >= 2 * segments::MIN_SEGMENT_SIZE
+                    && entry.block_crcs.is_none()
+                    && source::local_path(&entry.url).is_none() =>
+            {
+                let ranges = self.range_capability(&entry.url).await?;
+                ranges.is_none_or(|capability| capability.supports_ranges()).then_some(total)
+            }
+            _ => None,
+        };
+
+        let saved = map_path.exists().then(|| SegmentMap::load(&map_path)).flatten();
+        let Some(total) = plan else {
+            if map_path.exists() {
+                pb.println(format!(
+                    "{}: discarding segmented partial download, restarting over one connection",
+                    entry.file_name
+                ));
+                fs::remove_file(&map_path).context("Failed to remove segment map")?;
+                if partial_path.exists() {
+                    fs::remove_file(partial_path).context("Failed to remove partial file")?;
+                }
+            }
+            return Ok(None);
+        };
+
+        if let Some(map) = saved
+            && self.options.resume
+            && map.total_bytes == total
+            && partial_path.exists()
+        {
+            return Ok(Some(map));
+        }
+
+        // 没有分段表的 .part 来自单连接下载，其长度就是已完成的前缀，保留下来只分剩余部分；
+        // 分段表存在但不可用时，.part 内容无法对应到分段，只能从头开始
+        let mut prefix = 0;
+        if self.options.resume && !map_path.exists() && partial_path.exists() {
+            prefix = fs::metadata(partial_path)?.len();
+            if self.options.safe_resume && prefix > 0 && tarcheck::is_supported(&entry.file_name) {
+                prefix = prefix.min(tarcheck::last_good_offset(partial_path)?);
+            }
+            if prefix > total {
+                prefix = 0;
+            }
+        }
+        if prefix > 0 {
+            pb.println(format!(
+                "{}: keeping {} bytes of the single-connection partial download, splitting the rest",
+                entry.file_name, prefix
+            ));
+        }
+        let map = SegmentMap::split_from(total, self.options.segments, prefix);
+        fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(prefix == 0)
+            .open(partial_path)
+            .and_then(|file| {
+                // 分段按偏移写入，文件长度本就是总大小，可以直接分配全部空间
+                if self.options.preallocate {
+                    fs4::fs_std::FileExt::allocate(&file, total)
+                } else {
+                    file.set_len(total)
+                }
+            })
+            .map_err(|e| DownloadError::from_io(e, partial_path))?;
+        map.save(&map_path)?;
+        Ok(Some(map))
+    }
+
+    /// Downloads every unfinished segment of `map` at once, each over its own
+    /// connection, then removes the map. A failed segment fails the file, and the
+    /// retry resumes each segment where it stopped.
+    async fn download_segments(
+        &self,
+        entry: &LinkEntry,
+        state: &Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
+        partial_path: &Path,
+        map: SegmentMap,
+    ) -> Result<()> {
+        let map_path = segments::map_path(partial_path);
+        let downloaded = map.downloaded();
+        let pending: Vec<usize> = (0..map.segments.len()).filter(|&i| !map.segments[i].is_complete()).collect();
+        state.lock().unwrap().downloaded_bytes = downloaded;
+        pb.set_position(downloaded);
+        filelog::note(|| {
+            format!(
+                "Downloading {} of {} segments, {} bytes already done",
+                pending.len(),
+                map.segments.len(),
+                downloaded
+            )
+        });
+
+        let progress = AtomicU64::new(downloaded);
+        let map = Mutex::new(map);
+        let results = join_all(
+            pending
+                .iter()
+                .map(|&idx| self.download_segment(entry, state, pb, partial_path, &map, idx, &progress)),
+        )
+        .await;
+
+        // 无论成败都保存分段表，重试时每段从各自的位置继续
+        let map = map.into_inner().unwrap();
+        map.save(&map_path)?;
+        results.into_iter().collect::<Result<Vec<()>>>()?;
+
+        if self.options.fsync_mode != FsyncMode::None {
+            File::open(partial_path)?.sync_all()?;
+        }
+        fs::remove_file(&map_path).context("Failed to remove segment map")?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn download_segment(
+        &self,
+        entry: &LinkEntry,
+        state: &Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
+        partial_path: &Path,
+        map: &Mutex<SegmentMap>,
+        idx: usize,
+        progress: &AtomicU64,
+    ) -> Result<()> {
+        let (segment, total_bytes) = {
+            let map = map.lock().unwrap();
+            (map.segments[idx], map.total_bytes)
+        };
+        let map_path = segments::map_path(partial_path);
+        let mut pos = segment.position();
+
+        let _connection = self.connection().await;
+        self.pace().await;
+        let request = self
+            .get(&entry.url)
+            .header("Range", format!("bytes={}-{}", pos, segment.end - 1));
+        let response = self.send_watched(request, pos).await?;
+        filelog::note(|| format!("Segment {} GET bytes {}-{}: HTTP {}", idx, pos, segment.end - 1, response.status()));
+        if response.status() != 206 {
+            return Err(if response.status().is_success() {
+                anyhow!("Server ignored the range for segment {} of {}", idx, entry.file_name)
+            } else {
+                DownloadError::HttpStatus(response.status().as_u16()).into()
+            });
+        }
+        self.check_content_range(entry, response.headers(), pos, Some(total_bytes))?;
+        let mut body = Body::from_response(response, pos);
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(partial_path)
+            .await
+            .map_err(|e| DownloadError::from_io(e, partial_path))?;
+        file.seek(SeekFrom::Start(pos)).await?;
+        let (mut file, _reservation) = self.write_buffer(file).await;
+        let limiter = self.connection_limiter();
+        let mut last_save = Instant::now();
+        let mut last_flush = Instant::now();
+
+        while pos < segment.end {
+            let next = match self.options.max_idle_time {
+                Some(idle) => tokio::time::timeout(idle, body.chunk())
+                    .await
+                    .unwrap_or_else(|_| Err(DownloadError::Timeout { idle, offset: pos }.into())),
+                None => body.chunk().await,
+            };
+            let next = match next {
+                Ok(next) => next,
+                Err(e) => {
+                    // 分段表只记录已经落盘的字节
+                    file.flush().await.map_err(|e| DownloadError::from_io(e, partial_path))?;
+                    map.lock().unwrap().segments[idx].done = pos - segment.start;
+                    return Err(e);
+                }
+            };
+            let Some(chunk) = next.filter(|chunk| !chunk.is_empty()) else {
+                break;
+            };
+            // 多出的字节属于下一段，不能写入
+            let n = chunk.len().min((segment.end - pos) as usize);
+            file.write_all(&chunk[..n])
+                .await
+                .map_err(|e| DownloadError::from_io(e, partial_path))?;
+            pos += n as u64;
+            if self.flush_due(&mut last_flush) {
+                file.flush().await.map_err(|e| DownloadError::from_io(e, partial_path))?;
+            }
+            let total_downloaded = progress.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+            state.lock().unwrap().downloaded_bytes = total_downloaded;
+            pb.set_position(total_downloaded);
+            self.throttle(limiter.as_ref(), n).await;
+
+            if last_save.elapsed() >= SEGMENT_MAP_SAVE_INTERVAL {
+                last_save = Instant::now();
+                file.flush().await.map_err(|e| DownloadError::from_io(e, partial_path))?;
+                let mut map = map.lock().unwrap();
+                map.segments[idx].done = pos - segment.start;
+                map.save(&map_path)?;
+            }
+        }
+        file.flush().await.map_err(|e| DownloadError::from_io(e, partial_path))?;
+        map.lock().unwrap().segments[idx].done = pos - segment.start;
+
+        if pos < segment.end {
+            return Err(anyhow!(
+                "Segment {} of {} ended at byte {} instead of {}",
+                idx,
+                entry.file_name,
+                pos,
+                segment.end
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that every output directory (and the staging directory) accepts new
+    /// files, so a permission problem is reported once before any download starts.
+    pub fn check_writable(&self) -> Result<()> {
+        for dir in self.output_dirs.iter().chain(&self.options.staging_dir) {
+            let probe = dir.join(format!(".sa-1b-dl-write-test-{}", std::process::id()));
+            File::create(&probe).map_err(|e| DownloadError::from_io(e, dir))?;
+            fs::remove_file(&probe).map_err(|e| DownloadError::from_io(e, dir))?;
+        }
+        Ok(())
+    }
+
+    /// Copies a `file://` mirror into the `.part` file without reading it through
+    /// userspace, keeping progress, state and `--schedule` pauses up to date.
+    async fn copy_local_mirror(
+        &self,
+        source_path: &Path,
+        current_pos: u64,
+        partial_path: &Path,
+        state: &Arc<Mutex<DownloadState>>,
+        pb: &ProgressBar,
+    ) -> Result<()> {
+        let source_path = source_path.to_path_buf();
+        let partial_path = partial_path.to_path_buf();
+        let state = Arc::clone(state);
+        let pb = pb.clone();
+        let schedule = self.options.schedule;
+        let fsync = self.options.fsync_mode != FsyncMode::None;
+
+        task::spawn_blocking(move || {
+            // 不用 O_APPEND 打开：copy_file_range 拒绝追加模式的目标文件，会退回用户态复制
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(current_pos == 0)
+                .open(&partial_path)
+                .map_err(|e| DownloadError::from_io(e, &partial_path))?;
+            file.seek(SeekFrom::Start(current_pos)).map_err(|e| DownloadError::from_io(e, &partial_path))?;
+            let result = source::copy_local(&source_path, current_pos, &mut file, |copied| {
+                let total = current_pos + copied;
+                state.lock().unwrap().downloaded_bytes = total;
+                pb.set_position(total);
+                match &schedule {
+                    Some(schedule) if !schedule.is_open() => Err(SchedulePaused.into()),
+                    _ => Ok(()),
+                }
+            });
+            if fsync {
+                file.sync_all()?;
+            }
+            result.map(|_| ())
+        })
+        .await
+        .map_err(|e| anyhow!("Task error: {}", e))?
+    }
+
+    /// Resolves where `entry` is written inside the output directory. Nested names keep
+    /// their directories unless `--flatten` is set; `..` and absolute paths are rejected.
+    fn output_path(&self, entry: &LinkEntry, destination: &Path) -> Result<PathBuf> {
+        validate_file_name(&entry.file_name)?;
+        let relative = Path::new(&entry.file_name);
+
+        if self.options.flatten {
+            let base = relative
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid file name in link file: {}", entry.file_name))?;
+            Ok(destination.join(base))
+        } else {
+            Ok(destination.join(relative))
+        }
+    }
+
+    /// Where `entry` is stored: under the name `--honor-content-disposition` saved it
+    /// as, if any, otherwise under the link file's name.
+    fn saved_path(&self, entry: &LinkEntry, saved_as: Option<&str>, destination: &Path) -> Result<PathBuf> {
+        let path = self.output_path(entry, destination)?;
+        Ok(match saved_as {
+            Some(name) => path.with_file_name(name),
+            None => path,
+        })
+    }
+
+    fn destination(&self, state: &Arc<Mutex<DownloadState>>) -> PathBuf {
+        state
+            .lock()
+            .unwrap()
+            .destination
+            .clone()
+            .unwrap_or_else(|| self.output_dir.clone())
+    }
+
+    /// Picks the output directory for `entry`: the one recorded in state, else one that
+    /// already holds the file or its partial, else the next directory round-robin.
+    fn assign_destination(&self, entry: &LinkEntry, state: &mut DownloadState, next: &mut usize) {
+        if let Some(dest) = &state.destination
+            && self.output_dirs.contains(dest)
+        {
+            return;
+        }
+
+        let existing = self.output_dirs.iter().find(|dir| {
+            self.output_path(entry, dir).is_ok_and(|path| {
+                path.exists() || self.partial_path(entry, &path).exists()
+            })
+        });
+
+        let dest = match existing {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = self.output_dirs[*next % self.output_dirs.len()].clone();
+                *next += 1;
+                dir
+            }
+        };
+        state.destination = Some(dest);
+    }
+
+    /// Creates the directories above `path`, checking both before and after that
+    /// none of them is a symlink leading out of `destination`.
+    fn create_parent_dir(&self, path: &Path, destination: &Path) -> Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        // 先检查已存在的部分，避免 create_dir_all 顺着符号链接在输出目录外建目录
+        self.ensure_within_output_dir(parent, destination)?;
+        fs::create_dir_all(parent).map_err(|e| DownloadError::from_io(e, parent))?;
+        self.ensure_within_output_dir(parent, destination)
+    }
+
+    /// Guards against symlinked subdirectories that resolve outside the output directory.
+    /// A path that does not exist yet is judged by its deepest existing ancestor.
+    fn ensure_within_output_dir(&self, path: &Path, destination: &Path) -> Result<()> {
+        let base = destination
+            .canonicalize()
+            .context("Failed to resolve output directory")?;
+        let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+        let resolved = existing
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve output path {}", path.display()))?;
+        if !resolved.starts_with(&base) {
+            return Err(anyhow!(
+                "Refusing to write outside output directory: {}",
+                resolved.display()
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_accepted_content_type(&self, content_type: Option<&str>) -> bool {
+        let Some(content_type) = content_type else {
+            return true;
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if self.options.content_types.is_empty() {
+            mime != "text/html"
+        } else {
+            self.options
+                .content_types
+                .iter()
+                .any(|accepted| accepted.eq_ignore_ascii_case(&mime))
+        }
+    }
+
+    /// Sends a download GET. With `--max-idle-time` the wait for the response headers is
+    /// bounded by the idle window and the body by the watchdog in the chunk loop, in
+    /// place of the client's overall [`REQUEST_TIMEOUT`]; a stall is reported at
+    /// `offset` so the retry resumes from the `.part` file.
+    async fn send_watched(&self, request: RequestBuilder, offset: u64) -> Result<Response> {
+        let Some(idle) = self.options.max_idle_time else {
+            return request.send().await.context("GET request failed");
+        };
+        match tokio::time::timeout(idle, request.timeout(WATCHED_TRANSFER_TIMEOUT).send()).await {
+            Ok(response) => response.context("GET request failed"),
+            Err(_) => Err(DownloadError::Timeout { idle, offset }.into()),
+        }
+    }
+
+    /// Issues the download GET (ranged when resuming) and rejects error statuses and
+    /// unexpected content types before any bytes are written.
+    async fn open_http(&self, entry: &LinkEntry, output_path: &Path, current_pos: u64) -> Result<Response> {
+        let mut request = self.get(&entry.url);
+        if current_pos > 0 {
+            request = request.header("Range", format!("bytes={}-", current_pos));
+        }
+
+        self.pace().await;
+        let response = self.send_watched(request, current_pos).await?;
+        filelog::note(|| format!("GET from byte {}: HTTP {}", current_pos, response.status()));
+
+        if !response.status().is_success() && response.status() != 206 {
+            return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
+        }
+
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        // multipart/byteranges 的 206 响应由 Body 拆包，不按内容类型拒绝
+        let multipart = response.status() == 206
+            && content_type.as_deref().and_then(multipart::byteranges_boundary).is_some();
+        if !multipart && !self.is_accepted_content_type(content_type.as_deref()) {
+            let error_path = format!("{}.error.html", output_path.display());
+            let body = response.bytes().await.unwrap_or_default();
+            tokio::fs::write(&error_path, &body)
+                .await
+                .context("Failed to save error page")?;
+            return Err(anyhow!(
+                "Unexpected content type {} for {} (body saved to {})",
+                content_type.unwrap_or_default(),
+                entry.file_name,
+                error_path
+            ));
+        }
+
+        Ok(response)
+    }
+
+    /// Checks that a 206 answer starts at `current_pos` and agrees with the known size.
+    /// multipart/byteranges answers carry their ranges per part and are checked there.
+    fn check_content_range(&self, entry: &LinkEntry, headers: &HeaderMap, current_pos: u64, total_bytes: Option<u64>) -> Result<()> {
+        if let Some(start) = content_range_start(headers)
+            && start != current_pos
+        {
+            return Err(DownloadError::RangeMismatch {
+                file_name: entry.file_name.clone(),
+                expected: current_pos,
+                actual: start,
+            }
+            .into());
+        }
+        if let (Some(expected), Some(actual)) = (total_bytes, content_range_total(headers))
+            && expected != actual
+        {
+            return Err(DownloadError::SizeMismatch {
+                file_name: entry.file_name.clone(),
+                expected,
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Learns the remote size and modification time with a HEAD request, or with a
+    /// one-byte ranged GET (`--head-method get-range`) for servers that reject HEAD.
+    async fn discover_remote(&self, entry: &LinkEntry) -> Result<RemoteInfo> {
+        if let Some(source_path) = source::local_path(&entry.url) {
+            return Ok(RemoteInfo {
+                size: Some(source::local_size(&source_path)?),
+                last_modified: fs::metadata(&source_path)?.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+        // 信任清单给出的大小时无需再发 HEAD（--since 仍需要 Last-Modified）
+        if self.options.trust_manifest_size && entry.size.is_some() && self.options.since.is_none() {
+            filelog::note(|| format!("Trusting manifest size {:?}, no size probe", entry.size));
+            return Ok(RemoteInfo {
+                size: entry.size,
+                last_modified: None,
+            });
+        }
+
+        let _connection = self.connection().await;
+        self.pace().await;
+        let started = Instant::now();
+
+        let response = match self.options.head_method {
+            HeadMethod::Head => self
+                .request(Method::HEAD, &entry.url)
+                .send()
+                .await
+                .context("HEAD request failed")?,
+            HeadMethod::GetRange => {
+                let response = self
+                    .get(&entry.url)
+                    .header("Range", "bytes=0-0")
+                    .send()
+                    .await
+                    .context("Size probe request failed")?;
+                if !response.status().is_success() {
+                    return Err(DownloadError::HttpStatus(response.status().as_u16()))
+                        .context("Size probe failed");
+                }
+                response
+            }
+        };
+
+        // 服务器忽略 Range 时返回 200，此时 content-length 就是完整大小；不读取响应体
+        let total_bytes = if response.status() == 206 {
+            content_range_total(response.headers())
+        } else {
+            content_length(response.headers())
+        };
+        filelog::note(|| {
+            format!(
+                "Size probe ({:?}): HTTP {}, size {:?}, {:?}",
+                self.options.head_method,
+                response.status(),
+                total_bytes,
+                started.elapsed()
+            )
+        });
+
+        // 有些镜像对不存在的对象返回 200 + content-length: 0 或 HTML 页面，而不是 404
+        if response.status().is_success() {
+            let content_type = response
+                .headers()
+                .get("content-type")
+                .and_then(|v| v.to_str().ok());
+            let reason = if total_bytes == Some(0) {
+                Some("server reports a zero-byte object".to_string())
+            } else if !self.is_accepted_content_type(content_type) {
+                Some(format!("server answered with content type {}", content_type.unwrap_or_default()))
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(DownloadError::Unavailable {
+                    file_name: entry.file_name.clone(),
+                    url: entry.url.clone(),
+                    reason,
+                }
+                .into());
+            }
+        }
+
+        let last_modified = response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(since::parse_http_date);
+
+        self.health.record_latency(&entry.url, started.elapsed());
+        Ok(RemoteInfo {
+            size: total_bytes,
+            last_modified,
+        })
+    }
+
+    /// Decides whether `--since` skips this file. When it is downloaded, stale local
+    /// copies (and partials of an older server version) are removed first.
+    fn apply_since(
+        &self,
+        entry: &LinkEntry,
+        state: &Arc<Mutex<DownloadState>>,
+        remote: &RemoteInfo,
+        output_path: &Path,
+        partial_path: &Path,
+    ) -> Result<()> {
+        let Some(since) = self.options.since else {
+            return Ok(());
+        };
+        let recorded = state.lock().unwrap().last_modified;
+        let not_modified = || -> Result<()> {
+            Err(NotModified {
+                file_name: entry.file_name.clone(),
+            }
+            .into())
+        };
+
+        let Some(remote_modified) = remote.last_modified else {
+            return match self.options.since_missing {
+                MissingLastModified::Download => Ok(()),
+                MissingLastModified::Skip => not_modified(),
+            };
+        };
+
+        let cutoff = match since {
+            Since::Date(date) => Some(date),
+            Since::Recorded => recorded,
+        };
+        if cutoff.is_some_and(|cutoff| remote_modified <= cutoff) {
+            return not_modified();
+        }
+
+        // 服务器上的版本更新：替换本地副本，旧版本的 .part 也不能续传
+        if output_path.exists() {
+            fs::remove_file(output_path).context("Failed to remove outdated file")?;
+        }
+        if recorded != Some(remote_modified) && partial_path.exists() {
+            fs::remove_file(partial_path).context("Failed to remove outdated partial file")?;
+        }
+        let mut state = state.lock().unwrap();
+        state.last_modified = Some(remote_modified);
+        state.completed = false;
+        Ok(())
+    }
+
+    /// Compares the first and last `VERIFY_SAMPLE_BYTES` of a local file against the
+    /// remote using Range requests. Returns `None` when the server doesn't honor ranges.
+    async fn sample_matches_remote(
+        &self,
+        entry: &LinkEntry,
+        path: &Path,
+        local_size: u64,
+    ) -> Result<Option<bool>> {
+        if local_size == 0 {
+            return Ok(Some(false));
+        }
+        let sample_len = VERIFY_SAMPLE_BYTES.min(local_size);
+
+        let head = self
+            .fetch_range(entry, format!("bytes=0-{}", sample_len - 1))
+            .await?;
+        let Some((head, _)) = head else {
+            return Ok(None);
+        };
+
+        let tail = self
+            .fetch_range(entry, format!("bytes=-{}", sample_len))
+            .await?;
+        let Some((tail, remote_total)) = tail else {
+            return Ok(None);
+        };
+
+        if remote_total.is_some_and(|total| total != local_size) {
+            return Ok(Some(false));
+        }
+
+        let mut file = File::open(path).context("Failed to open existing file")?;
+        let mut local_head = vec![0u8; sample_len as usize];
+        file.read_exact(&mut local_head)?;
+        let mut local_tail = vec![0u8; sample_len as usize];
+        file.seek(SeekFrom::Start(local_size - sample_len))?;
+        file.read_exact(&mut local_tail)?;
+
+        Ok(Some(head == local_head && tail == local_tail))
+    }
+
+    /// Issues a ranged GET, returning the body and the total size from `Content-Range`,
+    /// or `None` if the server answered with anything other than 206.
+    async fn fetch_range(&self, entry: &LinkEntry, range: String) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let _connection = self.connection().await;
+        self.pace().await;
+        let response = self
+            .get(&entry.url)
+            .header("Range", range)
+            .send()
+            .await
+            .context("Range request failed")?;
+
+        if response.status() != 206 {
+            return Ok(None);
+        }
+
+        let total = content_range_total(response.headers());
+        let body = response.bytes().await.context("Failed to read range response")?;
+
+        Ok(Some((body.to_vec(), total)))
+    }
+
+    /// Truncates the partial file back to the start of a corrupt block so the retry
+    /// re-requests only from that block onward.
+    async fn rewind_to_block(
+        &self,
+        file: &mut BufWriter<tokio::fs::File>,
+        state: &Arc<Mutex<DownloadState>>,
+        mismatch: BlockMismatch,
+    ) -> anyhow::Error {
+        let truncated = match file.flush().await {
+            Ok(()) => file.get_ref().set_len(mismatch.block_start).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = truncated {
+            return anyhow!("{} (and failed to truncate partial file: {})", mismatch, e);
+        }
+        state.lock().unwrap().downloaded_bytes = mismatch.block_start;
+        mismatch.into()
+    }
+
+    /// `--dedup`: indexes the completed files of `entries` that have a checksum.
+    fn dedup_index(&self, entries: &[LinkEntry], states: &HashMap<String, DownloadState>) -> DedupIndex {
+        let index = DedupIndex::default();
+        for entry in entries {
+            let (Some(checksum), Some(state)) = (&entry.checksum, states.get(&entry.file_name)) else {
+                continue;
+            };
+            let destination = state.destination.clone().unwrap_or_else(|| self.output_dir.clone());
+            if state.completed
+                && let Ok(path) = self.saved_path(entry, state.saved_as.as_deref(), &destination)
+                && path.exists()
+            {
+                index.record(checksum, path);
+            }
+        }
+        index
+    }
+
+    /// `--dedup`: links `entry` to an already-downloaded file with the same checksum,
+    /// marking it complete. `false` when there is no such file or `entry` is already
+    /// on disk (and is left for `download_file` to validate).
+    fn link_duplicate(
+        &self,
+        dedup: &DedupIndex,
+        entry: &LinkEntry,
+        state: &Arc<Mutex<DownloadState>>,
+        checksum: &str,
+        pb: &ProgressBar,
+    ) -> Result<bool> {
+        let Some(source) = dedup.lookup(checksum) else {
+            return Ok(false);
+        };
+        let destination = self.destination(state);
+        let target = self.final_path(entry, state)?;
+        if target.exists() {
+            return Ok(false);
+        }
+        self.create_parent_dir(&target, &destination)?;
+        let bytes = dedup.link(&source, &target)?;
+        {
+            let mut state = state.lock().unwrap();
+            state.completed = true;
+            state.downloaded_bytes = bytes;
+            state.total_bytes = Some(bytes);
+        }
+        filelog::note(|| format!("Linked to identical {}", source.display()));
+        pb.set_message(format!("Linked (same content as {})", source.display()));
+        Ok(true)
+    }
+
+    /// Where `entry` ends up given its current state.
+    fn final_path(&self, entry: &LinkEntry, state: &Arc<Mutex<DownloadState>>) -> Result<PathBuf> {
+        let saved_as = state.lock().unwrap().saved_as.clone();
+        self.saved_path(entry, saved_as.as_deref(), &self.destination(state))
+    }
+
+    /// Gets a file that failed validation out of the way: moved to
+    /// `<quarantine>/<file_name>.<reason>` with `--quarantine-dir`, deleted otherwise.
+    fn discard(&self, entry: &LinkEntry, path: &Path, reason: &str, pb: &ProgressBar) -> Result<()> {
+        let Some(dir) = &self.options.quarantine_dir else {
+            return fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()));
+        };
+        fs::create_dir_all(dir).map_err(|e| DownloadError::from_io(e, dir))?;
+        let target = dir.join(format!("{}.{}", entry.file_name.replace('/', "__"), reason));
+        self.move_file(path, &target)
+            .with_context(|| format!("Failed to quarantine {}", path.display()))?;
+        pb.println(format!("{}: quarantined as {}", entry.file_name, target.display()));
+        filelog::note(|| format!("Quarantined as {}", target.display()));
+        self.quarantined.lock().unwrap().push(target);
+        Ok(())
+    }
+
+    /// Where the in-progress data for `entry` lives: `<output>.part`, or a flattened
+    /// name inside the staging directory when `--tmpfile-in-output=false`.
+    fn partial_path(&self, entry: &LinkEntry, output_path: &Path) -> PathBuf {
+        match &self.options.staging_dir {
+            Some(dir) => dir.join(format!("{}.part", entry.file_name.replace('/', "__"))),
+            None => PathBuf::from(format!("{}.part", output_path.display())),
+        }
+    }
+
+    /// Renames `from` to `to`, copying instead when they are on different filesystems.
+    fn move_file(&self, from: &Path, to: &Path) -> Result<()> {
+        match fs::rename(from, to) {
+            Ok(()) => Ok(()),
+            // 临时目录与输出目录不在同一文件系统时改为复制；
+            // fs::copy 在 Linux 上使用 copy_file_range，数据不经过用户态
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                fs::copy(from, to).context("Failed to copy completed file")?;
+                fs::remove_file(from).context("Failed to remove staged file")?;
+                Ok(())
+            }
+            Err(e) => Err(DownloadError::from_io(e, to).into()),
+        }
+    }
+
+    fn finalize_download(&self, state: Arc<Mutex<DownloadState>>, pb: &ProgressBar, output_path: &Path) -> Result<()> {
+        if let Some(mode) = self.options.output_permissions {
+            set_permissions(output_path, mode)?;
+        }
+        let actual_size = fs::metadata(output_path)?.len();
+
+        {
+            let mut state = state.lock().unwrap();
+            state.completed = true;
+            state.downloaded_bytes = actual_size;
+            state.partial_path = None;
+        }
+
+        pb.set_message("Done");
+        pb.finish();
+
+        Ok(())
+    }
+
+    fn draw_target(&self) -> ProgressDrawTarget {
+        if self.options.quiet || self.options.summary_only {
+            ProgressDrawTarget::hidden()
+        } else {
+            ProgressDrawTarget::stderr_with_hz(self.options.refresh_rate)
+        }
+    }
+
+    fn multi_progress(&self) -> MultiProgress {
+        MultiProgress::with_draw_target(self.draw_target())
+    }
+
+    fn progress_bar(&self, len: u64) -> ProgressBar {
+        ProgressBar::with_draw_target(Some(len), self.draw_target())
+    }
+
+    /// Compares the bytes `entries` still need with the free space in the output
+    /// directories before a batch starts, so a full disk is reported up front rather
+    /// than as failures halfway through. Sizes come from the link file, then from the
+    /// state file, and only then from a HEAD request; bytes already on disk are not
+    /// counted again. Output directories on the same filesystem count its free space
+    /// once. With `--force` a shortfall is only a warning. Run by `main` for all/range
+    /// batches only, so internal runs (scrub repair, the preflight sample) send no
+    /// extra requests.
+    pub async fn check_free_space(&self, entries: &[LinkEntry], concurrency: usize) -> Result<()> {
+        let recorded: HashMap<String, (u64, Option<u64>)> = self
+            .state_manager
+            .load_state()?
+            .into_iter()
+            .map(|s| (s.file_name.clone(), (s.downloaded_bytes, s.total_bytes)))
+            .collect();
+        // 会被拒绝写入的条目不会下载，也不为它们发请求
+        let entries: Vec<LinkEntry> = entries
+            .iter()
+            .filter(|entry| {
+                self.output_path(entry, &self.output_dir)
+                    .and_then(|path| self.ensure_within_output_dir(&path, &self.output_dir))
+                    .is_ok()
+            })
+            .cloned()
+            .collect();
+        // 状态里记录过总大小的文件不再发 HEAD
+        let known_size = |entry: &LinkEntry| {
+            entry
+                .size
+                .or_else(|| recorded.get(&entry.file_name).and_then(|(_, total)| *total))
+        };
+        let unsized_entries: Vec<LinkEntry> = entries.iter().filter(|e| known_size(e).is_none()).cloned().collect();
+        let mut probed = self.probe_sizes(&unsized_entries, concurrency).await.into_iter();
+        let sizes: Vec<Option<u64>> = entries
+            .iter()
+            .map(|entry| match known_size(entry) {
+                Some(size) => Some(size),
+                None => probed.next().flatten(),
+            })
+            .collect();
+        let unknown = sizes.iter().filter(|size| size.is_none()).count();
+        let needed: u64 = entries
+            .iter()
+            .zip(&sizes)
+            .filter_map(|(entry, size)| {
+                let on_disk = recorded.get(&entry.file_name).map_or(0, |(downloaded, _)| *downloaded);
+                Some((*size)?.saturating_sub(on_disk))
+            })
+            .sum();
+        let required = needed + self.options.min_free.unwrap_or(0);
+
+        // 同一文件系统上的多个输出目录共用同一份可用空间，只计一次
+        let mut devices = HashSet::new();
+        let mut available = 0u64;
+        for dir in &self.output_dirs {
+            if let Some(device) = device_of(dir)
+                && !devices.insert(device)
+            {
+                continue;
+            }
+            match fs4::available_space(dir) {
+                Ok(space) => available += space,
+                // 无法查询可用空间时不阻止下载
+                Err(e) => {
+                    eprintln!("Warning: could not check free space in {}: {}", dir.display(), e);
+                    return Ok(());
+                }
+            }
+        }
+        if required <= available {
+            return Ok(());
+        }
+
+        let unknown_note = if unknown > 0 {
+            format!(" (plus {} files of unknown size)", unknown)
+        } else {
+            String::new()
+        };
+        let message = format!(
+            "Not enough free space: the batch needs {}{}{} but only {} is available",
+            HumanBytes(needed),
+            self.options
+                .min_free
+                .map(|min_free| format!(" and --min-free keeps {}", HumanBytes(min_free)))
+                .unwrap_or_default(),
+            unknown_note,
+            HumanBytes(available)
+        );
+        if self.options.force {
+            eprintln!("Warning: {}; continuing because of --force", message);
+            Ok(())
+        } else {
+            Err(anyhow!("{} (use --force to start anyway)", message))
+        }
+    }
+
+    /// `--adaptive-concurrency`: resizes the download slots of a batch every
+    /// [`ADAPTIVE_WINDOW`] from the throughput and failed attempts of the last window,
+    /// starting from `start` and never exceeding `max`.
+    fn spawn_concurrency_controller(
+        &self,
+        tracker: Arc<ProgressTracker>,
+        semaphore: Arc<Semaphore>,
+        start: usize,
+        max: usize,
+        overall: ProgressBar,
+    ) -> task::JoinHandle<()> {
+        let failed_attempts = Arc::clone(&self.failed_attempts);
+        task::spawn(async move {
+            let mut aimd = autotune::Aimd::new(start, max);
+            // 起始并发超出上限时先收回多余的槽位
+            let mut surplus = start.saturating_sub(aimd.current());
+            let mut last = (Instant::now(), tracker.fetched_bytes(), failed_attempts.load(Ordering::Relaxed));
+            // 控制器被 abort 时 JoinSet 随之释放，仍在等待槽位的回收任务一并取消
+            let mut reclaimers = task::JoinSet::new();
+            loop {
+                while reclaimers.try_join_next().is_some() {}
+                for _ in 0..std::mem::take(&mut surplus) {
+                    // 收回槽位要等正在下载的文件完成；信号量按先来后到分配，不会饿死
+                    let semaphore = Arc::clone(&semaphore);
+                    reclaimers.spawn(async move {
+                        if let Ok(permit) = semaphore.acquire_owned().await {
+                            permit.forget();
+                        }
+                    });
+                }
+                tokio::time::sleep(ADAPTIVE_WINDOW).await;
+
+                let now = (Instant::now(), tracker.fetched_bytes(), failed_attempts.load(Ordering::Relaxed));
+                let rate = now.1.saturating_sub(last.1) as f64 / now.0.duration_since(last.0).as_secs_f64();
+                let errors = now.2 - last.2;
+                last = now;
+
+                let before = aimd.current();
+                let after = aimd.next(rate, errors);
+                if after > before {
+                    semaphore.add_permits(after - before);
+                } else {
+                    surplus = before - after;
+                }
+                if after != before {
+                    overall.println(format!(
+                        "Adaptive concurrency: {} -> {} workers ({}/s, {} failed attempts)",
+                        before,
+                        after,
+                        HumanBytes(rate as u64),
+                        errors
+                    ));
+                }
+            }
+        })
+    }
+
+    pub async fn download_all(&self, entries: Vec<LinkEntry>, num_threads: usize) -> Result<RunSummary> {
+        let started = Instant::now();
+        // 按文件名建索引，大清单下每个文件的状态查找是 O(1)
+        let states: Arc<Mutex<HashMap<String, DownloadState>>> = Arc::new(Mutex::new(
+            self.state_manager
+                .load_state()?
+                .into_iter()
+                .map(|s| (s.file_name.clone(), s))
+                .collect(),
+        ));
+        let mp = Arc::new(self.multi_progress());
+        let overall = mp.add(ProgressBar::new(entries.len() as u64));
+        overall.set_style(
+            ProgressStyle::default_bar()
+                .template("Overall {bar:40} {pos}/{len} files ({elapsed_precise}) {msg}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+
+        let semaphore = Arc::new(Semaphore::new(num_threads));
+
+        let low_space: Arc<Mutex<Option<(PathBuf, u64)>>> = Arc::new(Mutex::new(None));
+        let monitor = self.options.min_free.map(|min_free| {
+            let dirs = self.output_dirs.clone();
+            let low_space = Arc::clone(&low_space);
+            task::spawn(async move {
+                loop {
+                    if let Some(found) = find_low_space(&dirs, min_free) {
+                        *low_space.lock().unwrap() = Some(found);
+                        break;
+                    }
+                    tokio::time::sleep(FREE_SPACE_POLL_INTERVAL).await;
+                }
+            })
+        });
+
+        let extract_pool = self
+            .options
+            .extract
+            .then(|| ExtractPool::start(self.options.extract_threads, self.options.tar_index));
+
+        let tracker = (self.options.progress_file.is_some()
+            || self.options.bps_report_interval.is_some()
+            || self.options.show_eta_overall
+            || self.options.adaptive_concurrency.is_some())
+            .then(|| Arc::new(ProgressTracker::for_entries(&entries)));
+        let progress_writer = match (&tracker, &self.options.progress_file) {
+            (Some(tracker), Some(path)) => Some(tracker.spawn_writer(path.clone(), self.options.progress_interval)),
+            _ => None,
+        };
+        let bps_reporter = match (&tracker, self.options.bps_report_interval) {
+            (Some(tracker), Some(interval)) => {
+                let expected_bytes = entries.iter().map(|e| e.size).sum::<Option<u64>>();
+                Some(tracker.spawn_reporter(interval, expected_bytes))
+            }
+            _ => None,
+        };
+        let eta_reporter = match &tracker {
+            Some(tracker) if self.options.show_eta_overall => Some(tracker.spawn_eta(overall.clone())),
+            _ => None,
+        };
+        let concurrency_controller = match (&tracker, self.options.adaptive_concurrency) {
+            (Some(tracker), Some(max)) => Some(self.spawn_concurrency_controller(
+                Arc::clone(tracker),
+                Arc::clone(&semaphore),
+                num_threads,
+                max,
+                overall.clone(),
+            )),
+            _ => None,
+        };
+
+        // 熔断器：连续失败次数达到 --abort-after 时取消整个批次
+        let consecutive_failures = Arc::new(AtomicUsize::new(0));
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let cancel_tx = Arc::new(cancel_tx);
+        let checksum_abort: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+
+        let dedup = self.options.dedup.then(|| Arc::new(self.dedup_index(&entries, &states.lock().unwrap())));
+
+        let mut handles = Vec::new();
+        let mut next_destination = 0usize;
+        let mut not_started = 0usize;
 
         for (idx, entry) in entries.iter().enumerate() {
+            if low_space.lock().unwrap().is_some() || *cancel_rx.borrow() {
+                not_started = entries.len() - idx;
+                break;
+            }
+
             let entry = entry.clone();
-            let state = {
+            let mut state = {
                 let states = states.lock().unwrap();
                 states
-                    .iter()
-                    .find(|s| s.file_name == entry.file_name)
+                    .get(&entry.file_name)
                     .cloned()
                     .unwrap_or_else(|| DownloadState::new(entry.file_name.clone()))
             };
+            self.assign_destination(&entry, &mut state, &mut next_destination);
             let state = Arc::new(Mutex::new(state));
             let states = Arc::clone(&states);
             let semaphore = Arc::clone(&semaphore);
             let mp = mp.clone();
+            let overall = overall.clone();
+            let extract_tx = extract_pool.as_ref().map(|pool| pool.sender());
             let downloader = self.clone();
+            let consecutive_failures = Arc::clone(&consecutive_failures);
+            let cancel_tx = Arc::clone(&cancel_tx);
+            let checksum_abort = Arc::clone(&checksum_abort);
+            let mut cancel_rx = cancel_rx.clone();
+            let tracker = tracker.clone();
+            let dedup = dedup.clone();
+
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            // 等待空闲槽位期间磁盘空间可能已经不足，或熔断器已触发
+            if low_space.lock().unwrap().is_some() || *cancel_rx.borrow() {
+                not_started = entries.len() - idx;
+                break;
+            }
+
+            let handle = task::spawn(async move {
+                // 新进度条插入在总进度条之上，总进度条始终在最底部
+                let pb = mp.insert_before(&overall, ProgressBar::new(100));
+                let msg = format!("[{:>2}] {}", idx, entry.file_name);
+                pb.set_message(msg);
+                pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template("{msg} {prefix}")
+                        .unwrap()
+                        .progress_chars("=>-"),
+                );
+
+                if let Some(tracker) = &tracker {
+                    tracker.start(&state);
+                }
+                let file_started = Instant::now();
+                // 相同内容的条目依次处理，后面的直接链接到先下载好的文件
+                let duplicate = match (&dedup, &entry.checksum) {
+                    (Some(dedup), Some(checksum)) => Some((dedup, checksum, dedup.lock(checksum).await)),
+                    _ => None,
+                };
+                let linked = match &duplicate {
+                    Some((dedup, checksum, _)) => downloader.link_duplicate(dedup, &entry, &state, checksum, &pb),
+                    None => Ok(false),
+                };
+                let result = match linked {
+                    Ok(true) => Ok(()),
+                    Ok(false) => tokio::select! {
+                        result = downloader.download_file_with_retry(&entry, Arc::clone(&state), &pb) => result,
+                        _ = cancel_rx.wait_for(|cancelled| *cancelled) => Err(Cancelled.into()),
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Some((dedup, checksum, guard)) = duplicate {
+                    if result.is_ok()
+                        && let Ok(path) = downloader.final_path(&entry, &state)
+                    {
+                        dedup.record(checksum, path);
+                    }
+                    drop(guard);
+                }
+
+                match &result {
+                    Ok(_) => consecutive_failures.store(0, Ordering::SeqCst),
+                    Err(e) if e.is::<RuntimeExceeded>() || e.is::<Cancelled>() || e.is::<NotModified>() => {}
+                    Err(e) if is_checksum_mismatch(e)
+                        && downloader.options.on_checksum_mismatch == MismatchPolicy::Abort =>
+                    {
+                        checksum_abort
+                            .lock()
+                            .unwrap()
+                            .get_or_insert_with(|| format!("Aborted on checksum mismatch for {}", entry.file_name));
+                        cancel_tx.send_replace(true);
+                    }
+                    Err(_) => {
+                        let failures = consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+                        if downloader.options.abort_after.is_some_and(|limit| failures >= limit) {
+                            cancel_tx.send_replace(true);
+                        }
+                    }
+                }
+
+                // 下载完成后交给解压线程池，下载槽位立即释放给下一个文件
+                if result.is_ok()
+                    && let Some(tx) = &extract_tx
+                    && let Ok(path) = downloader.final_path(&entry, &state)
+                {
+                    tx.send(path).ok();
+                }
+
+                match &result {
+                    Err(e) if e.is::<NotModified>() || e.is::<Cancelled>() => {}
+                    result => {
+                        let mut state = state.lock().unwrap();
+                        // 失败原因记入状态，供 --list-failed 查看
+                        state.last_error = result.as_ref().err().map(|e| format!("{:#}", e));
+                        states.lock().unwrap().insert(entry.file_name.clone(), state.clone());
+                    }
+                }
+
+                drop(permit);
+                pb.finish();
+
+                // 完成的文件输出一行日志并移除进度条，只保留正在下载的进度条
+                let status = match &result {
+                    Ok(_) => pb.message().to_string(),
+                    Err(e) if e.is::<NotModified>() => "Skipped (not modified)".to_string(),
+                    Err(e) => format!("Failed: {}", e),
+                };
+                mp.println(format!("[{:>2}] {}: {}", idx, entry.file_name, status)).ok();
+                mp.remove(&pb);
+                overall.inc(1);
+
+                let bytes = state.lock().unwrap().downloaded_bytes;
+                if let Some(tracker) = &tracker {
+                    let failed = matches!(&result, Err(e) if !e.is::<NotModified>());
+                    tracker.finish(&entry.file_name, !failed, bytes);
+                }
+                (entry.file_name, bytes, file_started.elapsed(), result)
+            });
+
+            handles.push(handle);
+        }
+
+        let results: Vec<(String, u64, Duration, Result<()>)> = join_all(handles)
+            .await
+            .into_iter()
+            .map(|r| match r {
+                Ok(res) => res,
+                Err(e) => (String::new(), 0, Duration::ZERO, Err(anyhow!("Task error: {}", e))),
+            })
+            .collect();
+
+        if let Some(monitor) = monitor {
+            monitor.abort();
+        }
+
+        if let Some(writer) = progress_writer {
+            writer.abort();
+        }
+        if let Some(reporter) = bps_reporter {
+            reporter.abort();
+        }
+        if let Some(reporter) = eta_reporter {
+            reporter.abort();
+            overall.set_message("");
+        }
+        if let Some(controller) = concurrency_controller {
+            controller.abort();
+        }
+        if let (Some(tracker), Some(path)) = (&tracker, &self.options.progress_file) {
+            progress::write_snapshot(path, &tracker.snapshot())?;
+        }
+
+        let extract_summary = match extract_pool {
+            Some(pool) => {
+                overall.set_message("Waiting for extraction...");
+                Some(pool.finish().await)
+            }
+            None => None,
+        };
+
+        // 清除文件进度条
+        overall.finish();
+        mp.clear().ok();
+
+        let mut final_states: Vec<DownloadState> = states.lock().unwrap().values().cloned().collect();
+        final_states.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        self.state_manager.save_state(&final_states)?;
+
+        let tripped = *cancel_rx.borrow();
+        let checksum_abort = checksum_abort.lock().unwrap().take();
+        let mut summary = RunSummary {
+            not_started,
+            abort_reason: checksum_abort.or_else(|| {
+                tripped.then(|| {
+                    format!(
+                        "Aborted after {} consecutive failures",
+                        consecutive_failures.load(Ordering::SeqCst)
+                    )
+                })
+            }),
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            mirrors: self.health.report(),
+            quarantined: std::mem::take(&mut *self.quarantined.lock().unwrap()),
+            dedup_saved_bytes: dedup.as_ref().map_or(0, |dedup| dedup.saved_bytes()),
+            ..Default::default()
+        };
+        let metadata: HashMap<&str, &serde_json::Value> = entries
+            .iter()
+            .filter_map(|e| e.metadata.as_ref().map(|m| (e.file_name.as_str(), m)))
+            .collect();
+        for (file_name, bytes, elapsed, result) in results {
+            let checksum_mismatch = matches!(&result, Err(e) if is_checksum_mismatch(e))
+                .then_some(self.options.on_checksum_mismatch);
+            let (status, error) = match result {
+                Ok(_) => (FileStatus::Success, None),
+                Err(e) if e.is::<RuntimeExceeded>() => (FileStatus::Deferred, Some(e.to_string())),
+                Err(e) if e.is::<Cancelled>() => (FileStatus::Cancelled, None),
+                Err(e) if e.is::<NotModified>() => (FileStatus::Skipped, None),
+                Err(e) => (FileStatus::Failed, Some(format!("{:#}", e))),
+            };
+            match status {
+                FileStatus::Success => summary.success += 1,
+                FileStatus::Failed => summary.failed += 1,
+                FileStatus::Deferred => summary.deferred += 1,
+                FileStatus::Cancelled => summary.cancelled += 1,
+                FileStatus::Skipped => summary.skipped += 1,
+            }
+            summary.total_bytes += bytes;
+            let metadata = metadata.get(file_name.as_str()).map(|m| (*m).clone());
+            summary.files.push(FileOutcome {
+                file_name,
+                status,
+                bytes,
+                elapsed_secs: elapsed.as_secs_f64(),
+                error,
+                checksum_mismatch,
+                metadata,
+            });
+        }
+
+        if self.options.summary_only && !self.options.quiet {
+            println!();
+            report::print_table(&summary);
+        }
+
+        if self.options.quiet {
+            // 安静模式下只输出错误
+        } else if summary.deferred > 0 {
+            println!(
+                "\nDone: {} success, {} failed, {} deferred (max runtime exceeded)",
+                summary.success, summary.failed, summary.deferred
+            );
+        } else if summary.skipped > 0 {
+            println!(
+                "\nDone: {} success, {} failed, {} skipped (not modified)",
+                summary.success, summary.failed, summary.skipped
+            );
+        } else {
+            println!("\nDone: {} success, {} failed", summary.success, summary.failed);
+        }
+
+        // 其他批次留下的未完成条目仍需要状态文件续传，此时不删除
+        let nothing_left = summary.failed + summary.deferred + summary.cancelled + summary.not_started == 0
+            && summary.abort_reason.is_none()
+            && final_states.iter().all(|s| s.completed);
+        if self.options.trim_state_on_success && nothing_left {
+            self.state_manager.remove_state()?;
+            if !self.options.quiet {
+                println!("All files complete; removed the state file");
+            }
+        }
+
+        if summary.dedup_saved_bytes > 0 && !self.options.quiet {
+            println!("Deduplicated: {} not downloaded", HumanBytes(summary.dedup_saved_bytes));
+        }
+
+        if !summary.quarantined.is_empty() && !self.options.quiet {
+            println!("Quarantined {} files:", summary.quarantined.len());
+            for path in &summary.quarantined {
+                println!("  {}", path.display());
+            }
+        }
+
+        if let Some(reason) = &summary.abort_reason {
+            eprintln!(
+                "{}: {} in-flight downloads cancelled, {} not started. State saved; re-run to resume.",
+                reason, summary.cancelled, summary.not_started
+            );
+        }
+
+        if summary.mirrors.len() > 1 && !self.options.quiet {
+            println!("Mirrors:");
+            for mirror in &summary.mirrors {
+                println!(
+                    "  {}: {} ok, {} failed, {:.0}% recent success, avg latency {}",
+                    mirror.host,
+                    mirror.successes,
+                    mirror.failures,
+                    mirror.recent_success_rate * 100.0,
+                    mirror
+                        .avg_latency_ms
+                        .map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms))
+                );
+            }
+        }
+
+        if let Some(summary) = &extract_summary
+            && !self.options.quiet
+        {
+            println!(
+                "Extracted: {} ok, {} already extracted, {} failed",
+                summary.extracted,
+                summary.skipped,
+                summary.failed.len()
+            );
+            for (archive, error) in &summary.failed {
+                eprintln!("  extract failed: {}: {}", archive.display(), error);
+            }
+        }
 
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
+        if let Some((dir, available)) = low_space.lock().unwrap().clone() {
+            eprintln!(
+                "Stopped early: only {} free on {} (below --min-free), {} files not started. \
+                 Free up space and re-run with --resume to continue.",
+                HumanBytes(available),
+                dir.display(),
+                not_started
+            );
+        }
 
-            let handle = task::spawn(async move {
-                let pb = mp.add(ProgressBar::new(100));
-                let msg = format!("[{:>2}] {}", idx, entry.file_name);
-                pb.set_message(msg);
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template("{msg}")
-                        .unwrap()
-                        .progress_chars("=>-"),
-                );
+        Ok(summary)
+    }
+
+    /// Hashes each file on disk and compares it with the entry's checksum.
+    pub async fn verify_all(&self, entries: Vec<LinkEntry>, num_threads: usize) -> Result<()> {
+        let mut states: HashMap<String, DownloadState> = self
+            .state_manager
+            .load_state()?
+            .into_iter()
+            .map(|s| (s.file_name.clone(), s))
+            .collect();
+        let semaphore = Arc::new(Semaphore::new(num_threads));
+        let hashed_bytes = Arc::new(AtomicU64::new(0));
+        let started = Instant::now();
+        let pb = self.progress_bar(entries.len() as u64);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("Verify {bar:40} {pos}/{len} files ({elapsed_precise}) {msg}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+
+        let mut handles = Vec::new();
+        let mut unchanged = 0;
+
+        for entry in entries {
+            let state = states.get(&entry.file_name);
+            let destination = state
+                .and_then(|s| s.destination.clone())
+                .unwrap_or_else(|| self.output_dir.clone());
+            let path = self.saved_path(&entry, state.and_then(|s| s.saved_as.as_deref()), &destination)?;
+            if state.is_some_and(|s| self.unchanged_since_verify(s, &path)) {
+                unchanged += 1;
+                pb.inc(1);
+                continue;
+            }
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let pb = pb.clone();
+            let hash_algo = self.options.hash_algo;
+            let hashed_bytes = Arc::clone(&hashed_bytes);
 
-                let result =
-                    downloader.download_file_with_retry(&entry, Arc::clone(&state), &pb).await;
+            let reference = match &self.options.compare_dir {
+                Some(dir) => Some(self.output_path(&entry, dir)?),
+                None => None,
+            };
 
-                {
-                    let mut states = states.lock().unwrap();
-                    if result.is_ok() {
-                        if let Some(existing) = states.iter_mut().find(|s| s.file_name == entry.file_name)
-                        {
-                            let current_state = state.lock().unwrap().clone();
-                            *existing = current_state;
-                        } else {
-                            states.push(state.lock().unwrap().clone());
+            handles.push(task::spawn(async move {
+                let outcome = match (&reference, &entry.checksum) {
+                    (Some(_), _) if !path.exists() => VerifyOutcome::Missing,
+                    (Some(reference), _) if !reference.exists() => VerifyOutcome::NoChecksum,
+                    (Some(reference), _) => {
+                        let reference = reference.clone();
+                        task::spawn_blocking(move || compare_with_reference(&path, &reference, hash_algo))
+                            .await
+                            .unwrap_or_else(|e| VerifyOutcome::Error(format!("Task error: {}", e)))
+                    }
+                    (None, None) => VerifyOutcome::NoChecksum,
+                    (None, Some(_)) if !path.exists() => VerifyOutcome::Missing,
+                    (None, Some(expected)) => {
+                        let expected = expected.clone();
+                        let check = expected.clone();
+                        let (actual, bytes, elapsed) =
+                            hash_blocking(path, move |path| checksum::hash_file_for(path, &check, hash_algo)).await;
+                        hashed_bytes.fetch_add(bytes, Ordering::Relaxed);
+                        pb.set_message(format!("{} {}/s", entry.file_name, hash_rate(bytes, elapsed)));
+                        match actual {
+                            Ok(actual) if actual == expected => VerifyOutcome::Ok,
+                            Ok(actual) => VerifyOutcome::Mismatch(format!("expected {}, got {}", expected, actual)),
+                            Err(e) => VerifyOutcome::Error(e.to_string()),
                         }
                     }
-                }
+                };
 
+                match &outcome {
+                    VerifyOutcome::Mismatch(detail) => pb.println(format!("MISMATCH {}: {}", entry.file_name, detail)),
+                    VerifyOutcome::Missing => pb.println(format!("MISSING  {}", entry.file_name)),
+                    VerifyOutcome::Error(e) => pb.println(format!("ERROR    {}: {}", entry.file_name, e)),
+                    _ => {}
+                }
+                pb.inc(1);
                 drop(permit);
-                pb.finish();
+                (entry.file_name, outcome)
+            }));
+        }
 
-                result
-            });
+        let outcomes: Vec<(String, VerifyOutcome)> = join_all(handles)
+            .await
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|e| (String::new(), VerifyOutcome::Error(e.to_string()))))
+            .collect();
+        pb.finish_and_clear();
 
-            handles.push(handle);
+        // 记录通过校验的时间，供之后的 --verify-only-new 跳过未改动的文件
+        let now = Utc::now();
+        for (file_name, outcome) in &outcomes {
+            if let Some(state) = states.get_mut(file_name) {
+                state.verified_at = matches!(outcome, VerifyOutcome::Ok).then_some(now);
+            }
+        }
+        let mut final_states: Vec<DownloadState> = states.into_values().collect();
+        final_states.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        // 校验模式可能运行在只读挂载上，写不了状态不算校验失败
+        if let Err(e) = self.state_manager.save_state(&final_states) {
+            eprintln!("Warning: could not record verify times: {:#}", e);
         }
 
-        let results: Vec<Result<()>> = join_all(handles)
-            .await
+        let count = |f: fn(&VerifyOutcome) -> bool| outcomes.iter().filter(|(_, o)| f(o)).count();
+        let ok = count(|o| matches!(o, VerifyOutcome::Ok));
+        let mismatched = count(|o| matches!(o, VerifyOutcome::Mismatch(_)));
+        let missing = count(|o| matches!(o, VerifyOutcome::Missing));
+        let errors = count(|o| matches!(o, VerifyOutcome::Error(_)));
+        let unverified = count(|o| matches!(o, VerifyOutcome::NoChecksum));
+
+        if !self.options.quiet {
+            let unverified_label = match self.options.compare_dir {
+                Some(_) => "not in reference",
+                None => "without checksum",
+            };
+            println!(
+                "\nVerified: {} ok, {} mismatched, {} missing, {} errors, {} {}",
+                ok, mismatched, missing, errors, unverified, unverified_label
+            );
+            print_hash_throughput(hashed_bytes.load(Ordering::Relaxed), started.elapsed(), num_threads);
+            if unchanged > 0 {
+                println!("Skipped {} files unchanged since their last verify", unchanged);
+            }
+        }
+
+        if mismatched + missing + errors > 0 {
+            return Err(anyhow!("{} files failed verification", mismatched + missing + errors));
+        }
+
+        Ok(())
+    }
+
+    /// Re-hashes completed files against the hashes recorded in state, for catching
+    /// bit-rot on long-lived archives. Files without a recorded hash are checked against
+    /// the manifest checksum if there is one, otherwise their current hash becomes the
+    /// baseline. Problems are appended to `<output>/scrub.log`; with `repair` the affected
+    /// files are deleted and downloaded again.
+    pub async fn scrub_all(
+        &self,
+        entries: Vec<LinkEntry>,
+        num_threads: usize,
+        rehash_interval: Option<Duration>,
+        repair: bool,
+    ) -> Result<()> {
+        let mut states: HashMap<String, DownloadState> = self
+            .state_manager
+            .load_state()?
             .into_iter()
-            .map(|r| match r {
-                Ok(res) => res,
-                Err(e) => Err(anyhow!("Task error: {}", e)),
-            })
+            .map(|s| (s.file_name.clone(), s))
             .collect();
+        let now = Utc::now();
+        let semaphore = Arc::new(Semaphore::new(num_threads));
+        let hashed_bytes = Arc::new(AtomicU64::new(0));
+        let started = Instant::now();
+        let quiet = self.options.quiet;
 
-        // 清除文件进度条
-        mp.clear().ok();
+        let mut handles = Vec::new();
+        let mut fresh = 0;
+        let mut unchanged = 0;
+        for entry in &entries {
+            let Some(state) = states.get(&entry.file_name).filter(|s| s.completed) else {
+                continue;
+            };
+            let recently_hashed = match (rehash_interval, state.hashed_at) {
+                _ if self.options.force_rehash => false,
+                (Some(interval), Some(hashed_at)) => (now - hashed_at).to_std().is_ok_and(|age| age < interval),
+                _ => false,
+            };
+            if recently_hashed {
+                fresh += 1;
+                continue;
+            }
+
+            let destination = state.destination.clone().unwrap_or_else(|| self.output_dir.clone());
+            let path = self.saved_path(entry, state.saved_as.as_deref(), &destination)?;
+            if self.unchanged_since_verify(state, &path) {
+                unchanged += 1;
+                continue;
+            }
+            let expected = state.sha256.clone().or_else(|| self.sha256_checksum(entry));
+            let file_name = entry.file_name.clone();
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let hashed_bytes = Arc::clone(&hashed_bytes);
+
+            handles.push(task::spawn(async move {
+                let outcome = if !path.exists() {
+                    ScrubOutcome::Missing
+                } else {
+                    let (actual, bytes, elapsed) = hash_blocking(path, checksum::sha256_file).await;
+                    hashed_bytes.fetch_add(bytes, Ordering::Relaxed);
+                    if !quiet {
+                        println!("Hashed {} at {}/s", file_name, hash_rate(bytes, elapsed));
+                    }
+                    match (actual, expected) {
+                        (Ok(actual), None) => ScrubOutcome::Baseline(actual),
+                        (Ok(actual), Some(expected)) if actual == expected => ScrubOutcome::Ok(actual),
+                        (Ok(actual), Some(expected)) => ScrubOutcome::Corrupt { expected, actual },
+                        (Err(e), _) => ScrubOutcome::Error(e.to_string()),
+                    }
+                };
+                drop(permit);
+                (file_name, outcome)
+            }));
+        }
 
-        let final_states = states.lock().unwrap().clone();
+        let outcomes: Vec<(String, ScrubOutcome)> = join_all(handles)
+            .await
+            .into_iter()
+            .map(|r| r.map_err(|e| anyhow!("Task error: {}", e)))
+            .collect::<Result<_>>()?;
+
+        let mut problems = Vec::new();
+        let (mut ok, mut baselined) = (0, 0);
+        for (file_name, outcome) in &outcomes {
+            let state = states.get_mut(file_name).expect("scrubbed file has state");
+            match outcome {
+                ScrubOutcome::Ok(hash) | ScrubOutcome::Baseline(hash) => {
+                    if state.sha256.is_none() {
+                        baselined += 1;
+                    } else {
+                        ok += 1;
+                    }
+                    state.sha256 = Some(hash.clone());
+                    state.hashed_at = Some(now);
+                    state.verified_at = Some(now);
+                }
+                ScrubOutcome::Corrupt { expected, actual } => {
+                    state.verified_at = None;
+                    problems.push(format!("CORRUPT {}: expected {}, got {}", file_name, expected, actual));
+                }
+                ScrubOutcome::Missing => problems.push(format!("MISSING {}", file_name)),
+                ScrubOutcome::Error(e) => problems.push(format!("ERROR   {}: {}", file_name, e)),
+            }
+        }
+        let mut final_states: Vec<DownloadState> = states.values().cloned().collect();
+        final_states.sort_by(|a, b| a.file_name.cmp(&b.file_name));
         self.state_manager.save_state(&final_states)?;
 
-        let mut success = 0;
-        let mut failed = 0;
-        for result in &results {
-            match result {
-                Ok(_) => success += 1,
-                Err(_) => failed += 1,
+        if !problems.is_empty() {
+            let log_path = self.output_dir.join("scrub.log");
+            let mut log = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_path)
+                .with_context(|| format!("Failed to open {}", log_path.display()))?;
+            for problem in &problems {
+                writeln!(log, "{} {}", now.to_rfc3339(), problem)?;
+                if !self.options.quiet {
+                    eprintln!("{}", problem);
+                }
+            }
+        }
+
+        if !self.options.quiet {
+            println!(
+                "\nScrubbed: {} ok, {} baselined, {} failed, {} hashed within interval",
+                ok,
+                baselined,
+                problems.len(),
+                fresh
+            );
+            print_hash_throughput(hashed_bytes.load(Ordering::Relaxed), started.elapsed(), num_threads);
+            if unchanged > 0 {
+                println!("Skipped {} files unchanged since their last verify", unchanged);
             }
         }
-        println!("\nDone: {} success, {} failed", success, failed);
 
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if !repair {
+            return Err(anyhow!("{} files failed the scrub", problems.len()));
+        }
+
+        // 删除损坏的文件并重置状态，保留记录的哈希供下次擦洗比对
+        let broken: Vec<&str> = outcomes
+            .iter()
+            .filter(|(_, o)| matches!(o, ScrubOutcome::Corrupt { .. } | ScrubOutcome::Missing))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let mut repairs = Vec::new();
+        for entry in entries.into_iter().filter(|e| broken.contains(&e.file_name.as_str())) {
+            let state = states.get_mut(&entry.file_name).expect("scrubbed file has state");
+            let destination = state.destination.clone().unwrap_or_else(|| self.output_dir.clone());
+            let path = self.saved_path(&entry, state.saved_as.as_deref(), &destination)?;
+            if path.exists() {
+                fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            }
+            state.completed = false;
+            state.downloaded_bytes = 0;
+            state.total_bytes = None;
+            state.hashed_at = None;
+            repairs.push(entry);
+        }
+        let mut final_states: Vec<DownloadState> = states.into_values().collect();
+        final_states.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        self.state_manager.save_state(&final_states)?;
+
+        if !self.options.quiet {
+            println!("Re-downloading {} files", repairs.len());
+        }
+        let summary = self.download_all(repairs, num_threads).await?;
+        if summary.failed > 0 {
+            return Err(anyhow!("{} files could not be repaired", summary.failed));
+        }
         Ok(())
     }
 
-    pub async fn download_single(&self, entry: &LinkEntry) -> Result<()> {
-        let state = Arc::new(Mutex::new(DownloadState::new(entry.file_name.clone())));
+    /// Describes the files `summary` completed, resolving each one's path on disk.
+    pub fn manifest_records(&self, entries: &[LinkEntry], summary: &RunSummary) -> Result<Vec<ManifestRecord>> {
+        let states: HashMap<String, DownloadState> = self
+            .state_manager
+            .load_state()?
+            .into_iter()
+            .map(|s| (s.file_name.clone(), s))
+            .collect();
+        let entries: HashMap<&str, &LinkEntry> = entries.iter().map(|e| (e.file_name.as_str(), e)).collect();
+
+        let mut records = Vec::new();
+        for outcome in summary.files.iter().filter(|f| f.status == FileStatus::Success) {
+            let Some(entry) = entries.get(outcome.file_name.as_str()) else {
+                continue;
+            };
+            let state = states.get(&entry.file_name);
+            let destination = state
+                .and_then(|s| s.destination.clone())
+                .unwrap_or_else(|| self.output_dir.clone());
+            let saved_as = state.and_then(|s| s.saved_as.as_deref());
+            let path = self.saved_path(entry, saved_as, &destination)?;
+            let size = fs::metadata(&path)
+                .with_context(|| format!("Failed to stat {}", path.display()))?
+                .len();
+            records.push(ManifestRecord {
+                file_name: entry.file_name.clone(),
+                url: entry.url.clone(),
+                path,
+                size,
+                // 只记录本次运行实际校验过的哈希
+                sha256: self.sha256_checksum(entry).filter(|_| self.options.verify_after_download),
+            });
+        }
+        Ok(records)
+    }
+
+    /// Rebuilds state for entries it does not cover by looking for their files and
+    /// `.part`s on disk. A file counts as complete when it matches the manifest size, or
+    /// when the manifest has no size and no partial is left beside it. Returns the number
+    /// of complete and partial files recovered.
+    pub fn rebuild_state(&self, entries: &[LinkEntry]) -> Result<(usize, usize)> {
+        let mut states: HashMap<String, DownloadState> = self
+            .state_manager
+            .load_state()?
+            .into_iter()
+            .map(|s| (s.file_name.clone(), s))
+            .collect();
+
+        let (mut complete, mut partial) = (0, 0);
+        for entry in entries {
+            if states.contains_key(&entry.file_name) {
+                continue;
+            }
+            for dir in &self.output_dirs {
+                let output_path = self.output_path(entry, dir)?;
+                let partial_path = self.partial_path(entry, &output_path);
+                let mut state = DownloadState::new(entry.file_name.clone());
+                state.destination = Some(dir.clone());
+                state.total_bytes = entry.size;
+
+                if let Ok(meta) = fs::metadata(&output_path) {
+                    state.downloaded_bytes = meta.len();
+                    state.completed = match entry.size {
+                        Some(size) => size == meta.len(),
+                        None => !partial_path.exists(),
+                    };
+                } else if let Ok(meta) = fs::metadata(&partial_path) {
+                    state.downloaded_bytes = meta.len();
+                    if self.options.staging_dir.is_some() {
+                        state.partial_path = Some(partial_path);
+                    }
+                } else {
+                    continue;
+                }
+
+                if state.completed {
+                    complete += 1;
+                } else {
+                    partial += 1;
+                }
+                states.insert(entry.file_name.clone(), state);
+                break;
+            }
+        }
+
+        let mut final_states: Vec<DownloadState> = states.into_values().collect();
+        final_states.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        self.state_manager.save_state(&final_states)?;
+        Ok((complete, partial))
+    }
+
+    /// Rewrites the state file without entries whose file (or `.part`) is gone from
+    /// disk, and without every completed entry when `drop_completed` is set.
+    /// Returns how many entries were pruned and how many were kept.
+    pub fn compact_state(&self, drop_completed: bool) -> Result<(usize, usize)> {
+        let states = self.state_manager.load_state()?;
+        let before = states.len();
+        let mut kept = Vec::with_capacity(before);
+        for state in states {
+            if drop_completed && state.completed {
+                continue;
+            }
+            let entry = LinkEntry {
+                file_name: state.file_name.clone(),
+                url: String::new(),
+                checksum: None,
+                mirrors: Vec::new(),
+                block_crcs: None,
+                size: state.total_bytes,
+                metadata: None,
+            };
+            let destination = state.destination.clone().unwrap_or_else(|| self.output_dir.clone());
+            // 文件名不合法的旧记录同样没有对应的文件
+            let Ok(output_path) = self.saved_path(&entry, state.saved_as.as_deref(), &destination) else {
+                continue;
+            };
+            let partial_path = state
+                .partial_path
+                .clone()
+                .unwrap_or_else(|| self.partial_path(&entry, &output_path));
+            if output_path.exists() || (!state.completed && partial_path.exists()) {
+                kept.push(state);
+            }
+        }
+        self.state_manager.save_state(&kept)?;
+        Ok((before - kept.len(), kept.len()))
+    }
+
+    /// `--manifest-url`: fetches the manifest into `<output>/.manifest/`, resuming an
+    /// interrupted transfer like any other file. The cached copy is reused while the
+    /// server reports the same `ETag`/`Last-Modified`, or when the server is unreachable.
+    pub async fn fetch_manifest(&self, url: &str) -> Result<PathBuf> {
+        let name = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.path_segments()?.next_back().map(str::to_string))
+            .filter(|name| validate_file_name(name).is_ok())
+            .unwrap_or_else(|| "manifest.txt".to_string());
+        let cache_dir = self.output_dir.join(MANIFEST_CACHE_DIR);
+        fs::create_dir_all(&cache_dir).map_err(|e| DownloadError::from_io(e, &cache_dir))?;
+        let entry = LinkEntry {
+            file_name: name.clone(),
+            url: url.to_string(),
+            checksum: None,
+            mirrors: Vec::new(),
+            block_crcs: None,
+            size: None,
+            metadata: None,
+        };
+        let path = self.output_path(&entry, &cache_dir)?;
+        let meta_path = cache_dir.join(format!("{}.meta.json", name));
+        let cached: Option<ManifestVersion> = fs::read_to_string(&meta_path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok());
+
+        let remote = match self.manifest_version(url).await {
+            Ok(remote) => remote,
+            Err(e) if path.exists() => {
+                eprintln!(
+                    "Warning: could not check {} for a newer manifest, using the cached copy: {:#}",
+                    secret::redact_url(url),
+                    e
+                );
+                return Ok(path);
+            }
+            Err(e) => return Err(e),
+        };
+        let unchanged = remote.is_known() && cached.as_ref() == Some(&remote);
+        if unchanged && path.exists() {
+            if !self.options.quiet {
+                println!("Manifest unchanged since the last fetch, using {}", path.display());
+            }
+            return Ok(path);
+        }
+        if !unchanged {
+            // 服务器上的清单已更新（或无法判断版本）：旧的缓存和 .part 都不能再用
+            let partial_path = self.partial_path(&entry, &path);
+            for stale in [&path, &partial_path] {
+                if stale.exists() {
+                    fs::remove_file(stale).with_context(|| format!("Failed to remove {}", stale.display()))?;
+                }
+            }
+            tokio::fs::write(&meta_path, serde_json::to_string_pretty(&remote)?)
+                .await
+                .with_context(|| format!("Failed to write {}", meta_path.display()))?;
+        }
+
+        let mut state = DownloadState::new(name);
+        state.destination = Some(cache_dir);
+        state.is_manifest = true;
+        let pb = self.progress_bar(0);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} {bar:40} {bytes}/{total_bytes} ({bytes_per_sec}) {prefix}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+        pb.set_message("Manifest");
+        self.download_file_with_retry(&entry, Arc::new(Mutex::new(state)), &pb)
+            .await
+            .context("Failed to fetch manifest")?;
+        Ok(path)
+    }
+
+    /// Whether a downloaded `--manifest-url` manifest is non-empty and parses as a link file.
+    fn parses_as_link_file(&self, path: &Path) -> bool {
+        path.to_str()
+            .is_some_and(|path| self.parse_link_file(path).is_ok_and(|entries| !entries.is_empty()))
+    }
+
+    /// The validators a HEAD request reports for the manifest at `url`.
+    async fn manifest_version(&self, url: &str) -> Result<ManifestVersion> {
+        let _connection = self.connection().await;
+        self.pace().await;
+        let response = self
+            .request(Method::HEAD, url)
+            .send()
+            .await
+            .context("HEAD request for manifest failed")?;
+        if !response.status().is_success() {
+            return Err(DownloadError::HttpStatus(response.status().as_u16()).into());
+        }
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Ok(ManifestVersion {
+            url: url.to_string(),
+            etag: header("etag"),
+            last_modified: header("last-modified"),
+        })
+    }
+
+    /// Downloads one entry, loading and saving its state like `download_all`, so
+    /// runtime strikes and `--since now` carry over between single-file runs.
+    pub async fn download_single(&self, entry: &LinkEntry) -> Result<RunSummary> {
+        let started = Instant::now();
+        let mut states = self.state_manager.load_state()?;
+        let mut state = states
+            .iter()
+            .find(|s| s.file_name == entry.file_name)
+            .cloned()
+            .unwrap_or_else(|| DownloadState::new(entry.file_name.clone()));
+        self.assign_destination(entry, &mut state, &mut 0);
+        let state = Arc::new(Mutex::new(state));
+
+        let pb = self.progress_bar(100);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} {bar:40} {bytes}/{total_bytes} ({bytes_per_sec}) {prefix}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+        pb.set_message(entry.file_name.clone());
+
+        let result = self.download_file_with_retry(entry, Arc::clone(&state), &pb).await;
+        if !result.as_ref().is_err_and(|e| e.is::<NotModified>() || e.is::<Cancelled>()) {
+            let mut final_state = state.lock().unwrap().clone();
+            final_state.last_error = result.as_ref().err().map(|e| format!("{:#}", e));
+            states.retain(|s| s.file_name != entry.file_name);
+            states.push(final_state);
+            states.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            self.state_manager.save_state(&states)?;
+        }
 
-        let pb = ProgressBar::new(100);
+        let status = match result {
+            Ok(()) => FileStatus::Success,
+            Err(e) if e.is::<NotModified>() => {
+                if !self.options.quiet {
+                    println!("{}", e);
+                }
+                FileStatus::Skipped
+            }
+            Err(e) => return Err(e),
+        };
+
+        let bytes = state.lock().unwrap().downloaded_bytes;
+        Ok(RunSummary {
+            success: usize::from(status == FileStatus::Success),
+            skipped: usize::from(status == FileStatus::Skipped),
+            total_bytes: bytes,
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            quarantined: std::mem::take(&mut *self.quarantined.lock().unwrap()),
+            files: vec![FileOutcome {
+                file_name: entry.file_name.clone(),
+                status,
+                bytes,
+                elapsed_secs: started.elapsed().as_secs_f64(),
+                error: None,
+                checksum_mismatch: None,
+                metadata: entry.metadata.clone(),
+            }],
+            ..Default::default()
+        })
+    }
+
+    /// Fetches only `range` of `entry` into `<output>.<start>-<end>`, e.g. to inspect a
+    /// shard's first tar headers. No resume or size validation is done, and the
+    /// state file is neither read nor written.
+    pub async fn download_byte_range(&self, entry: &LinkEntry, range: ByteRange) -> Result<RunSummary> {
+        let started = Instant::now();
+        let mut state = DownloadState::new(entry.file_name.clone());
+        self.assign_destination(entry, &mut state, &mut 0);
+        let destination = state.destination.clone().unwrap_or_else(|| self.output_dir.clone());
+        let output_path = self.output_path(entry, &destination)?;
+        let target = PathBuf::from(format!("{}.{}", output_path.display(), range));
+        self.create_parent_dir(&target, &destination)?;
+
+        let pb = self.progress_bar(range.len());
         pb.set_style(
             ProgressStyle::default_bar()
                 .template("{msg} {bar:40} {bytes}/{total_bytes} ({bytes_per_sec})")
@@ -390,10 +3722,330 @@ impl Downloader {
         );
         pb.set_message(entry.file_name.clone());
 
-        self.download_file_with_retry(entry, state, &pb).await?;
+        let _connection = self.connection().await;
+        let mut body = match source::local_path(&entry.url) {
+            Some(source_path) => Body::open_local(&source_path, range.start).await?,
+            None => {
+                self.pace().await;
+                let response = self
+                    .get(&entry.url)
+                    .header("Range", range.header_value())
+                    .send()
+                    .await
+                    .context("Range request failed")?;
+                // 服务器忽略 Range 时，只有从 0 开始的区间还能直接截取前缀
+                let usable = response.status() == 206 || (response.status().is_success() && range.start == 0);
+                if !usable {
+                    return Err(anyhow!(
+                        "Server did not honor range {} for {}: HTTP {}",
+                        range,
+                        entry.file_name,
+                        response.status()
+                    ));
+                }
+                Body::Http(response)
+            }
+        };
+
+        let mut file = tokio::fs::File::create(&target)
+            .await
+            .context("Failed to create output file")?;
+        let mut fetched = 0u64;
+        let limiter = self.connection_limiter();
+        while fetched < range.len() {
+            let Some(chunk) = body.chunk().await? else {
+                break;
+            };
+            let take = chunk.len().min((range.len() - fetched) as usize);
+            file.write_all(&chunk[..take]).await?;
+            fetched += take as u64;
+            self.throttle(limiter.as_ref(), take).await;
+            pb.set_position(fetched);
+        }
+        file.flush().await?;
+        pb.finish_with_message("Done");
 
-        Ok(())
+        if !self.options.quiet {
+            println!("Fetched {} bytes to {}", fetched, target.display());
+        }
+
+        Ok(RunSummary {
+            success: 1,
+            total_bytes: fetched,
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            files: vec![FileOutcome {
+                file_name: entry.file_name.clone(),
+                status: FileStatus::Success,
+                bytes: fetched,
+                elapsed_secs: started.elapsed().as_secs_f64(),
+                error: None,
+                checksum_mismatch: None,
+                metadata: entry.metadata.clone(),
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+enum VerifyOutcome {
+    Ok,
+    /// Describes what differed.
+    Mismatch(String),
+    Missing,
+    NoChecksum,
+    Error(String),
+}
+
+enum ScrubOutcome {
+    /// Matches the recorded hash.
+    Ok(String),
+    /// No hash was recorded; this one becomes the reference.
+    Baseline(String),
+    Corrupt { expected: String, actual: String },
+    Missing,
+    Error(String),
+}
+
+/// Hashes `path` on the blocking pool so large files don't stall the runtime, and
+/// reports how many bytes were read and how long it took.
+async fn hash_blocking<F>(path: PathBuf, hash: F) -> (Result<String>, u64, Duration)
+where
+    F: FnOnce(&Path) -> Result<String> + Send + 'static,
+{
+    let started = Instant::now();
+    let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    let result = task::spawn_blocking(move || hash(&path))
+        .await
+        .map_err(|e| anyhow!("Task error: {}", e))
+        .and_then(|r| r);
+    (result, bytes, started.elapsed())
+}
+
+fn hash_rate(bytes: u64, elapsed: Duration) -> HumanBytes {
+    HumanBytes((bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64)
+}
+
+fn print_hash_throughput(bytes: u64, elapsed: Duration, threads: usize) {
+    if bytes > 0 {
+        println!(
+            "Hashed {} in {:.1}s ({}/s with {} hash threads)",
+            HumanBytes(bytes),
+            elapsed.as_secs_f64(),
+            hash_rate(bytes, elapsed),
+            threads
+        );
+    }
+}
+
+/// Compares a downloaded file with the same file in `--compare-dir`: size first,
+/// then the checksum (per `--hash-algo`) of both when the sizes agree.
+fn compare_with_reference(path: &Path, reference: &Path, algo: HashAlgo) -> VerifyOutcome {
+    let sizes = fs::metadata(path).and_then(|a| Ok((a.len(), fs::metadata(reference)?.len())));
+    match sizes {
+        Ok((size, reference_size)) if size != reference_size => {
+            return VerifyOutcome::Mismatch(format!("size {} vs reference {}", size, reference_size));
+        }
+        Ok(_) => {}
+        Err(e) => return VerifyOutcome::Error(e.to_string()),
+    }
+
+    match (checksum::hash_file(path, algo), checksum::hash_file(reference, algo)) {
+        (Ok(actual), Ok(expected)) if actual == expected => VerifyOutcome::Ok,
+        (Ok(actual), Ok(expected)) => {
+            VerifyOutcome::Mismatch(format!("checksum {} vs reference {}", actual, expected))
+        }
+        (Err(e), _) | (_, Err(e)) => VerifyOutcome::Error(e.to_string()),
+    }
+}
+
+/// Which version of a `--manifest-url` manifest is cached, stored next to it.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ManifestVersion {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl ManifestVersion {
+    /// Without a validator there is no telling whether the cached copy is current.
+    fn is_known(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+/// What the size probe learned about the remote file.
+struct RemoteInfo {
+    size: Option<u64>,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+/// Whether `error` is a checksum mismatch, the one failure `--on-checksum-mismatch` governs.
+fn is_checksum_mismatch(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(DownloadError::ChecksumMismatch { .. }))
+}
+
+/// Whether `error` came from failing to establish a connection (including DNS and TCP/TLS setup).
+fn is_connect_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|e| e.is_connect())
+}
+
+/// Whether a request failed with an HTTP/2 stream or connection error (e.g. a
+/// `RST_STREAM` with `PROTOCOL_ERROR`), which HTTP/1.1 may not run into.
+fn is_http2_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let message = cause.to_string();
+        ["http2 error", "stream error", "PROTOCOL_ERROR", "REFUSED_STREAM", "GOAWAY"]
+            .iter()
+            .any(|needle| message.contains(needle))
+    })
+}
+
+/// Whether a request failed because client and server share no acceptable TLS
+/// version, judged from the rustls or OpenSSL error text in the source chain.
+fn is_tls_version_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let message = cause.to_string();
+        ["ProtocolVersion", "SupportedVersions", "protocol version", "unsupported protocol"]
+            .iter()
+            .any(|needle| message.contains(needle))
+    })
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+/// Total size from a `Content-Range: bytes start-end/total` header.
+fn content_range_total(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("content-range")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit('/').next())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+/// The filesystem `path` lives on, so directories sharing one can be told apart.
+#[cfg(unix)]
+fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+// 无法区分文件系统时每个目录单独计算
+#[cfg(not(unix))]
+fn device_of(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Applies `--output-permissions` to a completed file.
+#[cfg(unix)]
+fn set_permissions(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))
+}
+
+// 非 Unix 平台没有权限位，main 启动时已给出警告
+#[cfg(not(unix))]
+fn set_permissions(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// `--preallocate`: reserves disk blocks for the first `len` bytes of `file` without
+/// changing its length, so resuming from the `.part` length keeps working. Returns
+/// `false` when the filesystem can't preallocate.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let len = libc::off_t::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+// 其他平台只能通过改变文件长度来分配空间，会破坏按 .part 长度续传，因此不预分配
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// The file name suggested by a `Content-Disposition` header, reduced to a single
+/// path component; `filename*=UTF-8''...` takes precedence over `filename=`.
+fn content_disposition_filename(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(CONTENT_DISPOSITION)?.to_str().ok()?;
+    let (mut plain, mut extended) = (None, None);
+    for param in value.split(';').skip(1) {
+        let Some((key, val)) = param.split_once('=') else {
+            continue;
+        };
+        let val = val.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => extended = val.split_once("''").map(|(_, name)| listing::percent_decode(name)),
+            "filename" => plain = Some(val.trim_matches('"').to_string()),
+            _ => {}
+        }
+    }
+    let name = extended.or(plain)?;
+    // 只保留最后一个路径分量，防止 ../ 或绝对路径逃出输出目录
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." || name.chars().any(char::is_control) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// First byte position of a `Content-Range: bytes <start>-<end>/<total>` header.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("content-range")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes "))
+        .and_then(|v| v.split('-').next())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+}
+
+/// Returns the first output directory whose filesystem has less than `min_free` bytes available.
+fn find_low_space(dirs: &[PathBuf], min_free: u64) -> Option<(PathBuf, u64)> {
+    dirs.iter().find_map(|dir| match fs4::available_space(dir) {
+        Ok(available) if available < min_free => Some((dir.clone(), available)),
+        _ => None,
+    })
+}
+
+/// Rejects manifest file names that are empty, absolute, contain `..`, or name a
+/// directory rather than a file, so an untrusted link file cannot write outside the
+/// output directory.
+pub fn validate_file_name(file_name: &str) -> Result<()> {
+    if file_name.trim().is_empty() {
+        return Err(anyhow!("Empty file name"));
+    }
+    if file_name.contains('\\') || file_name.contains('\0') {
+        return Err(anyhow!("Unsafe file name: {}", file_name));
+    }
+    // 以 `/` 或 `.` 结尾的名字指向目录本身
+    if matches!(file_name.rsplit('/').next(), Some("" | ".")) {
+        return Err(anyhow!("Unsafe file name: {}", file_name));
+    }
+    for component in Path::new(file_name).components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => return Err(anyhow!("Unsafe file name: {}", file_name)),
+        }
     }
+    Ok(())
 }
 
 impl Clone for Downloader {
@@ -401,9 +4053,20 @@ impl Clone for Downloader {
         Self {
             client: self.client.clone(),
             output_dir: self.output_dir.clone(),
-            state_manager: StateManager::new(&self.output_dir),
-            resume: self.resume,
-            retries: self.retries,
+            output_dirs: self.output_dirs.clone(),
+            state_manager: StateManager::new(&self.output_dir, self.options.state_format),
+            options: self.options.clone(),
+            health: Arc::clone(&self.health),
+            pacer: self.pacer.clone(),
+            bandwidth: self.bandwidth.clone(),
+            dns: Arc::clone(&self.dns),
+            memory: self.memory.clone(),
+            connections: self.connections.clone(),
+            ranges: self.ranges.clone(),
+            quarantined: Arc::clone(&self.quarantined),
+            http1_client: self.http1_client.clone(),
+            http1_hosts: Arc::clone(&self.http1_hosts),
+            failed_attempts: Arc::clone(&self.failed_attempts),
         }
     }
 }
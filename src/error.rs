@@ -0,0 +1,82 @@
+use indicatif::HumanBytes;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Why a single file failed to download. The download functions return these inside
+/// `anyhow::Error`, so callers branch with `err.downcast_ref::<DownloadError>()`.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("File size mismatch for {file_name}: expected {expected} bytes, got {actual} bytes")]
+    SizeMismatch { file_name: String, expected: u64, actual: u64 },
+
+    /// A completed file whose checksum did not match under `--verify-after-download`.
+    #[error("Checksum mismatch for {file_name}: expected {expected}, got {actual}")]
+    ChecksumMismatch { file_name: String, expected: String, actual: String },
+
+    /// A resumed request answered with a range that doesn't start at the end of the `.part`.
+    #[error("Server resumed {file_name} at byte {actual} instead of {expected}")]
+    RangeMismatch { file_name: String, expected: u64, actual: u64 },
+
+    /// The server could not negotiate TLS at or above `--min-tls-version`.
+    #[error("{host} does not support {minimum} or newer (required by --min-tls-version)")]
+    TlsVersion { host: String, minimum: String },
+
+    #[error("HTTP request failed: {0}")]
+    HttpStatus(u16),
+
+    /// No data arrived for `--max-idle-time`.
+    #[error("Transfer stalled: no data received for {}s at offset {offset}", .idle.as_secs())]
+    Timeout { idle: Duration, offset: u64 },
+
+    #[error("I/O error on {}: {source}", .path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Disk full while writing {}", .path.display())]
+    DiskFull { path: PathBuf },
+
+    /// Writes to the output disk stayed below `--min-disk-speed`.
+    #[error(
+        "Disk write speed to {} fell to {}/s (minimum {}/s); check the disk's health",
+        .path.display(),
+        HumanBytes(*.bytes_per_sec),
+        HumanBytes(*.minimum)
+    )]
+    DiskTooSlow { path: PathBuf, bytes_per_sec: u64, minimum: u64 },
+
+    /// An output or staging directory refuses writes.
+    #[error("output directory not writable: {} (fix its permissions or choose another --output/--tmp-dir)", .path.display())]
+    NotWritable { path: PathBuf },
+
+    /// A mirror that claims success for an object it doesn't actually have.
+    #[error("{file_name} is not available at {url}: {reason}")]
+    Unavailable { file_name: String, url: String, reason: String },
+}
+
+impl DownloadError {
+    /// Classifies an I/O failure on `path`: permission problems become `NotWritable`
+    /// (naming the directory), a full disk `DiskFull`, anything else `Io`.
+    pub fn from_io(error: io::Error, path: &Path) -> Self {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                let dir = if path.is_dir() { path } else { path.parent().unwrap_or(path) };
+                DownloadError::NotWritable { path: dir.to_path_buf() }
+            }
+            io::ErrorKind::StorageFull => DownloadError::DiskFull { path: path.to_path_buf() },
+            _ => DownloadError::Io { path: path.to_path_buf(), source: error },
+        }
+    }
+
+    /// Whether another attempt at the same file could succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::HttpStatus(status) => *status >= 500 || *status == 408 || *status == 429,
+            DownloadError::DiskFull { .. }
+            | DownloadError::DiskTooSlow { .. }
+            | DownloadError::NotWritable { .. }
+            | DownloadError::TlsVersion { .. } => false,
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::{self, JoinHandle};
+
+/// Marker written into an extraction directory once unpacking has finished.
+const EXTRACTED_MARKER: &str = ".extracted";
+
+/// Directory a shard is unpacked into: `sa_000000.tar` -> `sa_000000/` next to it.
+pub fn extraction_dir(archive: &Path) -> PathBuf {
+    let stem = archive
+        .file_stem()
+        .map(|s| s.to_os_string())
+        .unwrap_or_default();
+    archive.with_file_name(stem)
+}
+
+pub fn is_extracted(archive: &Path) -> bool {
+    extraction_dir(archive).join(EXTRACTED_MARKER).exists()
+}
+
+/// A regular file inside a tar archive, located for random access.
+#[derive(Debug, Serialize)]
+pub struct TarMember {
+    pub name: String,
+    /// Byte offset of the member's data (not its header) from the start of the archive.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Sidecar written by `--tar-index`: `sa_000000.tar` -> `sa_000000.index.json`.
+pub fn index_path(archive: &Path) -> PathBuf {
+    archive.with_extension("index.json")
+}
+
+/// Unpacks a tar archive, also writing its member index when `index` is set.
+/// `tar::Archive::unpack` and `Entry::unpack_in` already refuse entries that
+/// would escape the destination directory.
+pub fn extract_tar(archive: &Path, index: bool) -> Result<()> {
+    let dest = extraction_dir(archive);
+    fs::create_dir_all(&dest).context("Failed to create extraction directory")?;
+
+    let file = File::open(archive)
+        .with_context(|| format!("Failed to open {} for extraction", archive.display()))?;
+    if index {
+        // 解压时顺带记录每个成员的偏移，无需之后再扫描一遍
+        let members = scan_tar(file, Some(&dest)).with_context(|| format!("Failed to extract {}", archive.display()))?;
+        write_index(archive, &members)?;
+    } else {
+        tar::Archive::new(file)
+            .unpack(&dest)
+            .with_context(|| format!("Failed to extract {}", archive.display()))?;
+    }
+
+    File::create(dest.join(EXTRACTED_MARKER)).context("Failed to write extraction marker")?;
+    Ok(())
+}
+
+/// Writes the member index of an archive that was extracted without one.
+pub fn index_tar(archive: &Path) -> Result<()> {
+    let file = File::open(archive).with_context(|| format!("Failed to open {} for indexing", archive.display()))?;
+    let members = scan_tar(file, None).with_context(|| format!("Failed to index {}", archive.display()))?;
+    write_index(archive, &members)
+}
+
+/// Walks the archive's entries, unpacking each into `dest` if given, and returns
+/// the regular file members.
+fn scan_tar(file: File, dest: Option<&Path>) -> Result<Vec<TarMember>> {
+    let mut tar = tar::Archive::new(file);
+    let mut members = Vec::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() {
+            members.push(TarMember {
+                name: entry.path()?.to_string_lossy().into_owned(),
+                offset: entry.raw_file_position(),
+                size: entry.size(),
+            });
+        }
+        if let Some(dest) = dest {
+            entry.unpack_in(dest)?;
+        }
+    }
+    Ok(members)
+}
+
+fn write_index(archive: &Path, members: &[TarMember]) -> Result<()> {
+    let path = index_path(archive);
+    let content = serde_json::to_vec_pretty(members).context("Failed to serialize tar index")?;
+    fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractSummary {
+    pub extracted: usize,
+    pub skipped: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// A fixed pool of extraction workers fed by a channel, so CPU-bound unpacking
+/// runs alongside downloads instead of competing for download slots.
+pub struct ExtractPool {
+    sender: mpsc::UnboundedSender<PathBuf>,
+    workers: Vec<JoinHandle<ExtractSummary>>,
+}
+
+impl ExtractPool {
+    /// Starts `threads` workers; with `index` each archive also gets a member index.
+    pub fn start(threads: usize, index: bool) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel::<PathBuf>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                task::spawn(async move {
+                    let mut summary = ExtractSummary::default();
+                    loop {
+                        let next = receiver.lock().await.recv().await;
+                        let Some(archive) = next else {
+                            break;
+                        };
+
+                        if is_extracted(&archive) {
+                            summary.skipped += 1;
+                            // 早先未建索引的已解压归档只补写索引
+                            if index && !index_path(&archive).exists() {
+                                let path = archive.clone();
+                                let result = task::spawn_blocking(move || index_tar(&path))
+                                    .await
+                                    .map_err(|e| anyhow!("Task error: {}", e))
+                                    .and_then(|r| r);
+                                if let Err(e) = result {
+                                    summary.failed.push((archive, format!("{:#}", e)));
+                                }
+                            }
+                            continue;
+                        }
+
+                        let path = archive.clone();
+                        let result = task::spawn_blocking(move || extract_tar(&path, index))
+                            .await
+                            .map_err(|e| anyhow!("Task error: {}", e))
+                            .and_then(|r| r);
+                        match result {
+                            Ok(()) => summary.extracted += 1,
+                            Err(e) => summary.failed.push((archive, format!("{:#}", e))),
+                        }
+                    }
+                    summary
+                })
+            })
+            .collect();
+
+        Self { sender, workers }
+    }
+
+    pub fn sender(&self) -> mpsc::UnboundedSender<PathBuf> {
+        self.sender.clone()
+    }
+
+    /// Closes the queue and waits for all queued archives to be extracted.
+    pub async fn finish(self) -> ExtractSummary {
+        drop(self.sender);
+
+        let mut total = ExtractSummary::default();
+        for worker in self.workers {
+            match worker.await {
+                Ok(summary) => {
+                    total.extracted += summary.extracted;
+                    total.skipped += summary.skipped;
+                    total.failed.extend(summary.failed);
+                }
+                Err(e) => total.failed.push((PathBuf::new(), format!("Task error: {}", e))),
+            }
+        }
+        total
+    }
+}
@@ -0,0 +1,65 @@
+//! `--per-file-log`: a transcript per file (size probe, requests, offsets, retries,
+//! timing). The log is bound to the task working on the file, so any function on
+//! that path can add a line without a handle being threaded through every call.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+tokio::task_local! {
+    static CURRENT: Arc<FileLog>;
+}
+
+pub struct FileLog {
+    file: Mutex<File>,
+    started: Instant,
+}
+
+impl FileLog {
+    /// Opens `<dir>/<file_name>.log` for appending; nested names are flattened
+    /// with `__` so every log sits directly in `dir`.
+    pub fn open(dir: &Path, file_name: &str) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+        let path = dir.join(format!("{}.log", file_name.replace('/', "__")));
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let log = Self {
+            file: Mutex::new(file),
+            started: Instant::now(),
+        };
+        log.write(&format!("--- {} ---", file_name));
+        Ok(log)
+    }
+
+    fn write(&self, message: &str) {
+        let line = format!(
+            "[{}] +{:.3}s {}\n",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.started.elapsed().as_secs_f64(),
+            message
+        );
+        // 日志只用于排查问题，写入失败不影响下载
+        self.file.lock().unwrap().write_all(line.as_bytes()).ok();
+    }
+}
+
+/// Runs `future` with `log` as the current file's log, if there is one.
+pub async fn scope<F: Future>(log: Option<Arc<FileLog>>, future: F) -> F::Output {
+    match log {
+        Some(log) => CURRENT.scope(log, future).await,
+        None => future.await,
+    }
+}
+
+/// Appends a line to the current file's log. `message` is only built when a log
+/// is active.
+pub fn note(message: impl FnOnce() -> String) {
+    CURRENT.try_with(|log| log.write(&message())).ok();
+}
@@ -0,0 +1,60 @@
+//! `--on-start` / `--on-finish`: shell commands run around a whole batch, e.g. to
+//! mount a volume or send a notification.
+
+use crate::models::RunSummary;
+use anyhow::{anyhow, Context, Result};
+use tokio::process::Command;
+
+/// Runs `command` through the platform shell with `env` added and waits for it;
+/// a non-zero exit is an error.
+pub async fn run(command: &str, env: &[(&str, String)]) -> Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .status()
+        .await
+        .with_context(|| format!("Failed to run hook `{}`", command))?;
+    if !status.success() {
+        return Err(anyhow!("Hook `{}` exited with {}", command, status));
+    }
+    Ok(())
+}
+
+/// Environment for `--on-start`.
+pub fn start_env(files: usize) -> Vec<(&'static str, String)> {
+    vec![("SA1B_FILES", files.to_string())]
+}
+
+/// Environment for `--on-finish`: the batch outcome, or the error that ended it.
+pub fn finish_env(result: &Result<RunSummary>) -> Vec<(&'static str, String)> {
+    match result {
+        Ok(summary) => {
+            let failed = summary.failed > 0 || summary.abort_reason.is_some();
+            vec![
+                ("SA1B_STATUS", if failed { "failed" } else { "success" }.to_string()),
+                ("SA1B_SUCCESS", summary.success.to_string()),
+                ("SA1B_FAILED", summary.failed.to_string()),
+                ("SA1B_SKIPPED", summary.skipped.to_string()),
+                ("SA1B_DEFERRED", summary.deferred.to_string()),
+                ("SA1B_CANCELLED", summary.cancelled.to_string()),
+                ("SA1B_NOT_STARTED", summary.not_started.to_string()),
+                ("SA1B_TOTAL_BYTES", summary.total_bytes.to_string()),
+                ("SA1B_ELAPSED_SECS", format!("{:.1}", summary.elapsed_secs)),
+                ("SA1B_ERROR", summary.abort_reason.clone().unwrap_or_default()),
+            ]
+        }
+        Err(e) => vec![
+            ("SA1B_STATUS", "error".to_string()),
+            ("SA1B_ERROR", format!("{:#}", e)),
+        ],
+    }
+}
@@ -0,0 +1,33 @@
+//! Download engine behind the `sa-1b-dl` binary, exposed as a library so the
+//! integration tests can drive it directly.
+
+pub mod autotune;
+pub mod blockcrc;
+pub mod checksum;
+pub mod cli;
+pub mod columns;
+pub mod dedup;
+pub mod dns;
+pub mod downloader;
+pub mod error;
+pub mod extract;
+pub mod filelog;
+pub mod hooks;
+pub mod limiter;
+pub mod listing;
+pub mod mirrors;
+pub mod models;
+pub mod multipart;
+pub mod progress;
+pub mod ranges;
+pub mod report;
+pub mod schedule;
+pub mod secret;
+pub mod segments;
+pub mod since;
+pub mod source;
+pub mod state;
+pub mod tarcheck;
+pub mod units;
+pub mod useragent;
+pub mod warmup;
@@ -0,0 +1,138 @@
+use crate::schedule::BandwidthSchedule;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+
+/// Spaces out HTTP requests across all workers so that no more than `rate`
+/// requests per second are issued, independent of concurrency.
+#[derive(Debug)]
+pub struct RequestPacer {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RequestPacer {
+    pub fn new(requests_per_second: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / requests_per_second),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the caller may issue its next request.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().await;
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            *next_slot = slot + self.interval;
+            slot - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// How often a scheduled bandwidth cap is re-read from the wall clock.
+const RATE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token bucket shared by all workers that caps total bytes per second. The cap
+/// is either fixed or follows a [`BandwidthSchedule`]; windows without a cap fall
+/// back to the fixed limit, or no limit at all.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    fixed: Option<u64>,
+    schedule: Option<BandwidthSchedule>,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    rate_checked: Instant,
+    /// May go negative; the deficit is what the caller has to sleep off.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    pub fn new(fixed: Option<u64>, schedule: Option<BandwidthSchedule>) -> Self {
+        let now = Instant::now();
+        let rate = schedule.as_ref().and_then(|s| s.rate_now()).or(fixed);
+        Self {
+            fixed,
+            schedule,
+            bucket: Mutex::new(Bucket {
+                rate,
+                rate_checked: now,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: now,
+            }),
+        }
+    }
+
+    /// Accounts for `bytes` just transferred, sleeping if that exceeds the current rate.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+
+            if now.duration_since(bucket.rate_checked) >= RATE_REFRESH_INTERVAL {
+                bucket.rate = self.schedule.as_ref().and_then(|s| s.rate_now()).or(self.fixed);
+                bucket.rate_checked = now;
+            }
+            let Some(rate) = bucket.rate else {
+                bucket.last_refill = now;
+                return;
+            };
+
+            // 桶容量为一秒的流量，允许短时突发
+            let rate = rate as f64;
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Memory reserved for the chunk a transfer is reading, on top of its write buffer;
+/// matches the largest buffer a local source allocates and comfortably exceeds typical
+/// HTTP body chunks.
+pub const CHUNK_RESERVATION: usize = 256 * 1024;
+
+/// Budget accounting granularity; keeps permit counts within `u32`.
+const MEMORY_UNIT: usize = 1024;
+
+/// Global cap on bytes held in chunk buffers between reading and writing them.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    semaphore: Semaphore,
+    units: u32,
+}
+
+impl MemoryBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        let units = (max_bytes / MEMORY_UNIT as u64).clamp(1, u32::MAX as u64) as u32;
+        Self {
+            semaphore: Semaphore::new(units as usize),
+            units,
+        }
+    }
+
+    /// Waits until `bytes` fit in the budget. Requests larger than the whole budget
+    /// take all of it rather than waiting forever.
+    pub async fn reserve(&self, bytes: usize) -> SemaphorePermit<'_> {
+        let units = (bytes.div_ceil(MEMORY_UNIT) as u64).clamp(1, self.units as u64) as u32;
+        self.semaphore
+            .acquire_many(units)
+            .await
+            .expect("memory budget semaphore is never closed")
+    }
+}
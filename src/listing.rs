@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+
+/// One link found in a directory listing, relative to the listed directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingItem {
+    pub url: Url,
+    /// Path below the listed directory, without a trailing slash.
+    pub name: String,
+    pub is_dir: bool,
+    pub size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonItem {
+    Name(String),
+    Entry {
+        name: String,
+        #[serde(default, rename = "type")]
+        kind: Option<String>,
+        #[serde(default)]
+        size: Option<u64>,
+    },
+}
+
+/// Parses a directory index fetched from `base`: a JSON array (nginx `autoindex_format
+/// json` style objects or plain names) when `content_type` says JSON, otherwise the
+/// `href`s of an HTML page. Links leaving the directory (parents, other hosts, sort
+/// links) are dropped.
+pub fn parse_listing(base: &Url, content_type: &str, body: &str) -> Result<Vec<ListingItem>> {
+    let links: Vec<(String, Option<bool>, Option<u64>)> = if content_type.contains("json") {
+        let items: Vec<JsonItem> = serde_json::from_str(body).context("Failed to parse JSON directory listing")?;
+        items
+            .into_iter()
+            .map(|item| match item {
+                JsonItem::Name(name) => (name, None, None),
+                JsonItem::Entry { name, kind, size } => {
+                    let is_dir = kind.map(|k| k == "directory" || k == "dir");
+                    // 目录链接需要以 / 结尾才能正确拼接子路径
+                    let name = match is_dir {
+                        Some(true) if !name.ends_with('/') => format!("{}/", name),
+                        _ => name,
+                    };
+                    (name, is_dir, size)
+                }
+            })
+            .collect()
+    } else {
+        hrefs(body).into_iter().map(|href| (href, None, None)).collect()
+    };
+
+    let mut items: Vec<ListingItem> = Vec::new();
+    for (link, is_dir, size) in links {
+        let Ok(url) = base.join(&link) else {
+            continue;
+        };
+        let Some(relative) = url.as_str().strip_prefix(base.as_str()) else {
+            continue;
+        };
+        if relative.is_empty() || url.query().is_some() || url.fragment().is_some() {
+            continue;
+        }
+        let is_dir = is_dir.unwrap_or_else(|| relative.ends_with('/'));
+        let name = percent_decode(relative.trim_end_matches('/'));
+        if items.iter().any(|item| item.name == name) {
+            continue;
+        }
+        items.push(ListingItem { url, name, is_dir, size });
+    }
+    Ok(items)
+}
+
+/// Values of every `href` attribute in an HTML page.
+fn hrefs(html: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(found) = lower[rest..].find("href=") {
+        let start = rest + found + "href=".len();
+        let value = &html[start..];
+        let (link, consumed) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                Some(end) => (&value[1..end + 1], end + 2),
+                None => break,
+            },
+            _ => {
+                let end = value.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(value.len());
+                (&value[..end], end)
+            }
+        };
+        links.push(link.replace("&amp;", "&"));
+        rest = start + consumed;
+    }
+    links
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
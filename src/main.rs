@@ -1,49 +1,260 @@
-mod cli;
-mod downloader;
-mod models;
-mod state;
-
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use cli::{Args, DownloadMode};
-use downloader::Downloader;
+use indicatif::HumanBytes;
+use sa_1b_dl::checksum::HashAlgo;
+use sa_1b_dl::cli::{Args, DownloadMode, UrlListFormat};
+use sa_1b_dl::downloader::Downloader;
+use sa_1b_dl::models::{BatchCursor, DownloadState, FileStatus, LinkEntry, RunSummary};
+use sa_1b_dl::state::{StateManager, STATE_SCHEMA_VERSION};
+use sa_1b_dl::{blockcrc, checksum, hooks, report};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::path::Path;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let link_file = args.link_file.unwrap_or_else(|| "sa-1b_link.txt".to_string());
+    if cfg!(not(unix)) && args.output_permissions.is_some() {
+        eprintln!("Warning: --output-permissions is only supported on Unix and will be ignored");
+    }
+
+    let options = args.download_options()?;
+    let downloader = Downloader::new(&args.output, options)?;
+
+    if args.validate_json_state {
+        return validate_state(&args);
+    }
+
+    // 整理状态文件只看磁盘上的文件，不需要链接文件
+    if args.compact_state {
+        let (pruned, kept) = downloader.compact_state(args.compact_completed)?;
+        println!("Compacted state: pruned {} entries, kept {}", pruned, kept);
+        return Ok(());
+    }
+
+    let link_file = match &args.manifest_url {
+        Some(url) => downloader.fetch_manifest(url).await?.display().to_string(),
+        None => args.link_file.clone().unwrap_or_else(|| "sa-1b_link.txt".to_string()),
+    };
 
     if !Path::new(&link_file).exists() {
         return Err(anyhow!("Link file not found: {}", link_file));
     }
 
-    let downloader = Downloader::new(&args.output, args.resume, args.proxy.as_deref(), args.retries)?;
-
     let mut entries = downloader.parse_link_file(&link_file)?;
 
+    if args.expand_dirs {
+        let listed = entries.len();
+        entries = downloader.expand_dirs(entries).await?;
+        if !args.quiet_stdout() {
+            println!("Expanded {} link file entries into {} files", listed, entries.len());
+        }
+    }
+
     if entries.is_empty() {
         return Err(anyhow!("No entries found in link file"));
     }
 
+    if let Some(expected) = args.expected_count
+        && entries.len() != expected
+    {
+        return Err(anyhow!(
+            "Link file {} has {} entries but --expected-count is {}; it may be truncated or corrupted",
+            link_file,
+            entries.len(),
+            expected
+        ));
+    }
+
     entries.sort_by(|a, b| a.file_name.cmp(&b.file_name));
 
-    println!("Loaded {} entries from {}", entries.len(), link_file);
+    if args.list_failed {
+        return list_failed(&args, &entries);
+    }
+
+    let quiet = args.quiet_stdout();
+    if !quiet {
+        println!("Loaded {} entries from {}", entries.len(), link_file);
+    }
 
-    match args.mode {
-        DownloadMode::All => {
-            downloader.download_all(entries.clone(), args.threads).await?;
+    if let Some(sums_file) = &args.checksum_from {
+        let sums = checksum::parse_sums_file(sums_file)?;
+        let mut unlisted = 0;
+        for entry in &mut entries {
+            if let Some(sum) = sums.get(&entry.file_name) {
+                entry.checksum = Some(sum.clone());
+            }
+            if entry.checksum.is_none() {
+                unlisted += 1;
+            }
+        }
+        if !quiet {
+            println!("Loaded {} checksums from {}", sums.len(), sums_file);
+        }
+        if unlisted > 0 && !args.fail_on_missing_checksum {
+            eprintln!(
+                "Warning: {} files are not listed in {} and will not be verified",
+                unlisted, sums_file
+            );
+        }
+    }
+
+    if args.fail_on_missing_checksum {
+        let unverifiable: Vec<&str> = entries
+            .iter()
+            .filter(|e| e.checksum.is_none())
+            .map(|e| e.file_name.as_str())
+            .collect();
+        if !unverifiable.is_empty() {
+            return Err(anyhow!(
+                "{} entries have no checksum (--fail-on-missing-checksum):\n  {}",
+                unverifiable.len(),
+                unverifiable.join("\n  ")
+            ));
+        }
+    }
+
+    // auto 模式下提前检查每个校验和的长度，避免下载完成后才报错
+    if args.hash_algo == HashAlgo::Auto {
+        for entry in &entries {
+            if let Some(sum) = &entry.checksum {
+                args.hash_algo
+                    .resolve(sum)
+                    .with_context(|| format!("Invalid checksum for {}", entry.file_name))?;
+            }
+        }
+    }
+
+    if let Some(crc_file) = &args.block_crc_file {
+        let mut blocks = blockcrc::load_block_crc_file(crc_file)?;
+        for entry in &mut entries {
+            entry.block_crcs = blocks.remove(&entry.file_name);
+        }
+    }
+
+    if let Some(old_manifest) = &args.manifest_diff {
+        let old_entries = downloader.parse_link_file(old_manifest)?;
+        let old_names: HashSet<&str> = old_entries.iter().map(|e| e.file_name.as_str()).collect();
+        let new_names: HashSet<&str> = entries.iter().map(|e| e.file_name.as_str()).collect();
+        let removed = old_names.difference(&new_names).count();
+        let unchanged = new_names.intersection(&old_names).count();
+
+        entries.retain(|e| !old_names.contains(e.file_name.as_str()));
+        if !quiet {
+            println!(
+                "Manifest diff against {}: {} added, {} removed, {} unchanged",
+                old_manifest,
+                entries.len(),
+                removed,
+                unchanged
+            );
+        }
+        if entries.is_empty() {
+            if quiet {
+                println!("{}", serde_json::to_string(&RunSummary::default())?);
+            } else {
+                println!("No new entries to download");
+            }
+            return Ok(());
+        }
+    }
+
+    // verify 和 --print-urls 不写输出目录，其余模式先确认目录可写
+    if !matches!(args.mode, DownloadMode::Verify) && args.print_urls.is_none() {
+        downloader.check_writable()?;
+    }
+
+    if args.resume_scan {
+        let (complete, partial) = downloader.rebuild_state(&entries)?;
+        if !quiet {
+            println!("Rebuilt state from disk: {} complete, {} partial", complete, partial);
+        }
+    }
+
+    if let Some(count) = args.preflight_sample {
+        let mut sample: Vec<LinkEntry> = fastrand::choose_multiple(entries.iter().cloned(), count);
+        sample.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        if !quiet {
+            println!("Preflight: downloading {} random files of {}", sample.len(), entries.len());
+        }
+        let attempted = sample.len();
+        let summary = downloader.download_all(sample, args.threads).await?;
+        if quiet {
+            println!("{}", serde_json::to_string(&summary)?);
+        }
+        // 只要有一个样本失败就以非零状态退出，便于 CI 拦截完整下载
+        if summary.failed > 0 || summary.success + summary.skipped < attempted {
+            return Err(anyhow!(
+                "Preflight failed: {} of {} sampled files did not download",
+                attempted - summary.success - summary.skipped,
+                attempted
+            ));
+        }
+        if !quiet {
+            println!("Preflight passed: all {} sampled files downloaded", attempted);
+        }
+        return Ok(());
+    }
+
+    let batch = match args.mode {
+        DownloadMode::All => match args.range_by_size {
+            Some(target) => {
+                let sizes = downloader.probe_sizes(&entries, args.threads).await;
+                let unknown = sizes.iter().filter(|s| s.is_none()).count();
+                if unknown > 0 {
+                    eprintln!("Warning: {} files have an unknown size and count as 0 bytes", unknown);
+                }
+                let batches = size_batches(&sizes, target);
+                let Some(index) = args.batch_index else {
+                    for (i, (range, bytes)) in batches.iter().enumerate() {
+                        println!(
+                            "Batch {}: --start {} --end {} ({} files, {})",
+                            i,
+                            range.start,
+                            range.end - 1,
+                            range.len(),
+                            HumanBytes(*bytes)
+                        );
+                    }
+                    return Ok(());
+                };
+                let (range, bytes) = batches
+                    .get(index)
+                    .ok_or_else(|| anyhow!("--batch-index {} is out of range ({} batches)", index, batches.len()))?;
+                if !quiet {
+                    println!(
+                        "Batch {} of {}: files {} to {} ({} files, {})",
+                        index,
+                        batches.len(),
+                        range.start,
+                        range.end - 1,
+                        range.len(),
+                        HumanBytes(*bytes)
+                    );
+                }
+                select_batch(&args, &entries, range.start, range.end)?
+            }
+            None => select_batch(&args, &entries, 0, entries.len())?,
+        },
+        DownloadMode::Verify => {
+            downloader.verify_all(entries, args.hash_threads()).await?;
+            return Ok(());
+        }
+        DownloadMode::Scrub => {
+            downloader
+                .scrub_all(entries, args.hash_threads(), args.rehash_interval, args.scrub_repair)
+                .await?;
+            return Ok(());
         }
         DownloadMode::Single => {
-            let file_name = args.file.ok_or_else(|| anyhow!("--file argument required for single mode"))?;
+            let file_name = args.file.clone().ok_or_else(|| anyhow!("--file argument required for single mode"))?;
             let entry = entries
                 .iter()
                 .find(|e| e.file_name == file_name)
                 .ok_or_else(|| anyhow!("File not found in link file: {}", file_name))?
                 .clone();
-
-            downloader.download_single(&entry).await?;
+            vec![entry]
         }
         DownloadMode::Range => {
             let start = args.start.ok_or_else(|| anyhow!("--start argument required for range mode"))?;
@@ -51,11 +262,292 @@ async fn main() -> Result<()> {
             if start >= entries.len() || end >= entries.len() || start > end {
                 return Err(anyhow!("Invalid range: start={}, end={}, total={}", start, end, entries.len()));
             }
-            let range_entries = entries[start..=end].to_vec();
-            println!("Downloading files from index {} to {} ({} files)", start, end, range_entries.len());
-            downloader.download_all(range_entries, args.threads).await?;
+            if !quiet {
+                println!("Downloading files from index {} to {} ({} files)", start, end, end - start + 1);
+            }
+            select_batch(&args, &entries, start, end + 1)?
+        }
+    };
+
+    if let Some(format) = args.print_urls {
+        print_urls(&batch, format, &args.output[0]);
+        return Ok(());
+    }
+
+    // 游标按链接文件顺序推进，记下调整下载顺序之前的批次
+    let selected = (!matches!(args.mode, DownloadMode::Single)).then(|| batch.clone());
+
+    let threads = if args.workers_auto {
+        let threads = downloader.auto_workers(&batch, args.threads).await?;
+        if !quiet {
+            println!("Auto-selected {} worker threads", threads);
         }
+        threads
+    } else {
+        args.threads
+    };
+
+    let batch = if let Some(order_file) = &args.download_order_file {
+        apply_download_order(&args, batch, order_file)?
+    } else if args.smallest_first {
+        downloader.smallest_first(batch, threads).await
+    } else {
+        batch
+    };
+
+    if selected.is_some() {
+        downloader.check_free_space(&batch, threads).await?;
     }
 
+    if args.probe_ranges {
+        for (host, capability) in downloader.probe_hosts(&batch).await {
+            match capability {
+                Ok(capability) if !quiet => println!("Range support for {}: {}", host, capability),
+                Ok(_) => {}
+                Err(e) => eprintln!("Warning: could not probe range support of {}: {:#}", host, e),
+            }
+        }
+    }
+
+    if args.keep_open_connections {
+        for warmup in downloader.warm_up(&batch, threads).await {
+            if args.verbose && !quiet {
+                println!(
+                    "Warmed {} connections to {}: cold request {:?}, pooled request {:?} (~{:?} saved per file)",
+                    warmup.connections,
+                    warmup.host,
+                    warmup.cold,
+                    warmup.warm,
+                    warmup.saving()
+                );
+            }
+        }
+    }
+
+    if let Some(command) = &args.on_start {
+        hooks::run(command, &hooks::start_env(batch.len()))
+            .await
+            .context("--on-start hook failed")?;
+    }
+
+    let result = match args.mode {
+        DownloadMode::Single => match args.byte_range {
+            Some(range) => downloader.download_byte_range(&batch[0], range).await,
+            None => downloader.download_single(&batch[0]).await,
+        },
+        _ => downloader.download_all(batch, threads).await,
+    };
+
+    // 批次出错时同样运行结束钩子，保证总能收到通知
+    if let Some(command) = &args.on_finish
+        && let Err(e) = hooks::run(command, &hooks::finish_env(&result)).await
+    {
+        eprintln!("Warning: --on-finish hook failed: {:#}", e);
+    }
+    let summary = result?;
+
+    if let Some(selected) = &selected {
+        advance_cursor(&args, &entries, selected, &summary)?;
+    }
+
+    if let Some(path) = &args.output_manifest {
+        let records = downloader.manifest_records(&entries, &summary)?;
+        report::write_output_manifest(Path::new(path), &records)?;
+        if !quiet {
+            println!("Wrote manifest of {} files to {}", records.len(), path);
+        }
+    }
+
+    if let Some(path) = &args.summary_csv {
+        report::append_summary_csv(Path::new(path), &summary)?;
+    }
+
+    if quiet {
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+
+    if let Some(reason) = summary.abort_reason {
+        return Err(anyhow!(reason));
+    }
+
+    Ok(())
+}
+
+/// Narrows `entries[start..end]` by `--continue-cursor` and `--max-files`.
+fn select_batch(args: &Args, entries: &[LinkEntry], start: usize, end: usize) -> Result<Vec<LinkEntry>> {
+    let state_manager = StateManager::new(Path::new(&args.output[0]), args.state_format);
+    let mut first = start;
+
+    if args.continue_cursor
+        && let Some(cursor) = state_manager.load_cursor()?
+    {
+        let position = entries
+            .iter()
+            .position(|e| e.file_name == cursor.file_name)
+            .unwrap_or(cursor.index);
+        first = first.max(position + 1);
+        if !args.quiet_stdout() {
+            println!("Continuing after cursor: {} (index {})", cursor.file_name, position);
+        }
+    }
+
+    let last = match args.max_files {
+        Some(max) => end.min(first.saturating_add(max)),
+        None => end,
+    };
+
+    if first >= last {
+        if !args.quiet_stdout() {
+            println!("Nothing left to download after cursor");
+        }
+        return Ok(Vec::new());
+    }
+
+    Ok(entries[first..last].to_vec())
+}
+
+/// Moves `.cursor` past the leading entries of `selected` (in link-file order) that
+/// the run finished, so a failed or interrupted file is picked up again next time.
+/// Only written when `--continue-cursor` or `--max-files` splits the work into batches.
+fn advance_cursor(args: &Args, entries: &[LinkEntry], selected: &[LinkEntry], summary: &RunSummary) -> Result<()> {
+    if !(args.continue_cursor || args.max_files.is_some()) {
+        return Ok(());
+    }
+    let finished: HashSet<&str> = summary
+        .files
+        .iter()
+        .filter(|f| matches!(f.status, FileStatus::Success | FileStatus::Skipped))
+        .map(|f| f.file_name.as_str())
+        .collect();
+    let Some(last) = selected.iter().take_while(|e| finished.contains(e.file_name.as_str())).last() else {
+        return Ok(());
+    };
+    let Some(index) = entries.iter().position(|e| e.file_name == last.file_name) else {
+        return Ok(());
+    };
+    StateManager::new(Path::new(&args.output[0]), args.state_format).save_cursor(&BatchCursor {
+        index,
+        file_name: last.file_name.clone(),
+    })
+}
+
+/// Splits entries with the given sizes into contiguous batches of roughly `target`
+/// bytes: a batch is closed before the file that would take it past `target`, and
+/// every batch holds at least one file. Unknown sizes count as 0.
+fn size_batches(sizes: &[Option<u64>], target: u64) -> Vec<(Range<usize>, u64)> {
+    let mut batches = Vec::new();
+    let (mut start, mut bytes) = (0, 0u64);
+    for (i, size) in sizes.iter().enumerate() {
+        let size = size.unwrap_or(0);
+        if i > start && bytes + size > target {
+            batches.push((start..i, bytes));
+            (start, bytes) = (i, 0);
+        }
+        bytes += size;
+    }
+    if start < sizes.len() {
+        batches.push((start..sizes.len(), bytes));
+    }
+    batches
+}
+
+/// `--validate-json-state`: reports the state file's schema and any inconsistent
+/// entries, failing when there are some.
+fn validate_state(args: &Args) -> Result<()> {
+    let state_manager = StateManager::new(Path::new(&args.output[0]), args.state_format);
+    let Some(check) = state_manager.validate()? else {
+        println!("No state file in {}", args.output[0]);
+        return Ok(());
+    };
+    println!(
+        "{}: schema {} (current {}), {} entries",
+        check.path.display(),
+        check.schema_version,
+        STATE_SCHEMA_VERSION,
+        check.entries
+    );
+    for problem in &check.problems {
+        println!("  {}", problem);
+    }
+    if !check.problems.is_empty() {
+        return Err(anyhow!("State file has {} problems", check.problems.len()));
+    }
+    Ok(())
+}
+
+/// Prints the entries state records as incomplete, one tab-separated line each:
+/// name, URL, bytes downloaded, expected size and the last failure reason.
+fn list_failed(args: &Args, entries: &[LinkEntry]) -> Result<()> {
+    let state_manager = StateManager::new(Path::new(&args.output[0]), args.state_format);
+    let urls: HashMap<&str, &str> = entries.iter().map(|e| (e.file_name.as_str(), e.url.as_str())).collect();
+    let mut incomplete: Vec<DownloadState> = state_manager
+        .load_state()?
+        .into_iter()
+        .filter(|s| !s.completed)
+        .collect();
+    incomplete.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    for state in &incomplete {
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            state.file_name,
+            urls.get(state.file_name.as_str()).copied().unwrap_or("-"),
+            state.downloaded_bytes,
+            state.total_bytes.map_or_else(|| "-".to_string(), |t| t.to_string()),
+            state.last_error.as_deref().unwrap_or("-")
+        );
+    }
+    if !args.quiet_stdout() {
+        eprintln!("{} incomplete files", incomplete.len());
+    }
     Ok(())
 }
+
+/// Reorders `batch` to follow the file names listed one per line in `path`. Entries
+/// not listed keep their order after the listed ones, or are dropped with `--only-ordered`.
+fn apply_download_order(args: &Args, batch: Vec<LinkEntry>, path: &Path) -> Result<Vec<LinkEntry>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read order file {}", path.display()))?;
+    let mut remaining: HashMap<String, LinkEntry> = batch.iter().map(|e| (e.file_name.clone(), e.clone())).collect();
+
+    let mut ordered = Vec::with_capacity(batch.len());
+    let mut unknown = 0;
+    for name in content.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        match remaining.remove(name) {
+            Some(entry) => ordered.push(entry),
+            None => unknown += 1,
+        }
+    }
+    if unknown > 0 {
+        eprintln!(
+            "Warning: {} names in {} are not in the selected entries (or are repeated)",
+            unknown,
+            path.display()
+        );
+    }
+
+    let unlisted: Vec<LinkEntry> = batch.into_iter().filter(|e| remaining.contains_key(&e.file_name)).collect();
+    if args.only_ordered {
+        if !args.quiet_stdout() && !unlisted.is_empty() {
+            println!("Skipping {} entries not listed in {}", unlisted.len(), path.display());
+        }
+    } else {
+        ordered.extend(unlisted);
+    }
+    Ok(ordered)
+}
+
+/// Writes the selected entries to stdout for another downloader to consume.
+fn print_urls(entries: &[LinkEntry], format: UrlListFormat, output_dir: &str) {
+    for entry in entries {
+        match format {
+            UrlListFormat::Tsv => println!("{}\t{}", entry.file_name, entry.url),
+            UrlListFormat::Aria2 => {
+                // aria2 输入文件：同一行的多个 URI 视为同一文件的镜像
+                println!("{}", entry.urls().join("\t"));
+                println!("  dir={}", output_dir);
+                println!("  out={}", entry.file_name);
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of most recent outcomes per host used for the rolling success rate.
+const HEALTH_WINDOW: usize = 20;
+
+#[derive(Debug, Default)]
+struct HostStats {
+    successes: u64,
+    failures: u64,
+    latency_total: Duration,
+    latency_samples: u32,
+    recent: VecDeque<bool>,
+}
+
+impl HostStats {
+    fn success_rate(&self) -> f64 {
+        if self.recent.is_empty() {
+            // Untried hosts rank as healthy so every mirror gets a chance
+            return 1.0;
+        }
+        self.recent.iter().filter(|ok| **ok).count() as f64 / self.recent.len() as f64
+    }
+
+    fn avg_latency(&self) -> Option<Duration> {
+        (self.latency_samples > 0).then(|| self.latency_total / self.latency_samples)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorReport {
+    pub host: String,
+    pub successes: u64,
+    pub failures: u64,
+    pub recent_success_rate: f64,
+    pub avg_latency_ms: Option<u64>,
+}
+
+/// Rolling per-host success and latency stats shared by all workers in a run.
+#[derive(Debug, Default)]
+pub struct MirrorHealth {
+    hosts: Mutex<HashMap<String, HostStats>>,
+}
+
+/// Host used to group stats; host-less URLs such as `file://` group by scheme.
+pub fn host_of(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(u) => u
+            .host_str()
+            .map(|h| h.to_string())
+            .unwrap_or_else(|| format!("{}://", u.scheme())),
+        Err(_) => url.to_string(),
+    }
+}
+
+impl MirrorHealth {
+    pub fn record_outcome(&self, url: &str, ok: bool) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let stats = hosts.entry(host_of(url)).or_default();
+        if ok {
+            stats.successes += 1;
+        } else {
+            stats.failures += 1;
+        }
+        stats.recent.push_back(ok);
+        if stats.recent.len() > HEALTH_WINDOW {
+            stats.recent.pop_front();
+        }
+    }
+
+    pub fn record_latency(&self, url: &str, latency: Duration) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let stats = hosts.entry(host_of(url)).or_default();
+        stats.latency_total += latency;
+        stats.latency_samples += 1;
+    }
+
+    /// Orders candidate URLs from healthiest to least healthy: higher rolling success
+    /// rate first, then lower average latency. The original order breaks ties.
+    pub fn rank<'a>(&self, urls: &[&'a str]) -> Vec<&'a str> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut ranked: Vec<(usize, f64, Duration, &str)> = urls
+            .iter()
+            .enumerate()
+            .map(|(idx, url)| {
+                let stats = hosts.get(&host_of(url));
+                let rate = stats.map_or(1.0, |s| s.success_rate());
+                let latency = stats.and_then(|s| s.avg_latency()).unwrap_or_default();
+                (idx, rate, latency, *url)
+            })
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then(a.2.cmp(&b.2))
+                .then(a.0.cmp(&b.0))
+        });
+        ranked.into_iter().map(|(_, _, _, url)| url).collect()
+    }
+
+    pub fn report(&self) -> Vec<MirrorReport> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut report: Vec<MirrorReport> = hosts
+            .iter()
+            .map(|(host, stats)| MirrorReport {
+                host: host.clone(),
+                successes: stats.successes,
+                failures: stats.failures,
+                recent_success_rate: stats.success_rate(),
+                avg_latency_ms: stats.avg_latency().map(|l| l.as_millis() as u64),
+            })
+            .collect();
+        report.sort_by(|a, b| a.host.cmp(&b.host));
+        report
+    }
+}
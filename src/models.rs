@@ -1,9 +1,79 @@
+use crate::blockcrc::BlockChecksums;
+use crate::downloader::MismatchPolicy;
+use crate::mirrors::MirrorReport;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LinkEntry {
     pub file_name: String,
     pub url: String,
+    /// Expected hex digest, when known, in the algorithm chosen by `--hash-algo`.
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// Alternative URLs for the same file, tried when the primary host is unhealthy.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    /// Per-block CRC32C values checked while streaming.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_crcs: Option<BlockChecksums>,
+    /// Size in bytes as given by the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Extra manifest fields (e.g. `tags`) passed through untouched to the report.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl LinkEntry {
+    /// The primary URL followed by any mirrors.
+    pub fn urls(&self) -> Vec<&str> {
+        std::iter::once(self.url.as_str())
+            .chain(self.mirrors.iter().map(|m| m.as_str()))
+            .collect()
+    }
+}
+
+/// One line of a JSON-lines manifest. Unknown fields are kept as metadata.
+#[derive(Debug, Deserialize)]
+pub struct ManifestLine {
+    pub file_name: String,
+    pub url: String,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl From<ManifestLine> for LinkEntry {
+    fn from(line: ManifestLine) -> Self {
+        LinkEntry {
+            file_name: line.file_name,
+            url: line.url,
+            checksum: line.sha256.map(|h| h.to_ascii_lowercase()),
+            mirrors: line.mirrors,
+            block_crcs: None,
+            size: line.size,
+            metadata: (!line.extra.is_empty()).then_some(serde_json::Value::Object(line.extra)),
+        }
+    }
+}
+
+/// One completed file as written by `--output-manifest`. The JSON form can be read
+/// back as a JSON-lines link file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestRecord {
+    pub file_name: String,
+    pub url: String,
+    pub path: PathBuf,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,6 +82,38 @@ pub struct DownloadState {
     pub downloaded_bytes: u64,
     pub total_bytes: Option<u64>,
     pub completed: bool,
+    /// Consecutive runs in which this file hit `--max-runtime-per-file`.
+    #[serde(default)]
+    pub timeouts: u32,
+    /// Output directory this file was assigned to when striping across several.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<PathBuf>,
+    /// Location of the `.part` file when it is staged outside the output directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial_path: Option<PathBuf>,
+    /// Server `Last-Modified` of the copy being downloaded, used by `--since now`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<DateTime<Utc>>,
+    /// SHA-256 of the completed file, recorded when it was verified or first scrubbed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// When `sha256` was last confirmed against the file on disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hashed_at: Option<DateTime<Utc>>,
+    /// Why the last attempt at this file failed, shown by `--list-failed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// When the file last passed a verify or scrub, for `--verify-only-new`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified_at: Option<DateTime<Utc>>,
+    /// Name the file was saved under when `--honor-content-disposition` replaced the
+    /// last component of `file_name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saved_as: Option<String>,
+    /// Set for a `--manifest-url` download, which is complete when it parses as a link
+    /// file rather than by the size heuristic used for shards. Never persisted.
+    #[serde(skip)]
+    pub is_manifest: bool,
 }
 
 impl DownloadState {
@@ -21,6 +123,74 @@ impl DownloadState {
             downloaded_bytes: 0,
             total_bytes: None,
             completed: false,
+            timeouts: 0,
+            destination: None,
+            partial_path: None,
+            last_modified: None,
+            sha256: None,
+            hashed_at: None,
+            last_error: None,
+            verified_at: None,
+            saved_as: None,
+            is_manifest: false,
         }
     }
 }
+
+/// Position in the sorted manifest reached by the last run, stored in `.cursor`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchCursor {
+    pub index: usize,
+    pub file_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Success,
+    Failed,
+    Deferred,
+    Cancelled,
+    /// Not modified since the `--since` cutoff.
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileOutcome {
+    pub file_name: String,
+    pub status: FileStatus,
+    pub bytes: u64,
+    pub elapsed_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Policy applied when the file ended on a checksum mismatch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum_mismatch: Option<MismatchPolicy>,
+    /// Manifest metadata carried through from a JSON-lines entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// Final result of a batch, printed by `--quiet-summary-json`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    pub success: usize,
+    pub failed: usize,
+    pub deferred: usize,
+    pub cancelled: usize,
+    pub skipped: usize,
+    pub not_started: usize,
+    pub total_bytes: u64,
+    pub elapsed_secs: f64,
+    pub files: Vec<FileOutcome>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<MirrorReport>,
+    /// Set when the run was cut short by the `--abort-after` circuit breaker.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abort_reason: Option<String>,
+    /// Bytes not downloaded because `--dedup` linked an identical file instead.
+    pub dedup_saved_bytes: u64,
+    /// Bad files moved to `--quarantine-dir` instead of being deleted.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quarantined: Vec<PathBuf>,
+}
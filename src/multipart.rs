@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use reqwest::Response;
+
+/// Extracts the boundary from a `multipart/byteranges; boundary=...` content type.
+pub fn byteranges_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+/// Streams the payload of a single-part `multipart/byteranges` response, dropping
+/// the part headers and the closing boundary that some servers wrap around a
+/// plain `Range: bytes=N-` answer.
+pub struct MultipartBody {
+    response: Response,
+    expected_start: u64,
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    in_headers: bool,
+    done: bool,
+}
+
+impl MultipartBody {
+    pub fn new(response: Response, boundary: &str, expected_start: u64) -> Self {
+        Self {
+            response,
+            expected_start,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            buffer: Vec::new(),
+            in_headers: true,
+            done: false,
+        }
+    }
+
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+
+            let Some(incoming) = self.response.chunk().await? else {
+                return Err(anyhow!("Multipart response ended before its closing boundary"));
+            };
+            self.buffer.extend_from_slice(&incoming);
+
+            if self.in_headers {
+                let Some(end) = find(&self.buffer, b"\r\n\r\n") else {
+                    continue;
+                };
+                let headers = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+                self.check_part_range(&headers)?;
+                self.buffer.drain(..end + 4);
+                self.in_headers = false;
+            }
+
+            // 保留可能是分隔符开头的尾部字节，等下一块数据再判断
+            let emit = match find(&self.buffer, &self.delimiter) {
+                // 分隔符后是 "--" 才是结束标记，否则后面还有别的分段
+                Some(at) if self.buffer.len() >= at + self.delimiter.len() + 2 => {
+                    let after = at + self.delimiter.len();
+                    if &self.buffer[after..after + 2] != b"--" {
+                        return Err(anyhow!("Multipart response has more than one part"));
+                    }
+                    self.done = true;
+                    at
+                }
+                Some(at) => at,
+                None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if emit > 0 {
+                let data: Vec<u8> = self.buffer.drain(..emit).collect();
+                return Ok(Some(Bytes::from(data)));
+            }
+        }
+    }
+
+    /// Rejects a part whose `Content-Range` doesn't start where we asked.
+    fn check_part_range(&self, headers: &str) -> Result<()> {
+        let range = headers.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("content-range").then(|| value.trim().to_string())
+        });
+        let Some(range) = range else {
+            return Ok(());
+        };
+        let start = range
+            .strip_prefix("bytes ")
+            .and_then(|r| r.split('-').next())
+            .and_then(|s| s.parse::<u64>().ok());
+        if start != Some(self.expected_start) {
+            return Err(anyhow!(
+                "Multipart part covers '{}' but the resume offset is {}",
+                range,
+                self.expected_start
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response whose body arrives in exactly these chunks.
+    fn response(chunks: &[&[u8]]) -> Response {
+        let (mut tx, body) = hyper::Body::channel();
+        let chunks: Vec<Bytes> = chunks.iter().map(|c| Bytes::copy_from_slice(c)).collect();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                if tx.send_data(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        hyper::Response::new(body).into()
+    }
+
+    async fn read_all(chunks: &[&[u8]], expected_start: u64) -> Result<Vec<u8>> {
+        let mut body = MultipartBody::new(response(chunks), "XYZ", expected_start);
+        let mut out = Vec::new();
+        while let Some(chunk) = body.chunk().await? {
+            out.extend_from_slice(&chunk);
+        }
+        Ok(out)
+    }
+
+    const PART: &[u8] = b"--XYZ\r\nContent-Type: application/x-tar\r\nContent-Range: bytes 100-109/110\r\n\r\n0123456789\r\n--XYZ--\r\n";
+
+    #[test]
+    fn boundary_comes_from_the_content_type() {
+        assert_eq!(byteranges_boundary("multipart/byteranges; boundary=XYZ").as_deref(), Some("XYZ"));
+        assert_eq!(byteranges_boundary("Multipart/ByteRanges; Boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(byteranges_boundary("multipart/form-data; boundary=XYZ"), None);
+        assert_eq!(byteranges_boundary("multipart/byteranges"), None);
+    }
+
+    #[tokio::test]
+    async fn single_part_in_one_chunk() {
+        assert_eq!(read_all(&[PART], 100).await.unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn every_split_point_yields_the_same_payload() {
+        for split in 1..PART.len() {
+            let (head, tail) = PART.split_at(split);
+            assert_eq!(read_all(&[head, tail], 100).await.unwrap(), b"0123456789", "split at {}", split);
+        }
+    }
+
+    #[tokio::test]
+    async fn byte_at_a_time() {
+        let chunks: Vec<&[u8]> = PART.chunks(1).collect();
+        assert_eq!(read_all(&chunks, 100).await.unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn preamble_before_the_first_boundary_is_skipped() {
+        let body = [b"This is a multipart preamble.\r\n".as_slice(), PART].concat();
+        assert_eq!(read_all(&[&body], 100).await.unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn missing_closing_boundary_is_an_error() {
+        let truncated = &PART[..PART.len() - b"\r\n--XYZ--\r\n".len()];
+        let error = read_all(&[truncated], 100).await.unwrap_err();
+        assert!(error.to_string().contains("closing boundary"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn part_starting_elsewhere_is_rejected() {
+        let error = read_all(&[PART], 0).await.unwrap_err();
+        assert!(error.to_string().contains("resume offset is 0"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn response_with_several_parts_is_rejected() {
+        let body = b"--XYZ\r\nContent-Range: bytes 100-104/110\r\n\r\n01234\r\n--XYZ\r\nContent-Range: bytes 105-109/110\r\n\r\n56789\r\n--XYZ--\r\n";
+        for split in 1..body.len() {
+            let (head, tail) = body.split_at(split);
+            let error = read_all(&[head, tail], 100).await.unwrap_err();
+            assert!(error.to_string().contains("more than one part"), "split at {}: {}", split, error);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::models::{DownloadState, LinkEntry};
+use anyhow::{Context, Result};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{self, JoinHandle};
+
+/// Point-in-time view of a batch written by `--progress-file`.
+#[derive(Debug, Serialize)]
+pub struct ProgressSnapshot {
+    pub updated_at: String,
+    pub total_files: usize,
+    pub completed: usize,
+    pub failed: usize,
+    pub bytes_downloaded: u64,
+    pub active: Vec<FileProgress>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileProgress {
+    pub file_name: String,
+    pub downloaded_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes: Option<u64>,
+}
+
+/// `--show-eta-overall`: how far back the aggregate throughput is averaged.
+const ETA_WINDOW: Duration = Duration::from_secs(30);
+
+/// How often the overall ETA is refreshed on the progress bar.
+const ETA_REFRESH: Duration = Duration::from_secs(1);
+
+/// How often the overall ETA is also printed as a log line.
+const ETA_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Finished {
+    completed: usize,
+    failed: usize,
+    bytes: u64,
+    names: HashSet<String>,
+}
+
+/// Tracks in-flight and finished files so a snapshot can be taken at any time.
+pub struct ProgressTracker {
+    total_files: usize,
+    finished: Mutex<Finished>,
+    active: Mutex<HashMap<String, Arc<Mutex<DownloadState>>>>,
+    /// Size of every entry in the batch, where the manifest lists it.
+    sizes: HashMap<String, Option<u64>>,
+    /// Bytes already on disk when files started (resumed or complete), so they are
+    /// not counted as throughput.
+    preexisting: AtomicU64,
+}
+
+/// Time left for the whole batch, from the remaining bytes and the recent rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverallEta {
+    pub remaining_bytes: u64,
+    /// Entries whose size is not known yet; their bytes are missing from
+    /// `remaining_bytes`, so the ETA is only a lower bound.
+    pub unknown_sizes: usize,
+    pub bytes_per_sec: f64,
+}
+
+impl OverallEta {
+    pub fn eta(&self) -> Option<Duration> {
+        (self.bytes_per_sec >= 1.0).then(|| Duration::from_secs_f64(self.remaining_bytes as f64 / self.bytes_per_sec))
+    }
+}
+
+impl fmt::Display for OverallEta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bound = if self.unknown_sizes > 0 { ">= " } else { "" };
+        match self.eta() {
+            Some(eta) => write!(f, "ETA {}{}", bound, HumanDuration(eta))?,
+            None => write!(f, "ETA unknown")?,
+        }
+        write!(
+            f,
+            " ({}{} left at {}/s",
+            bound,
+            HumanBytes(self.remaining_bytes),
+            HumanBytes(self.bytes_per_sec as u64)
+        )?;
+        if self.unknown_sizes > 0 {
+            write!(f, ", {} sizes unknown", self.unknown_sizes)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl ProgressTracker {
+    pub fn new(total_files: usize) -> Self {
+        Self {
+            total_files,
+            finished: Mutex::default(),
+            active: Mutex::default(),
+            sizes: HashMap::new(),
+            preexisting: AtomicU64::new(0),
+        }
+    }
+
+    /// A tracker for `entries` that also knows their manifest sizes, for the overall ETA.
+    pub fn for_entries(entries: &[LinkEntry]) -> Self {
+        Self {
+            sizes: entries.iter().map(|e| (e.file_name.clone(), e.size)).collect(),
+            ..Self::new(entries.len())
+        }
+    }
+
+    pub fn start(&self, state: &Arc<Mutex<DownloadState>>) {
+        let (file_name, downloaded) = {
+            let state = state.lock().unwrap();
+            (state.file_name.clone(), state.downloaded_bytes)
+        };
+        self.preexisting.fetch_add(downloaded, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(file_name, Arc::clone(state));
+    }
+
+    pub fn finish(&self, file_name: &str, ok: bool, bytes: u64) {
+        self.active.lock().unwrap().remove(file_name);
+        let mut finished = self.finished.lock().unwrap();
+        finished.names.insert(file_name.to_string());
+        if ok {
+            finished.completed += 1;
+            finished.bytes += bytes;
+        } else {
+            finished.failed += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let mut active: Vec<FileProgress> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|state| {
+                let state = state.lock().unwrap();
+                FileProgress {
+                    file_name: state.file_name.clone(),
+                    downloaded_bytes: state.downloaded_bytes,
+                    total_bytes: state.total_bytes,
+                }
+            })
+            .collect();
+        active.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+        let finished = self.finished.lock().unwrap();
+        ProgressSnapshot {
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            total_files: self.total_files,
+            completed: finished.completed,
+            failed: finished.failed,
+            bytes_downloaded: finished.bytes + active.iter().map(|f| f.downloaded_bytes).sum::<u64>(),
+            active,
+        }
+    }
+
+    /// Rewrites `path` with a fresh snapshot every `interval` until the handle is aborted.
+    pub fn spawn_writer(self: &Arc<Self>, path: PathBuf, interval: Duration) -> JoinHandle<()> {
+        let tracker = Arc::clone(self);
+        task::spawn(async move {
+            loop {
+                if let Err(e) = write_snapshot(&path, &tracker.snapshot()) {
+                    eprintln!("Warning: failed to write progress file: {:#}", e);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// Prints a one-line throughput heartbeat to stderr every `interval` until the handle
+    /// is aborted. `expected_bytes` is the batch total when the manifest lists every size;
+    /// otherwise the remainder is extrapolated from the files finished so far.
+    pub fn spawn_reporter(self: &Arc<Self>, interval: Duration, expected_bytes: Option<u64>) -> JoinHandle<()> {
+        let tracker = Arc::clone(self);
+        task::spawn(async move {
+            let mut last = (Instant::now(), tracker.snapshot().bytes_downloaded);
+            loop {
+                tokio::time::sleep(interval).await;
+                let snapshot = tracker.snapshot();
+                let elapsed = last.0.elapsed().as_secs_f64();
+                // 续传文件开始时已有字节会一次性计入，差值可能偏大但不会为负
+                let rate = snapshot.bytes_downloaded.saturating_sub(last.1) as f64 / elapsed;
+                last = (Instant::now(), snapshot.bytes_downloaded);
+
+                let remaining = tracker.remaining_bytes(&snapshot, expected_bytes);
+                let eta = match remaining {
+                    Some(bytes) if rate >= 1.0 => HumanDuration(Duration::from_secs_f64(bytes as f64 / rate)).to_string(),
+                    _ => "unknown".to_string(),
+                };
+                eprintln!(
+                    "[{}] {}/s, {}/{} files done, {} in flight, {} remaining, ETA {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    HumanBytes(rate as u64),
+                    snapshot.completed + snapshot.failed,
+                    snapshot.total_files,
+                    snapshot.active.len(),
+                    remaining.map_or_else(|| "unknown".to_string(), |b| HumanBytes(b).to_string()),
+                    eta
+                );
+            }
+        })
+    }
+
+    /// Refreshes the overall ETA on `overall` every second, and prints it as a log line
+    /// every minute, until the handle is aborted.
+    pub fn spawn_eta(self: &Arc<Self>, overall: ProgressBar) -> JoinHandle<()> {
+        let tracker = Arc::clone(self);
+        task::spawn(async move {
+            let mut samples: VecDeque<(Instant, u64)> = VecDeque::new();
+            let mut last_log = Instant::now();
+            loop {
+                let now = Instant::now();
+                samples.push_back((now, tracker.fetched_bytes()));
+                while samples.len() > 2 && now.duration_since(samples[0].0) > ETA_WINDOW {
+                    samples.pop_front();
+                }
+                let (first, last) = (samples[0], samples[samples.len() - 1]);
+                let elapsed = last.0.duration_since(first.0).as_secs_f64();
+                // 滚动窗口内的平均速率，窗口不足一个采样间隔时还没有速率
+                let bytes_per_sec = if elapsed > 0.0 {
+                    last.1.saturating_sub(first.1) as f64 / elapsed
+                } else {
+                    0.0
+                };
+                let eta = tracker.overall_eta(bytes_per_sec);
+                overall.set_message(eta.to_string());
+                if last_log.elapsed() >= ETA_LOG_INTERVAL {
+                    last_log = Instant::now();
+                    overall.println(format!(
+                        "[{}] Overall {}",
+                        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        eta
+                    ));
+                }
+                tokio::time::sleep(ETA_REFRESH).await;
+            }
+        })
+    }
+
+    /// Bytes transferred in this run, excluding what was already on disk.
+    pub fn fetched_bytes(&self) -> u64 {
+        self.snapshot()
+            .bytes_downloaded
+            .saturating_sub(self.preexisting.load(Ordering::Relaxed))
+    }
+
+    /// Remaining bytes across every unfinished entry: the manifest size, or the size
+    /// learned when the download started, less what is already on disk.
+    pub fn overall_eta(&self, bytes_per_sec: f64) -> OverallEta {
+        let active: HashMap<String, (u64, Option<u64>)> = self
+            .active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, state)| {
+                let state = state.lock().unwrap();
+                (name.clone(), (state.downloaded_bytes, state.total_bytes))
+            })
+            .collect();
+        let finished = self.finished.lock().unwrap();
+
+        let mut remaining_bytes = 0;
+        let mut unknown_sizes = 0;
+        for (name, size) in &self.sizes {
+            if finished.names.contains(name) {
+                continue;
+            }
+            let (downloaded, total) = active.get(name).copied().unwrap_or_default();
+            match size.or(total) {
+                Some(size) => remaining_bytes += size.saturating_sub(downloaded),
+                None => unknown_sizes += 1,
+            }
+        }
+        OverallEta {
+            remaining_bytes,
+            unknown_sizes,
+            bytes_per_sec,
+        }
+    }
+
+    fn remaining_bytes(&self, snapshot: &ProgressSnapshot, expected_bytes: Option<u64>) -> Option<u64> {
+        if let Some(expected) = expected_bytes {
+            return Some(expected.saturating_sub(snapshot.bytes_downloaded));
+        }
+        let finished = self.finished.lock().unwrap();
+        if finished.completed == 0 {
+            return None;
+        }
+        // 清单没有大小时按已完成文件的平均大小估算
+        let average = finished.bytes / finished.completed as u64;
+        let in_flight: u64 = snapshot
+            .active
+            .iter()
+            .map(|f| f.total_bytes.unwrap_or(average).saturating_sub(f.downloaded_bytes))
+            .sum();
+        let pending = self.total_files.saturating_sub(finished.completed + finished.failed + snapshot.active.len());
+        Some(in_flight + average * pending as u64)
+    }
+}
+
+/// Writes to a temporary sibling and renames it over `path`, so readers never see
+/// a half-written file.
+pub fn write_snapshot(path: &Path, snapshot: &ProgressSnapshot) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let content = serde_json::to_vec_pretty(snapshot).context("Failed to serialize progress")?;
+    fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
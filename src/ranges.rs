@@ -0,0 +1,83 @@
+//! `--probe-ranges`: whether each host honours byte ranges, learned from one
+//! `Range: bytes=0-0` request per host and reused for every file it serves.
+
+use anyhow::Result;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// What a host answered to the `bytes=0-0` probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeCapability {
+    /// The probe came back as 206 Partial Content.
+    pub partial_content: bool,
+    /// The response advertised `Accept-Ranges: bytes`.
+    pub accept_ranges: bool,
+}
+
+impl RangeCapability {
+    /// Resuming (and splitting a file into ranges) is only safe when the server
+    /// actually answered the range; `Accept-Ranges` alone is just a hint.
+    pub fn supports_ranges(&self) -> bool {
+        self.partial_content
+    }
+}
+
+impl fmt::Display for RangeCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.supports_ranges() { "ranges supported" } else { "no range support" };
+        write!(
+            f,
+            "{} (206: {}, Accept-Ranges: {})",
+            verdict,
+            if self.partial_content { "yes" } else { "no" },
+            if self.accept_ranges { "bytes" } else { "none" }
+        )
+    }
+}
+
+/// Probe results keyed by `host:port`. Concurrent lookups for the same host wait
+/// for a single probe; failed probes are not cached, so the next lookup retries.
+#[derive(Debug, Default)]
+pub struct RangeCache {
+    hosts: Mutex<HashMap<String, Arc<OnceCell<RangeCapability>>>>,
+}
+
+impl RangeCache {
+    pub async fn get_or_probe<F, Fut>(&self, url: &str, probe: F) -> Result<Option<RangeCapability>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<RangeCapability>>,
+    {
+        let Some(host) = host_key(url) else {
+            return Ok(None);
+        };
+        let cell = Arc::clone(self.hosts.lock().unwrap().entry(host).or_default());
+        cell.get_or_try_init(probe).await.map(|capability| Some(*capability))
+    }
+
+    /// Every host probed so far, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, RangeCapability)> {
+        let mut hosts: Vec<(String, RangeCapability)> = self
+            .hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(host, cell)| cell.get().map(|capability| (host.clone(), *capability)))
+            .collect();
+        hosts.sort_by(|a, b| a.0.cmp(&b.0));
+        hosts
+    }
+}
+
+/// `host:port` of an HTTP(S) URL; other schemes (local mirrors) have no host to probe.
+pub fn host_key(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?))
+}
@@ -0,0 +1,145 @@
+use crate::models::{FileStatus, ManifestRecord, RunSummary};
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+fn status_label(status: FileStatus) -> &'static str {
+    match status {
+        FileStatus::Success => "ok",
+        FileStatus::Failed => "FAILED",
+        FileStatus::Deferred => "deferred",
+        FileStatus::Cancelled => "cancelled",
+        FileStatus::Skipped => "skipped",
+    }
+}
+
+/// Prints one aligned row per file with status, size, time and average speed.
+pub fn print_table(summary: &RunSummary) {
+    let rows: Vec<[String; 5]> = summary
+        .files
+        .iter()
+        .map(|file| {
+            let speed = if file.status == FileStatus::Success && file.elapsed_secs > 0.0 {
+                format!("{}/s", HumanBytes((file.bytes as f64 / file.elapsed_secs) as u64))
+            } else {
+                "-".to_string()
+            };
+            [
+                file.file_name.clone(),
+                status_label(file.status).to_string(),
+                HumanBytes(file.bytes).to_string(),
+                HumanDuration(Duration::from_secs_f64(file.elapsed_secs)).to_string(),
+                speed,
+            ]
+        })
+        .collect();
+
+    let header = ["FILE", "STATUS", "SIZE", "TIME", "SPEED"];
+    let mut widths = header.map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let format_row = |cells: [&str; 5]| {
+        format!(
+            "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}  {:>w4$}",
+            cells[0],
+            cells[1],
+            cells[2],
+            cells[3],
+            cells[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+            w4 = widths[4],
+        )
+    };
+
+    println!("{}", format_row(header));
+    for row in &rows {
+        println!("{}", format_row([&row[0], &row[1], &row[2], &row[3], &row[4]]));
+    }
+    for file in &summary.files {
+        if let Some(error) = &file.error {
+            println!("  {}: {}", file.file_name, error);
+        }
+    }
+}
+
+/// Writes completed files as a reusable manifest: JSON lines for `.json`/`.jsonl`
+/// paths, otherwise TSV in the link-file layout with path and hash columns appended.
+pub fn write_output_manifest(path: &Path, records: &[ManifestRecord]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    let json = matches!(path.extension().and_then(|e| e.to_str()), Some("json" | "jsonl"));
+
+    if json {
+        for record in records {
+            serde_json::to_writer(&mut out, record)?;
+            writeln!(out)?;
+        }
+    } else {
+        writeln!(out, "file_name\turl\tsize\tpath\tsha256")?;
+        for record in records {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                record.file_name,
+                record.url,
+                record.size,
+                record.path.display(),
+                record.sha256.as_deref().unwrap_or_default()
+            )?;
+        }
+    }
+
+    out.flush().context("Failed to write output manifest")
+}
+
+/// One row of the `--summary-csv` run history.
+#[derive(Serialize)]
+struct RunRow {
+    timestamp: String,
+    attempted: usize,
+    succeeded: usize,
+    failed: usize,
+    bytes: u64,
+    elapsed_secs: f64,
+    avg_bytes_per_sec: u64,
+}
+
+/// Appends one row describing this run to the CSV at `path`, writing the header
+/// first when the file is new or empty.
+pub fn append_summary_csv(path: &Path, summary: &RunSummary) -> Result<()> {
+    let needs_header = fs::metadata(path).map_or(true, |m| m.len() == 0);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut writer = csv::WriterBuilder::new().has_headers(needs_header).from_writer(file);
+
+    let avg_bytes_per_sec = if summary.elapsed_secs > 0.0 {
+        (summary.total_bytes as f64 / summary.elapsed_secs) as u64
+    } else {
+        0
+    };
+    writer.serialize(RunRow {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        attempted: summary.files.len(),
+        succeeded: summary.success,
+        failed: summary.failed,
+        bytes: summary.total_bytes,
+        elapsed_secs: (summary.elapsed_secs * 1000.0).round() / 1000.0,
+        avg_bytes_per_sec,
+    })?;
+    writer.flush().context("Failed to write summary CSV")
+}
@@ -0,0 +1,102 @@
+use crate::units::parse_bytes;
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveTime, Timelike};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A daily wall-clock window such as `01:00-07:00`. Windows whose end is earlier
+/// than their start (e.g. `22:00-06:00`) wrap past midnight.
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Schedule {
+    pub fn is_open_at(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(Local::now().time())
+    }
+
+    /// Time remaining until the window next opens (zero if it is open now).
+    pub fn until_open(&self) -> Duration {
+        let now = Local::now().time();
+        if self.is_open_at(now) {
+            return Duration::ZERO;
+        }
+        let now_secs = now.num_seconds_from_midnight() as i64;
+        let start_secs = self.start.num_seconds_from_midnight() as i64;
+        let wait = (start_secs - now_secs).rem_euclid(24 * 3600);
+        Duration::from_secs(wait as u64)
+    }
+
+    pub fn start(&self) -> NaiveTime {
+        self.start
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid schedule '{}', expected HH:MM-HH:MM", s))?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M")
+            .with_context(|| format!("Invalid schedule start time: {}", start))?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M")
+            .with_context(|| format!("Invalid schedule end time: {}", end))?;
+        if start == end {
+            return Err(anyhow!("Schedule window '{}' is empty", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Bandwidth caps that vary by time of day, e.g. `09:00-18:00=10MB,18:00-09:00=100MB`.
+#[derive(Debug, Clone)]
+pub struct BandwidthSchedule {
+    windows: Vec<(Schedule, u64)>,
+}
+
+impl BandwidthSchedule {
+    /// The cap in bytes per second for the first window containing `time`.
+    pub fn rate_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|(window, _)| window.is_open_at(time))
+            .map(|(_, rate)| *rate)
+    }
+
+    pub fn rate_now(&self) -> Option<u64> {
+        self.rate_at(Local::now().time())
+    }
+}
+
+impl FromStr for BandwidthSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let windows = s
+            .split(',')
+            .map(|part| {
+                let (window, rate) = part
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid bandwidth window '{}', expected HH:MM-HH:MM=RATE", part))?;
+                let rate = parse_bytes(rate)?;
+                if rate == 0 {
+                    return Err(anyhow!("Bandwidth limit in '{}' must be greater than zero", part));
+                }
+                Ok((window.parse()?, rate))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { windows })
+    }
+}
@@ -0,0 +1,168 @@
+//! `--segments`: one file fetched as several byte ranges over parallel connections.
+//! The segment map sits next to the `.part` file so each range resumes on its own.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Files are not split into segments smaller than this; the extra requests and
+/// connections would cost more than they gain.
+pub const MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Byte range `start..end` of the file, of which the first `done` bytes are on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub done: u64,
+}
+
+impl Segment {
+    /// Next byte to fetch.
+    pub fn position(&self) -> u64 {
+        self.start + self.done
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.position() >= self.end
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentMap {
+    pub total_bytes: u64,
+    pub segments: Vec<Segment>,
+}
+
+impl SegmentMap {
+    /// Splits `total_bytes` into at most `count` equal ranges of at least
+    /// [`MIN_SEGMENT_SIZE`]; the last range takes the remainder.
+    pub fn split(total_bytes: u64, count: usize) -> Self {
+        Self::split_from(total_bytes, count, 0)
+    }
+
+    /// Like [`split`](Self::split), but the first `offset` bytes are already on disk
+    /// (e.g. from a single-connection run): they become one finished segment and
+    /// only the rest of the file is split.
+    pub fn split_from(total_bytes: u64, count: usize, offset: u64) -> Self {
+        let offset = offset.min(total_bytes);
+        let remaining = total_bytes - offset;
+        let count = (count as u64).min(remaining / MIN_SEGMENT_SIZE).max(1);
+        let size = remaining / count;
+        let mut segments: Vec<Segment> = (offset > 0)
+            .then_some(Segment {
+                start: 0,
+                end: offset,
+                done: offset,
+            })
+            .into_iter()
+            .collect();
+        if remaining > 0 {
+            segments.extend((0..count).map(|i| Segment {
+                start: offset + i * size,
+                end: if i + 1 == count { total_bytes } else { offset + (i + 1) * size },
+                done: 0,
+            }));
+        }
+        Self { total_bytes, segments }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.segments.iter().map(|s| s.done.min(s.end - s.start)).sum()
+    }
+
+    /// Reads the map saved by an earlier run; `None` when there is none or it is unreadable.
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Writes to a temporary sibling and renames it over `path`, so an interrupted
+    /// save never leaves a map claiming bytes that are not on disk.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("segments.tmp");
+        let content = serde_json::to_vec(self).context("Failed to serialize segment map")?;
+        fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+}
+
+/// `<file>.part.segments`, next to the partial file it describes.
+pub fn map_path(partial_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.segments", partial_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn split_covers_the_file_with_the_remainder_in_the_last_segment() {
+        let map = SegmentMap::split(30 * MIB + 2, 3);
+        let bounds: Vec<(u64, u64)> = map.segments.iter().map(|s| (s.start, s.end)).collect();
+        assert_eq!(bounds, [(0, 10 * MIB), (10 * MIB, 20 * MIB), (20 * MIB, 30 * MIB + 2)]);
+        assert_eq!(map.downloaded(), 0);
+    }
+
+    #[test]
+    fn split_never_goes_below_the_minimum_segment_size() {
+        assert_eq!(SegmentMap::split(20 * MIB, 16).segments.len(), 2);
+        assert_eq!(SegmentMap::split(MIB, 4).segments.len(), 1);
+    }
+
+    #[test]
+    fn split_from_keeps_the_existing_prefix_as_a_finished_segment() {
+        let map = SegmentMap::split_from(20 * MIB, 4, 4 * MIB);
+        assert_eq!(
+            map.segments,
+            [
+                Segment { start: 0, end: 4 * MIB, done: 4 * MIB },
+                Segment { start: 4 * MIB, end: 12 * MIB, done: 0 },
+                Segment { start: 12 * MIB, end: 20 * MIB, done: 0 },
+            ]
+        );
+        assert_eq!(map.downloaded(), 4 * MIB);
+    }
+
+    #[test]
+    fn split_from_a_complete_prefix_leaves_nothing_to_fetch() {
+        let map = SegmentMap::split_from(20 * MIB, 2, 25 * MIB);
+        assert!(map.segments.iter().all(Segment::is_complete));
+        assert_eq!(map.downloaded(), 20 * MIB);
+    }
+
+    #[test]
+    fn downloaded_ignores_bytes_past_a_segment_end() {
+        let map = SegmentMap {
+            total_bytes: 20,
+            segments: vec![Segment { start: 0, end: 10, done: 15 }, Segment { start: 10, end: 20, done: 3 }],
+        };
+        assert_eq!(map.downloaded(), 13);
+        assert_eq!(map.segments[1].position(), 13);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = map_path(&dir.path().join("sa_0.tar.part"));
+        let mut map = SegmentMap::split(20 * MIB, 2);
+        map.segments[1].done = 1000;
+
+        map.save(&path).unwrap();
+
+        assert_eq!(SegmentMap::load(&path), Some(map));
+        assert!(!path.with_extension("segments.tmp").exists());
+    }
+
+    #[test]
+    fn unreadable_map_loads_as_none() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sa_0.tar.part.segments");
+        assert_eq!(SegmentMap::load(&path), None);
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(SegmentMap::load(&path), None);
+    }
+}
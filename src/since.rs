@@ -0,0 +1,43 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use std::str::FromStr;
+
+/// Cutoff for `--since`: a fixed date, or `now`, meaning "newer than the
+/// `Last-Modified` recorded in state for the copy we already have".
+#[derive(Debug, Clone, Copy)]
+pub enum Since {
+    Date(DateTime<Utc>),
+    Recorded,
+}
+
+impl FromStr for Since {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("now") {
+            return Ok(Since::Recorded);
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Since::Date(time.with_timezone(&Utc)));
+        }
+        let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map_err(|_| anyhow!("Invalid --since '{}', expected YYYY-MM-DD, RFC 3339 or 'now'", s))?;
+        Ok(Since::Date(date.and_hms_opt(0, 0, 0).unwrap().and_utc()))
+    }
+}
+
+/// What `--since` does with files whose server sends no `Last-Modified`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MissingLastModified {
+    #[default]
+    Download,
+    Skip,
+}
+
+/// Parses an HTTP-date such as `Wed, 21 Oct 2015 07:28:00 GMT`.
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
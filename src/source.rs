@@ -0,0 +1,112 @@
+use crate::multipart::{self, MultipartBody};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Response, StatusCode, Url};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const LOCAL_READ_SIZE: usize = 256 * 1024;
+
+/// Bytes handed to the kernel per copy when cloning a local mirror file; progress is
+/// reported between slices.
+const LOCAL_COPY_SLICE: u64 = 64 * 1024 * 1024;
+
+/// Resolves a `file://` URL to a local path; `None` for any other scheme.
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    if !url.starts_with("file://") {
+        return None;
+    }
+    Url::parse(url).ok().and_then(|u| u.to_file_path().ok())
+}
+
+/// Size of a local mirror file, failing clearly if it is missing or unreadable.
+pub fn local_size(path: &Path) -> Result<u64> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Source file not accessible: {}", path.display()))?;
+    std::fs::File::open(path)
+        .with_context(|| format!("Source file not readable: {}", path.display()))?;
+    Ok(metadata.len())
+}
+
+/// Writes `source` from `offset` onward to `dest` at its current position with
+/// `std::io::copy`, which on Linux moves the data with `copy_file_range` (or `sendfile`)
+/// without passing it through userspace, and falls back to a buffered copy elsewhere.
+/// `dest` must not be opened for appending, or the kernel path is refused. `progress` is called with
+/// the bytes copied so far after every slice and may stop the copy by returning an error.
+pub fn copy_local(
+    source: &Path,
+    offset: u64,
+    dest: &mut std::fs::File,
+    mut progress: impl FnMut(u64) -> Result<()>,
+) -> Result<u64> {
+    use std::io::{Read, Seek};
+
+    let mut file = std::fs::File::open(source)
+        .with_context(|| format!("Failed to open source file {}", source.display()))?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut copied = 0u64;
+    loop {
+        let n = std::io::copy(&mut (&file).take(LOCAL_COPY_SLICE), dest)
+            .with_context(|| format!("Failed to copy from {}", source.display()))?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        copied += n;
+        progress(copied)?;
+    }
+}
+
+/// A stream of bytes from either an HTTP response or a local mirror file.
+pub enum Body {
+    Http(Response),
+    Multipart(MultipartBody),
+    Local(File),
+}
+
+impl Body {
+    /// Wraps a download response, unwrapping a `multipart/byteranges` envelope
+    /// so only the requested bytes reach the file.
+    pub fn from_response(response: Response, offset: u64) -> Self {
+        let boundary = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(multipart::byteranges_boundary);
+        match boundary {
+            Some(boundary) if response.status() == StatusCode::PARTIAL_CONTENT => {
+                Body::Multipart(MultipartBody::new(response, &boundary, offset))
+            }
+            _ => Body::Http(response),
+        }
+    }
+
+    pub async fn open_local(path: &Path, offset: u64) -> Result<Self> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open source file {}", path.display()))?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(Body::Local(file))
+    }
+
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        match self {
+            Body::Http(response) => Ok(response.chunk().await?),
+            Body::Multipart(body) => body.chunk().await,
+            Body::Local(file) => {
+                let mut buffer = vec![0u8; LOCAL_READ_SIZE];
+                let n = file.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                buffer.truncate(n);
+                Ok(Some(Bytes::from(buffer)))
+            }
+        }
+    }
+}
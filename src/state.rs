@@ -1,36 +1,267 @@
-use crate::models::DownloadState;
-use anyhow::{Context, Result};
-use serde_json;
+use crate::models::{BatchCursor, DownloadState};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Layout of the state file written by this build. Version 0 is the original bare
+/// JSON array of entries; version 1 wraps it as `{"schema_version": 1, "files": [...]}`.
+pub const STATE_SCHEMA_VERSION: u64 = 1;
+
+#[derive(Serialize)]
+struct VersionedState<'a> {
+    schema_version: u64,
+    files: &'a [DownloadState],
+}
+
+/// What `--validate-json-state` found in the state file.
+#[derive(Debug)]
+pub struct StateCheck {
+    pub path: PathBuf,
+    pub schema_version: u64,
+    pub entries: usize,
+    pub problems: Vec<String>,
+}
+
+/// On-disk encoding of the state file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StateFormat {
+    /// Pretty-printed `.download_state.json`
+    #[default]
+    Pretty,
+    /// Single-line `.download_state.json`
+    Compact,
+    /// zstd-compressed compact JSON in `.download_state.json.zst`
+    Zstd,
+}
 
 pub struct StateManager {
     state_file: PathBuf,
+    zstd_state_file: PathBuf,
+    cursor_file: PathBuf,
+    format: StateFormat,
 }
 
 impl StateManager {
-    pub fn new(output_dir: &PathBuf) -> Self {
+    pub fn new(output_dir: &Path, format: StateFormat) -> Self {
         let state_file = output_dir.join(".download_state.json");
-        Self { state_file }
+        let zstd_state_file = output_dir.join(".download_state.json.zst");
+        let cursor_file = output_dir.join(".cursor");
+        Self {
+            state_file,
+            zstd_state_file,
+            cursor_file,
+            format,
+        }
     }
 
+    /// Loads state from whichever file exists, preferring the configured format.
+    /// The encoding is detected from the extension. Files in an older schema are
+    /// upgraded; a file from a newer version is set aside and loading starts fresh.
     pub fn load_state(&self) -> Result<Vec<DownloadState>> {
-        if !self.state_file.exists() {
+        let Some((path, content)) = self.read_state_file()? else {
             return Ok(Vec::new());
+        };
+        let value: Value = serde_json::from_str(&content).context("Failed to parse state file")?;
+        let version = schema_version(&value)?;
+        if version > STATE_SCHEMA_VERSION {
+            // 新版本写入的状态文件无法安全读取：备份后从空状态开始，避免下次保存时覆盖丢失
+            let backup = path.with_extension(format!("v{}.bak", version));
+            fs::copy(&path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+            eprintln!(
+                "Warning: {} uses state schema {} but this build reads up to {}; starting with empty state (old file kept as {})",
+                path.display(),
+                version,
+                STATE_SCHEMA_VERSION,
+                backup.display()
+            );
+            return Ok(Vec::new());
+        }
+
+        let files = migrate(value, version)?;
+        serde_json::from_value(files).context("Failed to parse state file")
+    }
+
+    /// `--validate-json-state`: loads the state file the way `load_state` would and
+    /// reports entries that contradict each other or themselves.
+    pub fn validate(&self) -> Result<Option<StateCheck>> {
+        let Some((path, content)) = self.read_state_file()? else {
+            return Ok(None);
+        };
+        let value: Value = serde_json::from_str(&content).context("State file is not valid JSON")?;
+        let schema_version = schema_version(&value)?;
+        if schema_version > STATE_SCHEMA_VERSION {
+            return Err(anyhow!(
+                "State schema {} is newer than this build supports ({})",
+                schema_version,
+                STATE_SCHEMA_VERSION
+            ));
+        }
+        let files = migrate(value, schema_version)?;
+        let Value::Array(files) = files else {
+            return Err(anyhow!("State file `files` is not an array"));
+        };
+
+        let mut problems = Vec::new();
+        let mut seen = HashSet::new();
+        for (idx, file) in files.iter().enumerate() {
+            let state: DownloadState = match serde_json::from_value(file.clone()) {
+                Ok(state) => state,
+                Err(e) => {
+                    problems.push(format!("entry {}: {}", idx, e));
+                    continue;
+                }
+            };
+            if !seen.insert(state.file_name.clone()) {
+                problems.push(format!("{}: listed more than once", state.file_name));
+            }
+            if let Some(total) = state.total_bytes {
+                if state.downloaded_bytes > total {
+                    problems.push(format!(
+                        "{}: {} bytes downloaded of {} expected",
+                        state.file_name, state.downloaded_bytes, total
+                    ));
+                }
+                if state.completed && state.downloaded_bytes != total {
+                    problems.push(format!(
+                        "{}: marked completed with {} of {} bytes",
+                        state.file_name, state.downloaded_bytes, total
+                    ));
+                }
+            }
         }
 
-        let content = fs::read_to_string(&self.state_file)
-            .context("Failed to read state file")?;
+        Ok(Some(StateCheck {
+            path,
+            schema_version,
+            entries: files.len(),
+            problems,
+        }))
+    }
+
+    fn read_state_file(&self) -> Result<Option<(PathBuf, String)>> {
+        let candidates = if self.format == StateFormat::Zstd {
+            [&self.zstd_state_file, &self.state_file]
+        } else {
+            [&self.state_file, &self.zstd_state_file]
+        };
+        let Some(path) = candidates.into_iter().find(|p| p.exists()) else {
+            return Ok(None);
+        };
 
-        serde_json::from_str(&content).context("Failed to parse state file")
+        let content = if path == &self.zstd_state_file {
+            let compressed = fs::read(path).context("Failed to read state file")?;
+            let bytes = zstd::decode_all(compressed.as_slice())
+                .context("Failed to decompress state file")?;
+            String::from_utf8(bytes).context("State file is not valid UTF-8")?
+        } else {
+            fs::read_to_string(path).context("Failed to read state file")?
+        };
+
+        Ok(Some((path.clone(), content)))
     }
 
     pub fn save_state(&self, states: &[DownloadState]) -> Result<()> {
-        let content = serde_json::to_string_pretty(states)
-            .context("Failed to serialize state")?;
+        let versioned = VersionedState {
+            schema_version: STATE_SCHEMA_VERSION,
+            files: states,
+        };
+        let content = match self.format {
+            StateFormat::Pretty => serde_json::to_string_pretty(&versioned),
+            StateFormat::Compact | StateFormat::Zstd => serde_json::to_string(&versioned),
+        }
+        .context("Failed to serialize state")?;
+
+        let (path, stale) = if self.format == StateFormat::Zstd {
+            let compressed = zstd::encode_all(content.as_bytes(), ZSTD_LEVEL)
+                .context("Failed to compress state")?;
+            fs::write(&self.zstd_state_file, compressed).context("Failed to write state file")?;
+            (&self.zstd_state_file, &self.state_file)
+        } else {
+            fs::write(&self.state_file, content).context("Failed to write state file")?;
+            (&self.state_file, &self.zstd_state_file)
+        };
 
-        fs::write(&self.state_file, content).context("Failed to write state file")?;
+        // 切换格式后删除旧格式的状态文件，避免下次加载到过期数据
+        if stale.exists() && stale != path {
+            fs::remove_file(stale).context("Failed to remove old state file")?;
+        }
 
         Ok(())
     }
+
+    /// Deletes the state file in either format.
+    pub fn remove_state(&self) -> Result<()> {
+        for path in [&self.state_file, &self.zstd_state_file] {
+            if path.exists() {
+                fs::remove_file(path).context("Failed to remove state file")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_cursor(&self) -> Result<Option<BatchCursor>> {
+        if !self.cursor_file.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.cursor_file)
+            .context("Failed to read cursor file")?;
+
+        serde_json::from_str(&content).map(Some).context("Failed to parse cursor file")
+    }
+
+    pub fn save_cursor(&self, cursor: &BatchCursor) -> Result<()> {
+        let content = serde_json::to_string_pretty(cursor)
+            .context("Failed to serialize cursor")?;
+
+        fs::write(&self.cursor_file, content).context("Failed to write cursor file")?;
+
+        Ok(())
+    }
+}
+
+/// Version 0 files are a bare array; later ones carry `schema_version`.
+fn schema_version(value: &Value) -> Result<u64> {
+    match value {
+        Value::Array(_) => Ok(0),
+        Value::Object(object) => object
+            .get("schema_version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("State file has no valid `schema_version`")),
+        _ => Err(anyhow!("State file is neither an array nor an object")),
+    }
+}
+
+/// Upgrades a state file of `version` one step at a time and returns its entries
+/// in the current layout.
+fn migrate(mut value: Value, version: u64) -> Result<Value> {
+    if let (0, Value::Array(files)) = (version, &mut value) {
+        value = migrate_v0(std::mem::take(files));
+    }
+    match value {
+        Value::Object(mut object) => object
+            .remove("files")
+            .ok_or_else(|| anyhow!("State file has no `files`")),
+        _ => Err(anyhow!("State file is not an object")),
+    }
+}
+
+/// 0 -> 1: wraps the bare array, and fills in `timeouts`, which entries written
+/// before `--max-runtime-per-file` lack.
+fn migrate_v0(mut files: Vec<Value>) -> Value {
+    for file in &mut files {
+        if let Value::Object(object) = file {
+            object.entry("timeouts").or_insert(Value::from(0));
+        }
+    }
+    let mut object = Map::new();
+    object.insert("schema_version".to_string(), Value::from(1));
+    object.insert("files".to_string(), Value::Array(files));
+    Value::Object(object)
 }
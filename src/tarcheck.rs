@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const BLOCK: u64 = 512;
+
+/// Whether `file_name` is a format [`last_good_offset`] knows how to inspect.
+pub fn is_supported(file_name: &str) -> bool {
+    file_name.ends_with(".tar")
+}
+
+/// Walks the tar headers in a partial archive and returns the largest offset
+/// up to which the data is structurally sound. A header with a bad checksum
+/// (typically a torn write) cuts the file back to where that header starts;
+/// otherwise the result is the length rounded down to a whole 512-byte block.
+pub fn last_good_offset(path: &Path) -> Result<u64> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let whole_blocks = len / BLOCK * BLOCK;
+
+    let mut offset = 0u64;
+    let mut header = [0u8; BLOCK as usize];
+    while offset + BLOCK <= len {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+
+        // 全零块是归档结束标记，之后不再有成员头
+        if header.iter().all(|&b| b == 0) {
+            return Ok(whole_blocks);
+        }
+        let Some(size) = parse_header(&header) else {
+            return Ok(offset);
+        };
+
+        let next = offset + BLOCK + size.div_ceil(BLOCK) * BLOCK;
+        if next > len {
+            // 当前成员的数据只下载了一部分，保留已完整写入的块
+            return Ok(whole_blocks);
+        }
+        offset = next;
+    }
+
+    Ok(offset)
+}
+
+/// Returns the member size if the header checksum is valid.
+fn parse_header(header: &[u8; BLOCK as usize]) -> Option<u64> {
+    let stored = parse_octal(&header[148..156])?;
+    // 计算校验和时 chksum 字段按 8 个空格处理
+    let actual: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum();
+    if stored != actual {
+        return None;
+    }
+
+    let size_field = &header[124..136];
+    if size_field[0] & 0x80 != 0 {
+        // GNU base-256 编码，用于超过 8GiB 的成员
+        Some(size_field[1..].iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+    } else {
+        parse_octal(size_field)
+    }
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(field).ok()?;
+    let digits = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ustar header for a member of `size` bytes, with a valid checksum.
+    fn header(name: &str, size: u64) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK as usize];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..108].copy_from_slice(b"0000644\0");
+        block[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        block[156] = b'0';
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[148..156].copy_from_slice(b"        ");
+        let sum: u64 = block.iter().map(|&b| b as u64).sum();
+        block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        block
+    }
+
+    /// A header followed by `size` bytes of data padded to whole blocks.
+    fn member(name: &str, size: u64) -> Vec<u8> {
+        let mut bytes = header(name, size);
+        bytes.resize(bytes.len() + (size.div_ceil(BLOCK) * BLOCK) as usize, 0x5a);
+        bytes
+    }
+
+    fn good_offset(image: &[u8]) -> u64 {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sa_0.tar.part");
+        std::fs::write(&path, image).unwrap();
+        last_good_offset(&path).unwrap()
+    }
+
+    #[test]
+    fn only_tar_files_are_supported() {
+        assert!(is_supported("sa_0.tar"));
+        assert!(!is_supported("sa_0.tar.gz"));
+        assert!(!is_supported("links.txt"));
+    }
+
+    #[test]
+    fn part_shorter_than_one_block_restarts_from_zero() {
+        assert_eq!(good_offset(&[]), 0);
+        assert_eq!(good_offset(&header("a.jpg", 10)[..300]), 0);
+    }
+
+    #[test]
+    fn whole_members_are_kept() {
+        let image = [member("a.jpg", 1000), member("b.json", 512)].concat();
+        assert_eq!(good_offset(&image), image.len() as u64);
+    }
+
+    #[test]
+    fn torn_header_is_cut_back_to_where_it_starts() {
+        let first = member("a.jpg", 1000);
+        let image = [first.clone(), header("b.json", 10)[..200].to_vec()].concat();
+        assert_eq!(good_offset(&image), first.len() as u64);
+    }
+
+    #[test]
+    fn header_with_a_bad_checksum_is_cut_back_to_where_it_starts() {
+        let first = member("a.jpg", 1000);
+        let mut second = member("b.json", 10);
+        second[0] ^= 0xff;
+        let image = [first.clone(), second].concat();
+        assert_eq!(good_offset(&image), first.len() as u64);
+    }
+
+    #[test]
+    fn member_with_partial_data_keeps_its_whole_blocks() {
+        let first = member("a.jpg", 1000);
+        let mut image = [first.clone(), header("b.jpg", 4096)].concat();
+        image.resize(image.len() + 1500, 0x5a);
+        assert_eq!(good_offset(&image), (first.len() + 512 + 1024) as u64);
+    }
+
+    #[test]
+    fn zero_end_of_archive_block_keeps_the_rest_of_the_file() {
+        let mut image = member("a.jpg", 1000);
+        image.resize(image.len() + 2 * BLOCK as usize + 100, 0);
+        assert_eq!(good_offset(&image), image.len() as u64 / BLOCK * BLOCK);
+    }
+
+    #[test]
+    fn base_256_sizes_are_decoded() {
+        let mut block = [0u8; BLOCK as usize];
+        block[124] = 0x80;
+        block[128..136].copy_from_slice(&(9u64 << 30).to_be_bytes());
+        block[148..156].copy_from_slice(b"        ");
+        let sum: u64 = block.iter().map(|&b| b as u64).sum();
+        block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        assert_eq!(parse_header(&block), Some(9 << 30));
+    }
+}
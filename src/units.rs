@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Parses a human-readable byte size such as `500GB`, `10M`, `1.5GiB` or `4096`.
+/// Decimal (`KB`, `MB`, ...) and binary (`KiB`, `MiB`, ...) suffixes are accepted;
+/// bare `K`/`M`/`G`/`T` are treated as binary.
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: {}", s))?;
+
+    let multiplier: u64 = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000_u64.pow(2),
+        "gb" => 1000_u64.pow(3),
+        "tb" => 1000_u64.pow(4),
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        other => return Err(anyhow!("Unknown size suffix '{}' in {}", other, s)),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a bandwidth limit in bytes per second, like [`parse_bytes`]; zero (which
+/// would leave the token bucket nothing to divide by) is rejected.
+pub fn parse_rate(s: &str) -> Result<u64> {
+    match parse_bytes(s)? {
+        0 => Err(anyhow!("Bandwidth limit must be greater than zero: {}", s.trim())),
+        rate => Ok(rate),
+    }
+}
+
+/// Slowest request rate accepted: one request a day. Anything slower would push the
+/// pacer's next slot past what `Instant` can represent.
+pub const MIN_REQUEST_RATE: f64 = 1.0 / 86_400.0;
+
+/// Parses a request rate per second such as `2` or `0.5`; zero, `NaN`, infinity and
+/// rates below [`MIN_REQUEST_RATE`] are rejected.
+pub fn parse_request_rate(s: &str) -> Result<f64> {
+    let rate: f64 = s.trim().parse().map_err(|_| anyhow!("Invalid request rate: {}", s.trim()))?;
+    if !rate.is_finite() || rate < MIN_REQUEST_RATE {
+        return Err(anyhow!(
+            "Request rate must be a number of at least {:.6} (one request a day): {}",
+            MIN_REQUEST_RATE,
+            s.trim()
+        ));
+    }
+    Ok(rate)
+}
+
+/// Parses a duration such as `30d`, `12h`, `45m`, `90s` or a bare number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration: {}", s))?;
+
+    let multiplier: u64 = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        other => return Err(anyhow!("Unknown duration suffix '{}' in {}", other, s)),
+    };
+
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+/// Parses a Unix permission mode in octal, e.g. `0640` or `640`.
+pub fn parse_mode(s: &str) -> Result<u32> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    let mode = u32::from_str_radix(digits, 8).map_err(|_| anyhow!("Invalid octal mode: {}", s))?;
+    if mode > 0o7777 {
+        return Err(anyhow!("Mode {} is out of range (max 7777)", s));
+    }
+    Ok(mode)
+}
+
+/// A half-open byte range `START-END` (END exclusive), e.g. `0-104857600` or `0-100MiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// The inclusive form used by the HTTP `Range` header.
+    pub fn header_value(&self) -> String {
+        format!("bytes={}-{}", self.start, self.end - 1)
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Expected START-END, got '{}'", s))?;
+        let start = parse_bytes(start)?;
+        let end = parse_bytes(end)?;
+        if end <= start {
+            return Err(anyhow!("Byte range end must be greater than start: {}", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
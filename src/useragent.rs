@@ -0,0 +1,30 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+
+/// Sent on every request unless `--randomize-user-agent` is set.
+pub const DEFAULT_USER_AGENT: &str = concat!("sa-1b-dl/", env!("CARGO_PKG_VERSION"));
+
+/// Built-in pool for `--randomize-user-agent` when no `--user-agent-file` is given.
+pub const BUILTIN_POOL: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Wget/1.21.4",
+    "curl/8.7.1",
+];
+
+/// Reads one user agent per line, ignoring blank lines and `#` comments.
+pub fn load_user_agent_file(path: &str) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path).context("Failed to read user agent file")?;
+    let pool: Vec<String> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if pool.is_empty() {
+        return Err(anyhow!("User agent file {} contains no entries", path));
+    }
+    Ok(pool)
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use reqwest::Client;
+use std::time::{Duration, Instant};
+
+/// Result of pre-opening connections to one host.
+#[derive(Debug, Clone)]
+pub struct Warmup {
+    pub host: String,
+    pub connections: usize,
+    /// Average time of the first requests, each of which had to connect (and handshake).
+    pub cold: Duration,
+    /// Time of a request sent afterwards over one of the pooled connections.
+    pub warm: Duration,
+}
+
+impl Warmup {
+    /// Connect latency a file saves by starting on an already open connection.
+    pub fn saving(&self) -> Duration {
+        self.cold.saturating_sub(self.warm)
+    }
+}
+
+/// Opens `connections` connections to the host serving `url` with parallel HEAD
+/// requests, leaving them idle in the client's pool for the downloads that follow.
+pub async fn warm_host(client: &Client, host: &str, url: &str, connections: usize) -> Result<Warmup> {
+    let connections = connections.max(1);
+    let cold = join_all((0..connections).map(|_| timed_head(client, url)))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    let warm = timed_head(client, url).await?;
+
+    Ok(Warmup {
+        host: host.to_string(),
+        connections,
+        cold: cold.iter().sum::<Duration>() / connections as u32,
+        warm,
+    })
+}
+
+async fn timed_head(client: &Client, url: &str) -> Result<Duration> {
+    let started = Instant::now();
+    // 任何状态码都说明连接已建立，响应丢弃后连接回到连接池
+    client
+        .head(url)
+        .send()
+        .await
+        .with_context(|| format!("Warm-up request to {} failed", url))?;
+    Ok(started.elapsed())
+}
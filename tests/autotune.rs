@@ -0,0 +1,33 @@
+//! `--adaptive-concurrency` grows the worker pool one at a time and backs off on errors.
+
+use sa_1b_dl::autotune::Aimd;
+
+#[test]
+fn increases_additively_and_halves_on_errors() {
+    let mut aimd = Aimd::new(4, 10);
+
+    assert_eq!(aimd.next(100.0, 0), 5);
+    assert_eq!(aimd.next(120.0, 0), 6);
+    assert_eq!(aimd.next(130.0, 3), 3);
+    assert_eq!(aimd.next(130.0, 1), 1);
+    // 不会降到一个线程以下
+    assert_eq!(aimd.next(130.0, 1), 1);
+}
+
+#[test]
+fn steps_back_when_throughput_falls() {
+    let mut aimd = Aimd::new(4, 10);
+
+    assert_eq!(aimd.next(100.0, 0), 5);
+    assert_eq!(aimd.next(80.0, 0), 4);
+    // 小幅波动不算下降
+    assert_eq!(aimd.next(75.0, 0), 5);
+}
+
+#[test]
+fn stays_within_maximum() {
+    let mut aimd = Aimd::new(8, 6);
+    assert_eq!(aimd.current(), 6);
+
+    assert_eq!(aimd.next(100.0, 0), 6);
+}
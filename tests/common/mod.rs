@@ -0,0 +1,118 @@
+//! Shared fixtures for the integration tests: a downloader configured from CLI
+//! flags, and a mock file server that honours byte ranges.
+
+// 每个测试二进制只用到其中一部分
+#![allow(dead_code)]
+
+use clap::Parser;
+use sa_1b_dl::cli::Args;
+use sa_1b_dl::downloader::Downloader;
+use sa_1b_dl::models::LinkEntry;
+use std::path::Path;
+use wiremock::{Request, Respond, ResponseTemplate};
+
+/// Builds a downloader writing to `output`, configured exactly as the binary would
+/// be with `flags` on the command line.
+pub fn downloader(output: &Path, flags: &[&str]) -> Downloader {
+    try_downloader(output, flags).unwrap()
+}
+
+/// Like [`downloader`], but returns the error when the output directory is rejected.
+pub fn try_downloader(output: &Path, flags: &[&str]) -> anyhow::Result<Downloader> {
+    let mut argv = vec!["sa-1b-dl", "--quiet-summary-json", "-o", output.to_str().unwrap()];
+    argv.extend_from_slice(flags);
+    let args = Args::try_parse_from(argv).expect("valid test flags");
+    Downloader::new(&args.output, args.download_options().unwrap())
+}
+
+pub fn entry(file_name: &str, url: String) -> LinkEntry {
+    LinkEntry {
+        file_name: file_name.to_string(),
+        url,
+        checksum: None,
+        mirrors: Vec::new(),
+        block_crcs: None,
+        size: None,
+        metadata: None,
+    }
+}
+
+/// Deterministic, non-repeating-looking test data.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// Serves `body` like a static file server: HEAD and plain GET return it whole,
+/// `Range: bytes=N-` and `bytes=N-M` return 206 with the requested slice.
+pub struct RangeFile {
+    pub body: Vec<u8>,
+}
+
+impl Respond for RangeFile {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let Some(range) = request.headers.get("range").and_then(|v| v.to_str().ok()) else {
+            return ResponseTemplate::new(200).set_body_bytes(self.body.clone());
+        };
+        let (start, end) = parse_range(range, self.body.len());
+        ResponseTemplate::new(206)
+            .insert_header(
+                "content-range",
+                format!("bytes {}-{}/{}", start, end - 1, self.body.len()).as_str(),
+            )
+            .set_body_bytes(self.body[start..end].to_vec())
+    }
+}
+
+/// Reports the full size on HEAD but drops the connection's data after `cut`
+/// bytes on every GET, like a transfer that was interrupted.
+pub struct TruncatedFile {
+    pub body: Vec<u8>,
+    pub cut: usize,
+}
+
+impl Respond for TruncatedFile {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        if request.method.as_str() == "HEAD" {
+            return ResponseTemplate::new(200).set_body_bytes(self.body.clone());
+        }
+        ResponseTemplate::new(200).set_body_bytes(self.body[..self.cut].to_vec())
+    }
+}
+
+/// Like [`RangeFile`], but answers an open-ended `Range: bytes=N-` with a single-part
+/// `multipart/byteranges` body, as some CDNs do.
+pub struct MultipartFile {
+    pub body: Vec<u8>,
+}
+
+impl Respond for MultipartFile {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let range = request.headers.get("range").and_then(|v| v.to_str().ok());
+        let Some(range) = range.filter(|r| r.ends_with('-')) else {
+            return RangeFile { body: self.body.clone() }.respond(request);
+        };
+        let (start, end) = parse_range(range, self.body.len());
+        let mut body = format!(
+            "--SPLIT\r\nContent-Type: application/x-tar\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            start,
+            end - 1,
+            self.body.len()
+        )
+        .into_bytes();
+        body.extend_from_slice(&self.body[start..end]);
+        body.extend_from_slice(b"\r\n--SPLIT--\r\n");
+        ResponseTemplate::new(206).set_body_raw(body, "multipart/byteranges; boundary=SPLIT")
+    }
+}
+
+/// Parses `bytes=N-` / `bytes=N-M` into a half-open range.
+fn parse_range(header: &str, len: usize) -> (usize, usize) {
+    let spec = header.strip_prefix("bytes=").expect("bytes range");
+    let (start, end) = spec.split_once('-').expect("range separator");
+    let start: usize = start.parse().unwrap();
+    let end = match end {
+        "" => len,
+        end => (end.parse::<usize>().unwrap() + 1).min(len),
+    };
+    (start, end)
+}
@@ -0,0 +1,1258 @@
+mod common;
+
+use clap::Parser;
+use common::{downloader, entry, payload, MultipartFile, RangeFile, TruncatedFile};
+use sa_1b_dl::cli::Args;
+use sa_1b_dl::error::DownloadError;
+use sa_1b_dl::models::{FileStatus, LinkEntry};
+use sa_1b_dl::segments::{self, Segment, SegmentMap};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::time::{Duration, Instant};
+use wiremock::matchers::{any, header_exists, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn downloads_whole_file() {
+    let server = MockServer::start().await;
+    let body = payload(10_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = downloader(dir.path(), &[])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 1);
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    assert!(!dir.path().join("sa_0.tar.part").exists());
+}
+
+#[tokio::test]
+async fn resumes_partial_file_with_range_request() {
+    let server = MockServer::start().await;
+    let body = payload(10_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..4_000]).unwrap();
+
+    downloader(dir.path(), &[])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    let get = requests.iter().find(|r| r.method.as_str() == "GET").unwrap();
+    assert_eq!(get.headers.get("range").unwrap(), "bytes=4000-");
+}
+
+#[tokio::test]
+async fn resume_rejects_range_starting_elsewhere() {
+    let server = MockServer::start().await;
+    let body = payload(10_000);
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(206)
+                .insert_header("content-range", "bytes 0-9999/10000")
+                .set_body_bytes(body.clone()),
+        )
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..4_000]).unwrap();
+
+    let error = downloader(dir.path(), &["--retries", "0"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap_err();
+
+    // 错位的数据不会接到 .part 后面，.part 清空等待从头下载
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(DownloadError::RangeMismatch { expected: 4_000, actual: 0, .. })
+    ));
+    assert!(!dir.path().join("sa_0.tar").exists());
+    assert!(fs::read(dir.path().join("sa_0.tar.part")).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn resume_restarts_when_server_ignores_range() {
+    let server = MockServer::start().await;
+    let body = payload(10_000);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(body.clone()))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..4_000]).unwrap();
+
+    downloader(dir.path(), &[])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn retries_after_rate_limit() {
+    let server = MockServer::start().await;
+    let body = payload(5_000);
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(429))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = downloader(dir.path(), &["--retries", "2"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 1);
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn truncated_body_keeps_partial_for_the_next_run() {
+    let server = MockServer::start().await;
+    let body = payload(8_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(TruncatedFile { body: body.clone(), cut: 3_000 })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let link = entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()));
+
+    let result = downloader(dir.path(), &["--retries", "1"]).download_single(&link).await;
+
+    assert!(result.is_err());
+    assert!(!dir.path().join("sa_0.tar").exists());
+    assert_eq!(fs::read(dir.path().join("sa_0.tar.part")).unwrap(), &body[..3_000]);
+
+    // 服务器恢复正常后，下一次运行从断点继续
+    server.reset().await;
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+
+    downloader(dir.path(), &["--retries", "1"]).download_single(&link).await.unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    let get = requests.iter().find(|r| r.method.as_str() == "GET").unwrap();
+    assert_eq!(get.headers.get("range").unwrap(), "bytes=3000-");
+}
+
+#[tokio::test]
+async fn download_all_reports_each_file() {
+    let server = MockServer::start().await;
+    let body = payload(2_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    Mock::given(path("/sa_1.tar"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let entries = vec![
+        entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())),
+        entry("sa_1.tar", format!("{}/sa_1.tar", server.uri())),
+    ];
+
+    let summary = downloader(dir.path(), &["--retries", "1"])
+        .download_all(entries, 2)
+        .await
+        .unwrap();
+
+    assert_eq!((summary.success, summary.failed), (1, 1));
+    let failed = summary.files.iter().find(|f| f.file_name == "sa_1.tar").unwrap();
+    assert_eq!(failed.status, FileStatus::Failed);
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn continues_incomplete_file_left_by_another_tool() {
+    let server = MockServer::start().await;
+    let body = payload(6_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar"), &body[..2_500]).unwrap();
+
+    downloader(dir.path(), &["--continue-partial-from-other-tool"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    let get = requests.iter().find(|r| r.method.as_str() == "GET").unwrap();
+    assert_eq!(get.headers.get("range").unwrap(), "bytes=2500-");
+}
+
+#[tokio::test]
+async fn preallocated_aria2_file_is_not_trusted() {
+    let server = MockServer::start().await;
+    let body = payload(6_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar"), vec![0u8; body.len()]).unwrap();
+    fs::write(dir.path().join("sa_0.tar.aria2"), b"control").unwrap();
+
+    downloader(dir.path(), &[])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    assert!(!dir.path().join("sa_0.tar.aria2").exists());
+}
+
+#[tokio::test]
+async fn copies_local_mirror_onto_partial_file() {
+    let mirror = tempfile::tempdir().unwrap();
+    let body = payload(300_000);
+    fs::write(mirror.path().join("sa_0.tar"), &body).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..120_000]).unwrap();
+
+    let url = format!("file://{}", mirror.path().join("sa_0.tar").display());
+    let summary = downloader(dir.path(), &[])
+        .download_single(&entry("sa_0.tar", url))
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 1);
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn allow_smaller_accepts_slightly_short_file() {
+    let server = MockServer::start().await;
+    let body = payload(4_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(TruncatedFile { body: body.clone(), cut: 3_990 })
+        .mount(&server)
+        .await;
+    let link = entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()));
+
+    let strict = tempfile::tempdir().unwrap();
+    let result = downloader(strict.path(), &["--retries", "1"]).download_single(&link).await;
+    assert!(result.is_err());
+
+    let tolerant = tempfile::tempdir().unwrap();
+    downloader(tolerant.path(), &["--retries", "1", "--allow-smaller", "16"])
+        .download_single(&link)
+        .await
+        .unwrap();
+    assert_eq!(fs::read(tolerant.path().join("sa_0.tar")).unwrap(), &body[..3_990]);
+}
+
+#[tokio::test]
+async fn client_errors_fail_without_retrying() {
+    let server = MockServer::start().await;
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let error = downloader(dir.path(), &["--retries", "3", "--head-method", "get-range"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap_err();
+
+    assert!(matches!(error.downcast_ref(), Some(DownloadError::HttpStatus(404))));
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn resume_scan_rebuilds_state_from_disk() {
+    let server = MockServer::start().await;
+    let body = payload(8_000);
+    Mock::given(path("/sa_1.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar"), payload(6_000)).unwrap();
+    fs::write(dir.path().join("sa_1.tar.part"), &body[..3_000]).unwrap();
+    let entries: Vec<_> = [("sa_0.tar", 6_000), ("sa_1.tar", 8_000)]
+        .into_iter()
+        .map(|(name, size)| LinkEntry {
+            size: Some(size),
+            ..entry(name, format!("{}/{}", server.uri(), name))
+        })
+        .collect();
+
+    let downloader = downloader(dir.path(), &["--trust-manifest-size"]);
+    assert_eq!(downloader.rebuild_state(&entries).unwrap(), (1, 1));
+    let summary = downloader.download_all(entries, 2).await.unwrap();
+
+    assert_eq!(summary.failed, 0);
+    assert_eq!(fs::read(dir.path().join("sa_1.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests.iter().all(|r| r.url.path() == "/sa_1.tar"));
+}
+
+#[test]
+fn compact_state_prunes_entries_without_files() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar"), payload(100)).unwrap();
+    fs::write(dir.path().join("sa_1.tar.part"), payload(50)).unwrap();
+    fs::write(dir.path().join("sa_2.tar"), payload(100)).unwrap();
+    let entries: Vec<_> = ["sa_0.tar", "sa_1.tar", "sa_2.tar"]
+        .into_iter()
+        .map(|name| LinkEntry {
+            size: Some(100),
+            ..entry(name, format!("http://127.0.0.1:9/{}", name))
+        })
+        .collect();
+    let downloader = downloader(dir.path(), &[]);
+    assert_eq!(downloader.rebuild_state(&entries).unwrap(), (2, 1));
+    fs::remove_file(dir.path().join("sa_2.tar")).unwrap();
+
+    assert_eq!(downloader.compact_state(false).unwrap(), (1, 2));
+    assert_eq!(downloader.compact_state(true).unwrap(), (1, 1));
+}
+
+#[tokio::test]
+async fn expand_dirs_lists_directories_recursively() {
+    let server = MockServer::start().await;
+    Mock::given(path("/set/"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"<a href="../">../</a><a href="?C=N;O=D">Name</a><a href="a.tar">a.tar</a><a href="inner/">inner/</a>"#,
+            "text/html",
+        ))
+        .mount(&server)
+        .await;
+    Mock::given(path("/set/inner/"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"[{"name":"b.tar","type":"file","size":42},{"name":"deeper","type":"directory"}]"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    Mock::given(path("/set/inner/deeper/"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw("[]", "application/json"))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let entries = downloader(dir.path(), &[])
+        .expand_dirs(vec![
+            entry("batch", format!("{}/set/", server.uri())),
+            entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())),
+        ])
+        .await
+        .unwrap();
+
+    let mut listed: Vec<_> = entries.iter().map(|e| (e.file_name.as_str(), e.url.as_str(), e.size)).collect();
+    listed.sort();
+    let a = format!("{}/set/a.tar", server.uri());
+    let b = format!("{}/set/inner/b.tar", server.uri());
+    let sa_0 = format!("{}/sa_0.tar", server.uri());
+    assert_eq!(
+        listed,
+        vec![
+            ("batch/a.tar", a.as_str(), None),
+            ("batch/inner/b.tar", b.as_str(), Some(42)),
+            ("sa_0.tar", sa_0.as_str(), None),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn hash_algo_auto_infers_algorithm_from_checksum_length() {
+    let server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: b"hello world".to_vec() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let checked = |name: &str, checksum: &str| LinkEntry {
+        checksum: Some(checksum.to_string()),
+        ..entry(name, format!("{}/{}", server.uri(), name))
+    };
+
+    let summary = downloader(
+        dir.path(),
+        &["--verify-after-download", "--hash-algo", "auto", "--on-checksum-mismatch", "skip"],
+    )
+    .download_all(
+        vec![
+            checked("md5.tar", "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+            checked("sha1.tar", "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"),
+            checked("bad.tar", &"0".repeat(40)),
+        ],
+        1,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!((summary.success, summary.failed), (2, 1));
+    assert!(dir.path().join("md5.tar").exists());
+    assert!(dir.path().join("sha1.tar").exists());
+    assert!(!dir.path().join("bad.tar").exists());
+}
+
+#[tokio::test]
+async fn failed_files_keep_their_error_in_state() {
+    let server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = downloader(dir.path(), &["--head-method", "get-range"])
+        .download_all(vec![entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))], 1)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.failed, 1);
+    let state = fs::read_to_string(dir.path().join(".download_state.json")).unwrap();
+    assert!(state.contains(r#""last_error": "Size probe failed: HTTP request failed: 404"#), "{}", state);
+}
+
+#[tokio::test]
+async fn single_file_mode_remembers_last_modified_for_since_now() {
+    let server = MockServer::start().await;
+    let body = payload(10_000);
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("Last-Modified", "Wed, 01 Jan 2025 00:00:00 GMT")
+                .set_body_bytes(body.clone()),
+        )
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let link = entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()));
+
+    let first = downloader(dir.path(), &["--since", "now"]).download_single(&link).await.unwrap();
+    let second = downloader(dir.path(), &["--since", "now"]).download_single(&link).await.unwrap();
+
+    assert_eq!((first.success, second.skipped), (1, 1));
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn global_connection_cap_serializes_requests() {
+    let server = MockServer::start().await;
+    let body = payload(1_000);
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(body.clone())
+                .set_delay(Duration::from_millis(200)),
+        )
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let entries = (0..4)
+        .map(|i| LinkEntry {
+            size: Some(1_000),
+            ..entry(&format!("sa_{}.tar", i), format!("{}/sa_{}.tar", server.uri(), i))
+        })
+        .collect();
+
+    let started = Instant::now();
+    let summary = downloader(dir.path(), &["--trust-manifest-size", "--max-connections-global", "1"])
+        .download_all(entries, 4)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 4);
+    // 四个请求只能依次进行
+    assert!(started.elapsed() >= Duration::from_millis(800));
+}
+
+#[tokio::test]
+async fn smallest_first_orders_by_probed_and_listed_sizes() {
+    let server = MockServer::start().await;
+    Mock::given(path("/a.tar"))
+        .respond_with(RangeFile { body: payload(3_000) })
+        .mount(&server)
+        .await;
+    Mock::given(path("/c.tar"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let sized = |name: &str, size: Option<u64>| LinkEntry {
+        size,
+        ..entry(name, format!("{}/{}", server.uri(), name))
+    };
+
+    let ordered = downloader(dir.path(), &[])
+        .smallest_first(
+            vec![
+                sized("a.tar", None),
+                sized("b.tar", Some(1_000)),
+                sized("c.tar", None),
+                sized("d.tar", Some(5_000)),
+            ],
+            4,
+        )
+        .await;
+
+    let names: Vec<_> = ordered.iter().map(|e| e.file_name.as_str()).collect();
+    assert_eq!(names, ["b.tar", "a.tar", "d.tar", "c.tar"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn output_permissions_apply_to_completed_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start().await;
+    let body = payload(2_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    downloader(dir.path(), &["--output-permissions", "0640"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    let mode = fs::metadata(dir.path().join("sa_0.tar")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o7777, 0o640);
+}
+
+#[tokio::test]
+async fn probe_ranges_restarts_partials_on_hosts_without_ranges() {
+    let server = MockServer::start().await;
+    let body = payload(6_000);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(body.clone()))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), vec![0u8; 2_000]).unwrap();
+    fs::write(dir.path().join("sa_1.tar.part"), vec![0u8; 3_000]).unwrap();
+
+    let dl = downloader(dir.path(), &["--probe-ranges"]);
+    for name in ["sa_0.tar", "sa_1.tar"] {
+        dl.download_single(&entry(name, format!("{}/{}", server.uri(), name))).await.unwrap();
+        assert_eq!(fs::read(dir.path().join(name)).unwrap(), body);
+    }
+
+    let requests = server.received_requests().await.unwrap();
+    let ranged: Vec<_> = requests.iter().filter_map(|r| r.headers.get("range")).collect();
+    assert_eq!(ranged, ["bytes=0-0"], "one probe for the host and no resume ranges");
+}
+
+#[tokio::test]
+async fn content_disposition_names_the_saved_file() {
+    let server = MockServer::start().await;
+    let body = payload(3_000);
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("content-disposition", "attachment; filename=\"../sa_7.tar\"")
+                .set_body_bytes(body.clone()),
+        )
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    downloader(dir.path(), &["--honor-content-disposition"])
+        .download_single(&entry("shard.bin", format!("{}/get?id=7", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_7.tar")).unwrap(), body);
+    assert!(!dir.path().join("shard.bin").exists());
+    assert!(!dir.path().parent().unwrap().join("sa_7.tar").exists());
+}
+
+#[tokio::test]
+async fn content_disposition_never_overwrites_another_entrys_file() {
+    let server = MockServer::start().await;
+    let bodies = [payload(3_000), payload(4_000)];
+    for (i, body) in bodies.iter().enumerate() {
+        Mock::given(path(format!("/get/{}", i)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-disposition", "attachment; filename=\"download.tar\"")
+                    .set_body_bytes(body.clone()),
+            )
+            .mount(&server)
+            .await;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let entries = vec![
+        entry("sa_0.tar", format!("{}/get/0", server.uri())),
+        entry("sa_1.tar", format!("{}/get/1", server.uri())),
+    ];
+
+    let summary = downloader(dir.path(), &["--honor-content-disposition"])
+        .download_all(entries, 2)
+        .await
+        .unwrap();
+
+    // 先完成的条目得到服务器给的名字，另一个保留清单中的名字
+    assert_eq!(summary.success, 2);
+    let shared = fs::read(dir.path().join("download.tar")).unwrap();
+    let (winner, loser) = if shared == bodies[0] { (0, 1) } else { (1, 0) };
+    assert_eq!(shared, bodies[winner]);
+    assert_eq!(fs::read(dir.path().join(format!("sa_{}.tar", loser))).unwrap(), bodies[loser]);
+}
+
+#[tokio::test]
+async fn manifest_records_the_content_disposition_name() {
+    let server = MockServer::start().await;
+    let body = payload(3_000);
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("content-disposition", "attachment; filename=\"sa_7.tar\"")
+                .set_body_bytes(body.clone()),
+        )
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let entries = vec![entry("shard.bin", format!("{}/get?id=7", server.uri()))];
+    let dl = downloader(dir.path(), &["--honor-content-disposition"]);
+
+    let summary = dl.download_all(entries.clone(), 1).await.unwrap();
+    let records = dl.manifest_records(&entries, &summary).unwrap();
+
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].path.file_name().unwrap(), "sa_7.tar");
+    assert_eq!(records[0].size, body.len() as u64);
+}
+
+/// A small TSV link file with `count` entries.
+fn link_file(count: usize) -> Vec<u8> {
+    let mut text = String::from("file_name\tcdn_link\n");
+    for i in 0..count {
+        text.push_str(&format!("sa_{}.tar\thttps://example.com/sa_{}.tar\n", i, i));
+    }
+    text.into_bytes()
+}
+
+#[tokio::test]
+async fn small_manifest_of_unknown_size_is_accepted() {
+    let server = MockServer::start().await;
+    // HEAD 不给出大小，只能靠内容判断清单是否完整
+    Mock::given(method("HEAD"))
+        .respond_with(ResponseTemplate::new(200).insert_header("etag", "\"v1\""))
+        .mount(&server)
+        .await;
+    let manifest = link_file(2);
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(manifest.clone()))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let path = downloader(dir.path(), &[])
+        .fetch_manifest(&format!("{}/links.txt", server.uri()))
+        .await
+        .unwrap();
+
+    assert!(manifest.len() < 1024);
+    assert_eq!(fs::read(&path).unwrap(), manifest);
+}
+
+#[tokio::test]
+async fn manifest_url_is_cached_until_its_etag_changes() {
+    let server = MockServer::start().await;
+    let serve = |etag: &str, body: &[u8]| {
+        Mock::given(any()).respond_with(ResponseTemplate::new(200).insert_header("etag", etag).set_body_bytes(body.to_vec()))
+    };
+    let manifest = link_file(3);
+    serve("\"v1\"", &manifest).mount(&server).await;
+    let dir = tempfile::tempdir().unwrap();
+    let dl = downloader(dir.path(), &[]);
+    let url = format!("{}/links.txt", server.uri());
+
+    let path = dl.fetch_manifest(&url).await.unwrap();
+    assert_eq!(path, dir.path().canonicalize().unwrap().join(".manifest/links.txt"));
+    assert_eq!(fs::read(&path).unwrap(), manifest);
+
+    // ETag 与缓存记录一致：只发 HEAD，不重新下载
+    server.reset().await;
+    serve("\"v1\"", &manifest).mount(&server).await;
+    assert_eq!(dl.fetch_manifest(&url).await.unwrap(), path);
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests.iter().all(|r| r.method.as_str() == "HEAD"));
+
+    // ETag 变化后重新下载
+    let updated = link_file(4);
+    server.reset().await;
+    serve("\"v2\"", &updated).mount(&server).await;
+    dl.fetch_manifest(&url).await.unwrap();
+    assert_eq!(fs::read(&path).unwrap(), updated);
+}
+
+#[tokio::test]
+async fn quarantine_dir_keeps_files_that_fail_verification() {
+    let server = MockServer::start().await;
+    let body = payload(2_000);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let quarantine = dir.path().join("quarantine");
+    let bad = LinkEntry {
+        checksum: Some("0".repeat(64)),
+        ..entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))
+    };
+
+    let summary = downloader(
+        dir.path(),
+        &["--verify-after-download", "--on-checksum-mismatch", "skip", "--quarantine-dir", quarantine.to_str().unwrap()],
+    )
+    .download_all(vec![bad], 1)
+    .await
+    .unwrap();
+
+    let kept = quarantine.join("sa_0.tar.checksum-mismatch");
+    assert_eq!(summary.failed, 1);
+    assert_eq!(summary.quarantined, vec![kept.clone()]);
+    assert_eq!(fs::read(&kept).unwrap(), body);
+    assert!(!dir.path().join("sa_0.tar").exists());
+}
+
+#[tokio::test]
+async fn misaligned_resume_restarts_from_the_beginning() {
+    let server = MockServer::start().await;
+    let body = payload(10_000);
+    // 无论请求哪个区间，都从第 0 字节开始回答
+    Mock::given(header_exists("range"))
+        .respond_with(
+            ResponseTemplate::new(206)
+                .insert_header("content-range", format!("bytes 0-{}/{}", body.len() - 1, body.len()).as_str())
+                .set_body_bytes(body.clone()),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(body.clone()))
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..4_000]).unwrap();
+
+    downloader(dir.path(), &["--trust-manifest-size"])
+        .download_single(&LinkEntry {
+            size: Some(body.len() as u64),
+            ..entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let ranged = server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.headers.contains_key("range"))
+        .count();
+    assert_eq!(ranged, 1, "the misaligned range is not requested again");
+}
+
+#[tokio::test]
+async fn resumes_through_a_multipart_byteranges_response() {
+    let server = MockServer::start().await;
+    let body = payload(50_000);
+    Mock::given(any())
+        .respond_with(MultipartFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..20_000]).unwrap();
+
+    downloader(dir.path(), &[])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    assert!(
+        requests
+            .iter()
+            .any(|r| r.headers.get("range").is_some_and(|v| v == "bytes=20000-")),
+        "the resume must have gone through the multipart answer"
+    );
+}
+
+/// A crash after the last byte was written but before validation leaves a full-length
+/// `.part` whose content may be wrong; the rerun must check it, not promote it.
+#[tokio::test]
+async fn corrupt_part_left_by_a_crash_is_not_promoted() {
+    let server = MockServer::start().await;
+    let body = payload(4_000);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), vec![0xAA; body.len()]).unwrap();
+    let checked = LinkEntry {
+        checksum: Some(hex::encode(Sha256::digest(&body))),
+        ..entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))
+    };
+
+    let summary = downloader(dir.path(), &["--verify-after-download"])
+        .download_all(vec![checked], 1)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 1);
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    assert!(!dir.path().join("sa_0.tar.part").exists());
+}
+
+#[tokio::test]
+async fn oversized_part_left_by_a_crash_is_redownloaded() {
+    let server = MockServer::start().await;
+    let body = payload(4_000);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("sa_0.tar.part"), vec![0xAA; body.len() + 100]).unwrap();
+
+    let summary = downloader(dir.path(), &[])
+        .download_all(vec![entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))], 1)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 1);
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn race_mirrors_downloads_from_the_fastest_mirror() {
+    let body = payload(5_000);
+    let slow = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)).set_body_bytes(body.clone()))
+        .mount(&slow)
+        .await;
+    let fast = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&fast)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let link = LinkEntry {
+        mirrors: vec![format!("{}/sa_0.tar", fast.uri())],
+        ..entry("sa_0.tar", format!("{}/sa_0.tar", slow.uri()))
+    };
+
+    let started = Instant::now();
+    downloader(dir.path(), &["--race-mirrors"]).download_single(&link).await.unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let fast_gets = fast.received_requests().await.unwrap();
+    assert!(fast_gets.iter().any(|r| r.method.as_str() == "GET" && r.headers.get("range").is_none()));
+}
+
+#[tokio::test]
+async fn dedup_links_entries_with_the_same_checksum() {
+    let server = MockServer::start().await;
+    let body = payload(4_000);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let same = |name: &str| LinkEntry {
+        checksum: Some("ab".repeat(32)),
+        ..entry(name, format!("{}/{}", server.uri(), name))
+    };
+
+    let summary = downloader(dir.path(), &["--dedup"])
+        .download_all(vec![same("sa_0.tar"), same("sa_1.tar")], 2)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 2);
+    assert_eq!(summary.dedup_saved_bytes, 4_000);
+    assert_eq!(fs::read(dir.path().join("sa_1.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.iter().filter(|r| r.method.as_str() == "GET").count(), 1);
+}
+
+#[tokio::test]
+async fn segmented_download_fetches_each_range() {
+    let server = MockServer::start().await;
+    let body = payload(20 * 1024 * 1024);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    downloader(dir.path(), &["--segments", "2"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    assert!(!segments::map_path(&dir.path().join("sa_0.tar.part")).exists());
+    let requests = server.received_requests().await.unwrap();
+    let mut ranges: Vec<&str> = requests
+        .iter()
+        .filter(|r| r.method.as_str() == "GET")
+        .filter_map(|r| r.headers.get("range")?.to_str().ok())
+        .filter(|range| *range != "bytes=0-0")
+        .collect();
+    ranges.sort();
+    assert_eq!(ranges, ["bytes=0-10485759", "bytes=10485760-20971519"]);
+}
+
+#[tokio::test]
+async fn segmented_download_resumes_each_segment() {
+    let server = MockServer::start().await;
+    let body = payload(20 * 1024 * 1024);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let part = dir.path().join("sa_0.tar.part");
+    let half = 10 * 1024 * 1024;
+    // 第一段已完成，第二段写了 1000 字节，其余部分是空洞
+    let mut partial = body[..half + 1000].to_vec();
+    partial.resize(body.len(), 0);
+    fs::write(&part, partial).unwrap();
+    SegmentMap {
+        total_bytes: body.len() as u64,
+        segments: vec![
+            Segment { start: 0, end: half as u64, done: half as u64 },
+            Segment { start: half as u64, end: body.len() as u64, done: 1000 },
+        ],
+    }
+    .save(&segments::map_path(&part))
+    .unwrap();
+
+    downloader(dir.path(), &["--segments", "2"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    let ranges: Vec<&str> = requests
+        .iter()
+        .filter(|r| r.method.as_str() == "GET")
+        .filter_map(|r| r.headers.get("range")?.to_str().ok())
+        .filter(|range| *range != "bytes=0-0")
+        .collect();
+    assert_eq!(ranges, [format!("bytes={}-{}", half + 1000, body.len() - 1)]);
+}
+
+#[tokio::test]
+async fn segmented_download_keeps_a_single_connection_partial() {
+    let server = MockServer::start().await;
+    let body = payload(20 * 1024 * 1024);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let prefix = 4 * 1024 * 1024;
+    fs::write(dir.path().join("sa_0.tar.part"), &body[..prefix]).unwrap();
+
+    downloader(dir.path(), &["--segments", "2"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    let requests = server.received_requests().await.unwrap();
+    let mut ranges: Vec<&str> = requests
+        .iter()
+        .filter(|r| r.method.as_str() == "GET")
+        .filter_map(|r| r.headers.get("range")?.to_str().ok())
+        .filter(|range| *range != "bytes=0-0")
+        .collect();
+    ranges.sort();
+    assert_eq!(ranges, ["bytes=12582912-20971519", "bytes=4194304-12582911"]);
+}
+
+#[tokio::test]
+async fn limit_rate_caps_each_connection_separately() {
+    let server = MockServer::start().await;
+    let body = payload(200_000);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let entries = (0..2)
+        .map(|i| entry(&format!("sa_{}.tar", i), format!("{}/sa_{}.tar", server.uri(), i)))
+        .collect();
+
+    let started = Instant::now();
+    let summary = downloader(dir.path(), &["--limit-rate", "100KB"])
+        .download_all(entries, 2)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 2);
+    // 每个连接超出一秒突发额度的部分需要约一秒；两个连接互不影响，总共也只需约一秒
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(2_500), "{:?}", elapsed);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn preallocated_partial_keeps_its_length_for_resume() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = MockServer::start().await;
+    let body = payload(1_000_000);
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(TruncatedFile { body: body.clone(), cut: 3_000 })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let link = entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()));
+
+    let result = downloader(dir.path(), &["--retries", "1", "--preallocate"])
+        .download_single(&link)
+        .await;
+
+    assert!(result.is_err());
+    let part = fs::metadata(dir.path().join("sa_0.tar.part")).unwrap();
+    assert_eq!(part.len(), 3_000);
+    // 磁盘块已按完整大小分配，但长度仍是实际写入的字节数
+    assert!(part.blocks() * 512 >= 1_000_000, "{} blocks", part.blocks());
+
+    server.reset().await;
+    Mock::given(path("/sa_0.tar"))
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+
+    downloader(dir.path(), &["--retries", "1", "--preallocate"])
+        .download_single(&link)
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn batch_larger_than_free_space_fails_unless_forced() {
+    let server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: payload(1_000) })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let huge = || {
+        vec![LinkEntry {
+            size: Some(1 << 60),
+            ..entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))
+        }]
+    };
+
+    let error = downloader(dir.path(), &[]).check_free_space(&huge(), 1).await.unwrap_err();
+
+    assert!(error.to_string().contains("Not enough free space"), "{}", error);
+    assert!(server.received_requests().await.unwrap().is_empty());
+
+    downloader(dir.path(), &["--force"]).check_free_space(&huge(), 1).await.unwrap();
+}
+
+#[tokio::test]
+async fn download_all_sends_one_head_per_file() {
+    let server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: payload(2_000) })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    downloader(dir.path(), &[])
+        .download_all(vec![entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))], 1)
+        .await
+        .unwrap();
+
+    // 空间预检只在 main 的批次下载前运行，download_all 自身不再为它发 HEAD
+    let heads = server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.method.as_str() == "HEAD")
+        .count();
+    assert_eq!(heads, 1);
+}
+
+#[tokio::test]
+async fn free_space_check_uses_sizes_recorded_in_state() {
+    let server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: payload(1_000) })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join(".download_state.json"),
+        format!(r#"[{{"file_name": "sa_0.tar", "downloaded_bytes": 0, "total_bytes": {}, "completed": false}}]"#, 1u64 << 60),
+    )
+    .unwrap();
+
+    let error = downloader(dir.path(), &[])
+        .check_free_space(&[entry("sa_0.tar", format!("{}/sa_0.tar", server.uri()))], 1)
+        .await
+        .unwrap_err();
+
+    assert!(error.to_string().contains("Not enough free space"), "{}", error);
+    assert!(server.received_requests().await.unwrap().is_empty(), "the recorded size needs no HEAD");
+}
+
+#[tokio::test]
+async fn output_dirs_on_one_filesystem_share_its_free_space() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::create_dir_all(&a).unwrap();
+    fs::create_dir_all(&b).unwrap();
+    let available = fs4::available_space(dir.path()).unwrap();
+    // 两个目录各算一次就够放，按同一文件系统只算一次则不够
+    let entries = vec![LinkEntry {
+        size: Some(available / 2 * 3),
+        ..entry("sa_0.tar", "http://127.0.0.1:9/sa_0.tar".to_string())
+    }];
+
+    let error = downloader(&a, &["-o", b.to_str().unwrap()])
+        .check_free_space(&entries, 1)
+        .await
+        .unwrap_err();
+
+    assert!(error.to_string().contains("Not enough free space"), "{}", error);
+}
+
+#[tokio::test]
+async fn stalled_response_is_abandoned_and_retried() {
+    let server = MockServer::start().await;
+    let body = payload(5_000);
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(body.clone())
+                .set_delay(Duration::from_secs(10)),
+        )
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let started = Instant::now();
+    downloader(dir.path(), &["--stall-timeout", "1"])
+        .download_single(&entry("sa_0.tar", format!("{}/sa_0.tar", server.uri())))
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read(dir.path().join("sa_0.tar")).unwrap(), body);
+    // 一秒无响应即放弃，重试前等待一秒，远早于第一次响应的十秒延迟
+    assert!(started.elapsed() < Duration::from_secs(6), "{:?}", started.elapsed());
+}
+
+#[test]
+fn zero_limit_rate_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let error = Args::try_parse_from(["sa-1b-dl", "-o", dir.path().to_str().unwrap(), "--limit-rate", "0"]).unwrap_err();
+
+    assert!(error.to_string().contains("greater than zero"), "{}", error);
+}
+
+#[test]
+fn zero_bandwidth_limit_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+
+    for flag in ["--bandwidth-limit", "--limit-rate-total"] {
+        let error = Args::try_parse_from(["sa-1b-dl", "-o", dir.path().to_str().unwrap(), flag, "0"]).unwrap_err();
+        assert!(error.to_string().contains("greater than zero"), "{}: {}", flag, error);
+    }
+}
+
+#[tokio::test]
+async fn write_buffers_fit_a_memory_budget_smaller_than_one_buffer() {
+    let server = MockServer::start().await;
+    let body = payload(300_000);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    let dir = tempfile::tempdir().unwrap();
+    let entries = (0..3)
+        .map(|i| entry(&format!("sa_{}.tar", i), format!("{}/sa_{}.tar", server.uri(), i)))
+        .collect();
+
+    // 每个写缓冲连同数据块超出整个预算：传输依次占满预算，不会互相等待
+    let summary = tokio::time::timeout(
+        Duration::from_secs(30),
+        downloader(dir.path(), &["--max-memory", "64KiB", "--write-buffer-size", "128KiB"]).download_all(entries, 3),
+    )
+    .await
+    .expect("downloads must not deadlock on the memory budget")
+    .unwrap();
+
+    assert_eq!(summary.success, 3);
+    for i in 0..3 {
+        assert_eq!(fs::read(dir.path().join(format!("sa_{}.tar", i))).unwrap(), body);
+    }
+}
+
+#[test]
+fn invalid_request_rates_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+
+    for rate in ["0", "-1", "NaN", "inf", "1e-300", "fast"] {
+        let result = Args::try_parse_from(["sa-1b-dl", "-o", dir.path().to_str().unwrap(), "--limit-files-per-second", rate]);
+        assert!(result.is_err(), "{} should be rejected", rate);
+    }
+    assert!(Args::try_parse_from(["sa-1b-dl", "-o", dir.path().to_str().unwrap(), "--limit-files-per-second", "0.5"]).is_ok());
+}
@@ -0,0 +1,66 @@
+//! Timing check for resuming a batch against a large state file: every entry looks
+//! up its state by name, which must stay a hash lookup rather than a scan.
+//!
+//! Ignored by default; run with `cargo test --release --test large_state -- --ignored --nocapture`.
+
+mod common;
+
+use common::{downloader, entry};
+use sa_1b_dl::models::LinkEntry;
+use sa_1b_dl::state::{StateFormat, StateManager};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Builds a run over `count` files that are all already complete, so the time goes
+/// into loading the state and matching entries to it rather than into transfers.
+fn completed_batch(dir: &Path, count: usize) -> Vec<LinkEntry> {
+    let files: Vec<serde_json::Value> = (0..count)
+        .map(|i| {
+            let name = format!("sa_{:06}.tar", i);
+            fs::write(dir.join(&name), b"x").unwrap();
+            serde_json::json!({
+                "file_name": name,
+                "downloaded_bytes": 1,
+                "total_bytes": 1,
+                "completed": true,
+            })
+        })
+        .collect();
+    fs::write(dir.join(".download_state.json"), serde_json::to_vec(&files).unwrap()).unwrap();
+    assert_eq!(StateManager::new(dir, StateFormat::Pretty).load_state().unwrap().len(), count);
+
+    // 清单带有大小，配合 --trust-manifest-size 整个批次不发任何请求
+    (0..count)
+        .map(|i| LinkEntry {
+            size: Some(1),
+            ..entry(&format!("sa_{:06}.tar", i), format!("http://127.0.0.1:9/sa_{:06}.tar", i))
+        })
+        .collect()
+}
+
+async fn time_run(count: usize) -> Duration {
+    let dir = tempfile::tempdir().unwrap();
+    let entries = completed_batch(dir.path(), count);
+    let dl = downloader(dir.path(), &["--trust-manifest-size"]);
+
+    let started = Instant::now();
+    let summary = dl.download_all(entries, 8).await.unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!(summary.skipped + summary.success, count);
+    elapsed
+}
+
+/// Four times the entries should take about four times as long; a per-entry scan
+/// of the state would make it sixteen.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "timing benchmark; run explicitly"]
+async fn resume_time_grows_linearly_with_state_size() {
+    let small = time_run(5_000).await;
+    let large = time_run(20_000).await;
+    let ratio = large.as_secs_f64() / small.as_secs_f64();
+    println!("5000 entries: {:.2?}, 20000 entries: {:.2?} (x{:.1})", small, large, ratio);
+
+    assert!(ratio < 8.0, "resume time grew x{:.1} for x4 entries", ratio);
+}
@@ -0,0 +1,92 @@
+//! Link files are untrusted input: no file name in them may point outside the
+//! output directory.
+
+mod common;
+
+use common::downloader;
+use sa_1b_dl::downloader::validate_file_name;
+use std::fs;
+
+#[test]
+fn accepts_plain_and_nested_names() {
+    for name in ["sa_000000.tar", "train/sa_000001.tar", "./sa_000002.tar", "a..b.tar"] {
+        assert!(validate_file_name(name).is_ok(), "{:?} should be accepted", name);
+    }
+}
+
+#[test]
+fn rejects_hostile_names() {
+    let hostile = [
+        "",
+        "   ",
+        ".",
+        "..",
+        "./",
+        "train/",
+        "train/.",
+        "../x",
+        "train/../../x",
+        "/etc/passwd",
+        "//server/share",
+        "..\\x",
+        "C:\\Windows\\x",
+        "sa_0.tar\0.txt",
+    ];
+    for name in hostile {
+        assert!(validate_file_name(name).is_err(), "{:?} should be rejected", name);
+    }
+}
+
+#[test]
+fn hostile_tsv_link_file_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let links = dir.path().join("links.txt");
+    fs::write(
+        &links,
+        "file_name\tcdn_link\nsa_0.tar\thttps://example.com/sa_0.tar\n../../.bashrc\thttps://example.com/x\n",
+    )
+    .unwrap();
+
+    let error = downloader(&dir.path().join("out"), &[])
+        .parse_link_file(links.to_str().unwrap())
+        .unwrap_err();
+
+    assert!(format!("{:#}", error).contains("line 3"), "{:#}", error);
+}
+
+#[test]
+fn hostile_jsonl_link_file_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let links = dir.path().join("links.jsonl");
+    fs::write(&links, "{\"file_name\": \"/tmp/evil.tar\", \"url\": \"https://example.com/x\"}\n").unwrap();
+
+    let error = downloader(&dir.path().join("out"), &[])
+        .parse_link_file(links.to_str().unwrap())
+        .unwrap_err();
+
+    assert!(format!("{:#}", error).contains("Unsafe file name"), "{:#}", error);
+}
+
+#[test]
+fn jsonl_sha256_is_only_used_for_sha256_checks() {
+    let dir = tempfile::tempdir().unwrap();
+    let links = dir.path().join("links.jsonl");
+    let digest = "ab".repeat(32);
+    fs::write(
+        &links,
+        format!("{{\"file_name\": \"sa_0.tar\", \"url\": \"https://example.com/sa_0.tar\", \"sha256\": \"{}\"}}\n", digest),
+    )
+    .unwrap();
+    let parse = |algo: &str| {
+        downloader(&dir.path().join("out"), &["--hash-algo", algo])
+            .parse_link_file(links.to_str().unwrap())
+            .unwrap()
+            .remove(0)
+            .checksum
+    };
+
+    assert_eq!(parse("sha256"), Some(digest.clone()));
+    assert_eq!(parse("auto"), Some(digest));
+    assert_eq!(parse("md5"), None);
+    assert_eq!(parse("sha1"), None);
+}
@@ -0,0 +1,130 @@
+//! State files written by every earlier release must keep loading, and files from a
+//! newer release must not be misread.
+
+use sa_1b_dl::state::{StateFormat, StateManager, STATE_SCHEMA_VERSION};
+use std::fs;
+
+const STATE_FILE: &str = ".download_state.json";
+
+/// The first release: a bare array, before `timeouts` or any optional field existed.
+const V0_ORIGINAL: &str = r#"[
+  {"file_name": "sa_0.tar", "downloaded_bytes": 10000, "total_bytes": 10000, "completed": true},
+  {"file_name": "sa_1.tar", "downloaded_bytes": 4000, "total_bytes": null, "completed": false}
+]"#;
+
+/// Still a bare array, with the fields later releases added.
+const V0_EXTENDED: &str = r#"[
+  {
+    "file_name": "sa_0.tar",
+    "downloaded_bytes": 10000,
+    "total_bytes": 10000,
+    "completed": true,
+    "timeouts": 2,
+    "sha256": "abc123",
+    "hashed_at": "2025-01-02T03:04:05Z",
+    "saved_as": "renamed.tar"
+  }
+]"#;
+
+const V1: &str = r#"{
+  "schema_version": 1,
+  "files": [
+    {"file_name": "sa_0.tar", "downloaded_bytes": 500, "total_bytes": 10000, "completed": false, "timeouts": 1}
+  ]
+}"#;
+
+#[test]
+fn loads_original_array_format() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(STATE_FILE), V0_ORIGINAL).unwrap();
+
+    let states = StateManager::new(dir.path(), StateFormat::Pretty).load_state().unwrap();
+
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0].file_name, "sa_0.tar");
+    assert!(states[0].completed);
+    assert_eq!(states[0].timeouts, 0);
+    assert_eq!(states[1].downloaded_bytes, 4000);
+    assert_eq!(states[1].total_bytes, None);
+    assert_eq!(states[1].sha256, None);
+}
+
+#[test]
+fn loads_extended_array_format() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(STATE_FILE), V0_EXTENDED).unwrap();
+
+    let states = StateManager::new(dir.path(), StateFormat::Pretty).load_state().unwrap();
+
+    assert_eq!(states.len(), 1);
+    assert_eq!(states[0].timeouts, 2);
+    assert_eq!(states[0].sha256.as_deref(), Some("abc123"));
+    assert_eq!(states[0].saved_as.as_deref(), Some("renamed.tar"));
+}
+
+#[test]
+fn loads_zstd_array_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let compressed = zstd::encode_all(V0_ORIGINAL.as_bytes(), 3).unwrap();
+    fs::write(dir.path().join(".download_state.json.zst"), compressed).unwrap();
+
+    let states = StateManager::new(dir.path(), StateFormat::Zstd).load_state().unwrap();
+
+    assert_eq!(states.len(), 2);
+}
+
+#[test]
+fn loads_versioned_format() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(STATE_FILE), V1).unwrap();
+
+    let states = StateManager::new(dir.path(), StateFormat::Pretty).load_state().unwrap();
+
+    assert_eq!(states.len(), 1);
+    assert_eq!(states[0].downloaded_bytes, 500);
+    assert_eq!(states[0].timeouts, 1);
+}
+
+#[test]
+fn saving_upgrades_to_current_schema() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(STATE_FILE), V0_ORIGINAL).unwrap();
+    let manager = StateManager::new(dir.path(), StateFormat::Pretty);
+
+    let states = manager.load_state().unwrap();
+    manager.save_state(&states).unwrap();
+
+    let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.path().join(STATE_FILE)).unwrap()).unwrap();
+    assert_eq!(saved["schema_version"], STATE_SCHEMA_VERSION);
+    assert_eq!(saved["files"].as_array().unwrap().len(), 2);
+    assert_eq!(manager.load_state().unwrap().len(), 2);
+}
+
+#[test]
+fn newer_schema_starts_fresh_and_keeps_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let future = r#"{"schema_version": 99, "files": [{"file_name": "sa_0.tar", "chunks": []}]}"#;
+    fs::write(dir.path().join(STATE_FILE), future).unwrap();
+
+    let states = StateManager::new(dir.path(), StateFormat::Pretty).load_state().unwrap();
+
+    assert!(states.is_empty());
+    assert_eq!(fs::read_to_string(dir.path().join(".download_state.v99.bak")).unwrap(), future);
+}
+
+#[test]
+fn validate_reports_inconsistent_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = r#"[
+      {"file_name": "sa_0.tar", "downloaded_bytes": 900, "total_bytes": 1000, "completed": true},
+      {"file_name": "sa_0.tar", "downloaded_bytes": 0, "total_bytes": null, "completed": false},
+      {"file_name": "sa_1.tar", "downloaded_bytes": 1000, "total_bytes": 1000, "completed": true}
+    ]"#;
+    fs::write(dir.path().join(STATE_FILE), state).unwrap();
+
+    let check = StateManager::new(dir.path(), StateFormat::Pretty).validate().unwrap().unwrap();
+
+    assert_eq!(check.schema_version, 0);
+    assert_eq!(check.entries, 3);
+    assert_eq!(check.problems.len(), 2, "{:?}", check.problems);
+}
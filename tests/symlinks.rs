@@ -0,0 +1,84 @@
+//! Output directories reached through symlinks must behave like their targets, and
+//! symlinks must not let a download escape the output directory.
+
+mod common;
+
+use common::{downloader, entry, payload, try_downloader, RangeFile};
+use std::fs;
+use std::os::unix::fs::symlink;
+use wiremock::matchers::any;
+use wiremock::{Mock, MockServer};
+
+async fn file_server(len: usize) -> (MockServer, Vec<u8>) {
+    let server = MockServer::start().await;
+    let body = payload(len);
+    Mock::given(any())
+        .respond_with(RangeFile { body: body.clone() })
+        .mount(&server)
+        .await;
+    (server, body)
+}
+
+#[tokio::test]
+async fn downloads_into_symlinked_output_dir() {
+    let (server, body) = file_server(4_000).await;
+    let dir = tempfile::tempdir().unwrap();
+    let real = dir.path().join("real");
+    fs::create_dir(&real).unwrap();
+    let link = dir.path().join("link");
+    symlink(&real, &link).unwrap();
+
+    let summary = downloader(&link, &[])
+        .download_all(vec![entry("sub/sa_0.tar", format!("{}/sa_0.tar", server.uri()))], 1)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.success, 1);
+    assert_eq!(fs::read(real.join("sub/sa_0.tar")).unwrap(), body);
+    assert!(real.join(".download_state.json").exists());
+}
+
+#[tokio::test]
+async fn rejects_symlink_loop_as_output_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    symlink(&b, &a).unwrap();
+    symlink(&a, &b).unwrap();
+
+    let error = try_downloader(&a, &[]).err().expect("loop must be rejected");
+    assert!(error.to_string().contains("does not resolve"), "{}", error);
+}
+
+#[tokio::test]
+async fn rejects_the_same_directory_twice_through_a_symlink() {
+    let dir = tempfile::tempdir().unwrap();
+    let link = dir.path().join("link");
+    symlink(dir.path(), &link).unwrap();
+
+    let error = try_downloader(dir.path(), &["-o", link.to_str().unwrap()])
+        .err()
+        .expect("duplicate output must be rejected");
+    assert!(error.to_string().contains("already an output directory"), "{}", error);
+}
+
+#[tokio::test]
+async fn refuses_symlinked_subdirectory_leading_outside() {
+    let (server, _) = file_server(4_000).await;
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out");
+    let outside = dir.path().join("outside");
+    fs::create_dir_all(&output).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    symlink(&outside, output.join("sub")).unwrap();
+
+    let summary = downloader(&output, &[])
+        .download_all(vec![entry("sub/deeper/sa_0.tar", format!("{}/sa_0.tar", server.uri()))], 1)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.failed, 1);
+    assert!(summary.files[0].error.as_deref().unwrap().contains("Refusing to write outside"));
+    assert!(!outside.join("deeper").exists());
+    assert!(server.received_requests().await.unwrap().is_empty());
+}